@@ -21,16 +21,150 @@ use anyhow::{anyhow, bail, Context, Result};
 use itertools::Itertools;
 use thiserror::Error;
 
-use std::{collections::HashMap, convert::TryFrom};
+use std::{collections::{HashMap, HashSet}, convert::TryFrom};
 
 use chrono::prelude::*;
-use ical::parser::ical::component::IcalCalendar;
+use ical::parser::ical::component::{IcalCalendar, IcalEvent};
 
+/// Fetched unless FetchOptions names other calendars
 const CALENDAR_URL : &str = "https://calendar.google.com/calendar/ical/1b1et1slg27jm1rgdltu3mn2j4@group.calendar.google.com/public/basic.ics";
 
-use super::CalendarEvent;
+/// Parses a calendar URL, rejecting anything reqwest can't fetch
+pub(crate) fn parse_calendar_url(s: &str) -> Result<reqwest::Url> {
+    let url = reqwest::Url::parse(s).with_context(|| format!("Invalid calendar URL {:?}", s))?;
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        scheme => bail!("Calendar URL {:?} must use http or https, not {}", s, scheme),
+    }
+}
+
+use super::{CalendarEvent, CalendarUpdaterError};
+use crate::config::{FULL_WIDTH_CATEGORY, FULL_WIDTH_PREFIX};
+use crate::fetch_cache::FetchCache;
+use crate::timezone::DisplayZone;
+
+use tracing::{debug, error, field, info, info_span, warn};
+
+use super::in_timed_span;
+
+/// Events running longer than this are likely data-entry mistakes (e.g. a year-long DTEND)
+const LONG_EVENT_WARNING_HOURS: i64 = 24 * 7;
+
+/// Order of events within a single day
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum DaySort {
+    /// By start time, then end time, then title
+    Time,
+    /// By title
+    Alpha,
+    /// By first category (uncategorized events last), then time
+    Category,
+}
+
+impl std::str::FromStr for DaySort {
+    type Err = anyhow::Error;
 
-use tracing::{error, info};
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "time" => Ok(DaySort::Time),
+            "alpha" => Ok(DaySort::Alpha),
+            "category" => Ok(DaySort::Category),
+            _ => Err(anyhow!("Unknown sort order {:?}", s)),
+        }
+    }
+}
+
+/// Whether an event starting exactly at the end of the display window is shown
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum WindowEnd {
+    /// Shown, listed under the final day. All-day events on the following day are not shown.
+    Inclusive,
+    Exclusive,
+}
+
+impl std::str::FromStr for WindowEnd {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "inclusive" => Ok(WindowEnd::Inclusive),
+            "exclusive" => Ok(WindowEnd::Exclusive),
+            _ => Err(anyhow!("Unknown window end {:?}", s)),
+        }
+    }
+}
+
+/// How duplicate events within a day are detected
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum DedupBy {
+    /// Same UID and start time
+    Uid,
+    /// Same start, end and title, ignoring case and whitespace differences. The default, and
+    /// the only check made before this was configurable.
+    Content,
+    /// Keep every event
+    None,
+}
+
+impl std::str::FromStr for DedupBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "uid" => Ok(DedupBy::Uid),
+            "content" => Ok(DedupBy::Content),
+            "none" => Ok(DedupBy::None),
+            _ => Err(anyhow!("Unknown dedup strategy {:?}", s)),
+        }
+    }
+}
+
+pub(crate) struct FetchOptions {
+    /// Events longer than this are dropped entirely
+    pub max_event_duration: Option<chrono::Duration>,
+    /// Group events starting before the rollover hour under the previous day
+    pub group_early_starts: bool,
+    /// Hour at which the next day begins
+    pub rollover_hour: u32,
+    /// Fail on any malformed event, rather than skipping it
+    pub strict: bool,
+    /// Zone used to group events into days
+    pub zone: DisplayZone,
+    /// The time the calendar is generated for, in the display zone
+    pub now: DateTime<FixedOffset>,
+    pub sort_within_day: DaySort,
+    /// Drop events which have already ended
+    pub hide_ended: bool,
+    /// Drop events marked TRANSP:TRANSPARENT
+    pub hide_transparent: bool,
+    /// Keep events marked STATUS:CANCELLED, rather than dropping them
+    pub show_cancelled: bool,
+    /// Calendars to fetch and merge; CALENDAR_URL if empty
+    pub calendar_urls: Vec<reqwest::Url>,
+    /// Shell command whose stdout is used as the ICS data, instead of fetching the calendar URL
+    pub fetch_command: Option<String>,
+    /// File to read the ICS data from, instead of fetching the calendar URL
+    pub calendar_file: Option<String>,
+    /// Where fetched calendars are cached, if anywhere
+    pub cache: Option<FetchCache>,
+    /// Applies to the fetch command, and to each HTTP request
+    pub fetch_timeout: std::time::Duration,
+    /// Proxy for HTTP requests; otherwise the usual proxy environment variables are used
+    pub proxy: Option<String>,
+    pub window_end: WindowEnd,
+    /// Number of days shown, counting today
+    pub days_ahead: u32,
+    pub dedup_by: DedupBy,
+}
+
+/// Returns the day under which an event starting at `dt` is listed
+fn listing_date(dt: DateTime<FixedOffset>, group_early_starts: bool, rollover_hour: u32) -> Date<FixedOffset> {
+    if group_early_starts && dt.time().hour() < rollover_hour {
+        dt.date().pred()
+    } else {
+        dt.date()
+    }
+}
 
 #[derive(Error, Debug)]
 enum CalendarFetchError {
@@ -50,16 +184,44 @@ fn want_prop<'a>(
         .ok_or(CalendarFetchError::MissingProperty(name).into())
 }
 
-fn parse_date(s: &str) -> Result<DateTime<Local>> {
+/// Parses a date in the offset given in the feed; `fetch_calendar` converts it to the display zone
+fn parse_date(s: &str) -> Result<DateTime<FixedOffset>> {
     const ICAL_DATE_FMT: &'static str = "%Y%m%dT%H%M%S%#z";
     let fixed_date = DateTime::parse_from_str(s, ICAL_DATE_FMT)?;
-    Ok(fixed_date.with_timezone(&Local))
+    Ok(fixed_date)
+}
+
+/// Whether a date property holds a date with no time (VALUE=DATE), as all-day events use
+fn is_date_only(prop: &ical::property::Property) -> bool {
+    let value_date = prop
+        .params
+        .as_ref()
+        .map(|params| {
+            params.iter().any(|(k, v)| {
+                k.eq_ignore_ascii_case("VALUE") && v.iter().any(|v| v.eq_ignore_ascii_case("DATE"))
+            })
+        })
+        .unwrap_or(false);
+
+    value_date
+        || prop
+            .value
+            .as_ref()
+            .map(|v| v.len() == 8 && v.bytes().all(|b| b.is_ascii_digit()))
+            .unwrap_or(false)
+}
+
+/// Parses a date-only value as midnight UTC. These are floating dates, so `fetch_calendar`
+/// moves them to midnight in the display zone instead of converting them.
+fn parse_date_only(s: &str) -> Result<DateTime<FixedOffset>> {
+    let date = NaiveDate::parse_from_str(s, "%Y%m%d")?;
+    Ok(FixedOffset::east(0).from_utc_datetime(&date.and_hms(0, 0, 0)))
 }
 
 fn want_date0<'a>(
     map: &HashMap<&'a str, &'a ical::property::Property>,
     name: &'static str,
-) -> Result<DateTime<Local>> {
+) -> Result<DateTime<FixedOffset>> {
     const ICAL_DATE_FMT: &'static str = "%Y%m%dT%H%M%S%#z";
     let prop = want_prop(map, name)?;
     parse_date(prop)
@@ -68,19 +230,257 @@ fn want_date0<'a>(
 fn want_date<'a>(
     map: &HashMap<&'a str, &'a ical::property::Property>,
     name: &'static str,
-) -> Result<DateTime<Local>> {
+) -> Result<DateTime<FixedOffset>> {
     want_date0(map, name)
         .with_context(|| format!("Failed to parse or retrieve date property {:?}", name))
 }
 
+/// A DATE or DATE-TIME value, as used by EXDATE, RECURRENCE-ID and UNTIL
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum IcalDate {
+    /// A whole day, compared against dates in the display zone
+    Date(NaiveDate),
+    Time(DateTime<FixedOffset>),
+}
+
+impl IcalDate {
+    fn parse(s: &str, date_only: bool) -> Result<Self> {
+        if date_only || (s.len() == 8 && s.bytes().all(|b| b.is_ascii_digit())) {
+            Ok(IcalDate::Date(NaiveDate::parse_from_str(s, "%Y%m%d")?))
+        } else {
+            Ok(IcalDate::Time(parse_date(s)?))
+        }
+    }
+
+    /// Whether this names the occurrence starting at `start`
+    fn matches(&self, start: DateTime<FixedOffset>) -> bool {
+        match *self {
+            IcalDate::Date(date) => start.naive_local().date() == date,
+            IcalDate::Time(time) => start == time,
+        }
+    }
+
+    /// Whether an occurrence starting at `start` is within an UNTIL of this value
+    fn allows(&self, start: DateTime<FixedOffset>) -> bool {
+        match *self {
+            IcalDate::Date(date) => start.naive_local().date() <= date,
+            IcalDate::Time(time) => start <= time,
+        }
+    }
+}
+
+/// Parses each value of a (possibly comma-separated) date list property like EXDATE
+fn parse_date_list(prop: &ical::property::Property) -> Vec<IcalDate> {
+    let date_only = is_date_only(prop);
+
+    prop.value
+        .iter()
+        .flat_map(|v| v.split(','))
+        .filter_map(|s| match IcalDate::parse(s.trim(), date_only) {
+            Ok(date) => Some(date),
+            Err(e) => {
+                warn!("Ignoring unparseable {} value {:?}: {}", prop.name, s, e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Frequency {
+    Daily,
+    Weekly,
+}
+
+/// The parts of an RRULE we can expand
+#[derive(Clone, Debug)]
+struct Recurrence {
+    freq: Frequency,
+    interval: u32,
+    /// Total number of occurrences, counting DTSTART
+    count: Option<u32>,
+    until: Option<IcalDate>,
+    /// Weekdays the event falls on; for weekly rules, empty means DTSTART's weekday
+    by_day: Vec<Weekday>,
+    /// First day of the week, which decides which weeks count for INTERVAL
+    week_start: Weekday,
+}
+
+fn parse_weekday(s: &str) -> Result<Weekday> {
+    match s.trim().to_ascii_uppercase().as_str() {
+        "MO" => Ok(Weekday::Mon),
+        "TU" => Ok(Weekday::Tue),
+        "WE" => Ok(Weekday::Wed),
+        "TH" => Ok(Weekday::Thu),
+        "FR" => Ok(Weekday::Fri),
+        "SA" => Ok(Weekday::Sat),
+        "SU" => Ok(Weekday::Sun),
+        _ => Err(anyhow!("Unsupported weekday {:?}", s)),
+    }
+}
+
+/// Parses an RRULE value. Rules using anything but the parts in `Recurrence` are an error,
+/// rather than being expanded incorrectly.
+fn parse_rrule(s: &str) -> Result<Recurrence> {
+    let mut freq = None;
+    let mut rule = Recurrence {
+        freq: Frequency::Daily,
+        interval: 1,
+        count: None,
+        until: None,
+        by_day: Vec::new(),
+        week_start: Weekday::Mon,
+    };
+
+    for part in s.split(';').filter(|part| !part.is_empty()) {
+        let mut kv = part.splitn(2, '=');
+        let key = kv.next().unwrap_or("").trim().to_ascii_uppercase();
+        let value = kv.next().unwrap_or("").trim();
+
+        match key.as_str() {
+            "FREQ" => {
+                freq = Some(match value.to_ascii_uppercase().as_str() {
+                    "DAILY" => Frequency::Daily,
+                    "WEEKLY" => Frequency::Weekly,
+                    _ => bail!("Unsupported frequency {:?}", value),
+                })
+            }
+            "INTERVAL" => {
+                rule.interval = value.parse().with_context(|| format!("Bad INTERVAL {:?}", value))?;
+                if rule.interval == 0 {
+                    bail!("INTERVAL must be positive");
+                }
+            }
+            "COUNT" => rule.count = Some(value.parse().with_context(|| format!("Bad COUNT {:?}", value))?),
+            "UNTIL" => rule.until = Some(IcalDate::parse(value, false)?),
+            "BYDAY" => {
+                rule.by_day = value.split(',').map(parse_weekday).collect::<Result<_>>()?;
+            }
+            "WKST" => rule.week_start = parse_weekday(value)?,
+            _ => bail!("Unsupported rule part {:?}", key),
+        }
+    }
+
+    rule.freq = freq.ok_or_else(|| anyhow!("Rule has no FREQ"))?;
+    Ok(rule)
+}
+
+impl Recurrence {
+    /// Start times of each occurrence beginning no later than `limit`, keeping DTSTART's
+    /// wall-clock time in `zone` across DST changes. DTSTART itself is always the first.
+    fn occurrences(
+        &self,
+        dtstart: DateTime<FixedOffset>,
+        zone: DisplayZone,
+        limit: DateTime<FixedOffset>,
+    ) -> Vec<DateTime<FixedOffset>> {
+        let first = dtstart.naive_local();
+        let first_date = first.date();
+        let last_date = limit.naive_local().date();
+        let week_of = |date: NaiveDate| {
+            let into_week = (7 + date.weekday().num_days_from_monday()
+                - self.week_start.num_days_from_monday())
+                % 7;
+            date - chrono::Duration::days(into_week as i64)
+        };
+        let on_day = |date: NaiveDate| {
+            if self.by_day.is_empty() {
+                self.freq == Frequency::Daily || date.weekday() == first_date.weekday()
+            } else {
+                self.by_day.contains(&date.weekday())
+            }
+        };
+        let interval = self.interval as i64;
+
+        let mut starts = Vec::new();
+        let mut date = first_date;
+        while date <= last_date {
+            let in_period = match self.freq {
+                Frequency::Daily => (date - first_date).num_days() % interval == 0,
+                Frequency::Weekly => (week_of(date) - week_of(first_date)).num_weeks() % interval == 0,
+            };
+
+            if date == first_date || (in_period && on_day(date)) {
+                let start = if date == first_date { dtstart } else { zone.from_local(date.and_time(first.time())) };
+                if start > limit || !self.until.map(|until| until.allows(start)).unwrap_or(true) {
+                    break;
+                }
+
+                starts.push(start);
+                if Some(starts.len() as u32) == self.count {
+                    break;
+                }
+            }
+
+            date = date.succ();
+        }
+
+        starts
+    }
+}
+
+/// An event which was skipped because it could not be parsed
+#[derive(Clone, Debug, serde::Serialize)]
+pub(crate) struct ParseWarning {
+    /// The event's UID, or its position in the feed if it has none
+    pub event: String,
+    pub reason: String,
+}
+
+impl ParseWarning {
+    fn new(index: usize, event: &ical::parser::ical::component::IcalEvent, err: &anyhow::Error) -> Self {
+        let event_id = event
+            .properties
+            .iter()
+            .find(|p| p.name == "UID")
+            .and_then(|p| p.value.clone())
+            .unwrap_or_else(|| format!("#{}", index));
+
+        ParseWarning {
+            event: event_id,
+            reason: format!("{:#}", err),
+        }
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct ParsedEntry<'a> {
-    dtstart: DateTime<Local>,
-    dtend: Option<DateTime<Local>>,
+    dtstart: DateTime<FixedOffset>,
+    dtend: Option<DateTime<FixedOffset>>,
     uid: &'a str,
     description: Option<&'a str>,
     summary: &'a str,
+    location: Option<&'a str>,
+    geo: Option<(f64, f64)>,
+    /// Entries of the CATEGORIES property
+    categories: Vec<&'a str>,
+    /// TRANSP:TRANSPARENT, i.e. the event does not block time
+    transparent: bool,
+    /// STATUS:CANCELLED
+    cancelled: bool,
+    /// DTSTART is date-only; dtend is then the (exclusive) midnight after the last day
+    all_day: bool,
+    /// RRULE, if the event repeats and we understand the rule
+    recurrence: Option<Recurrence>,
+    /// Occurrences of a recurring event which were cancelled
+    exdates: Vec<IcalDate>,
+    /// For a single occurrence of a recurring event that was edited, the start of the
+    /// occurrence it replaces
+    recurrence_id: Option<IcalDate>,
+}
+
+/// Parses a GEO property value (`latitude;longitude`), ignoring coordinates off the globe
+fn parse_geo(s: &str) -> Option<(f64, f64)> {
+    let mut parts = s.splitn(2, ';');
+    let lat: f64 = parts.next()?.trim().parse().ok()?;
+    let long: f64 = parts.next()?.trim().parse().ok()?;
+
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&long) {
+        return None;
+    }
+
+    Some((lat, long))
 }
 
 impl<'a> TryFrom<&'a ical::parser::ical::component::IcalEvent> for ParsedEntry<'a> {
@@ -93,20 +493,83 @@ impl<'a> TryFrom<&'a ical::parser::ical::component::IcalEvent> for ParsedEntry<'
             hm.insert(prop.name.as_str(), prop);
         }
 
-        Ok(ParsedEntry {
-            dtstart: want_date(&hm, "DTSTART")?,
-            dtend: hm
+        let all_day = hm.get("DTSTART").map(|&p| is_date_only(p)).unwrap_or(false);
+        let (dtstart, dtend) = if all_day {
+            let dtstart = parse_date_only(want_prop(&hm, "DTSTART")?)
+                .context("Failed to parse date property \"DTSTART\"")?;
+            // A date-only DTEND is exclusive, so a 20210530-20210601 event covers the 30th and
+            // 31st. Without one, the event lasts a single day (RFC 5545 3.6.1).
+            let dtend = hm
+                .get("DTEND")
+                .filter(|&&p| is_date_only(p))
+                .and_then(|p| p.value.as_ref())
+                .and_then(|s| parse_date_only(s).ok())
+                .filter(|&dtend| dtend > dtstart)
+                .unwrap_or(dtstart + chrono::Duration::days(1));
+
+            (dtstart, Some(dtend))
+        } else {
+            let dtend = hm
                 .get("DTEND")
                 .and_then(|p| p.value.as_ref())
                 .map(|s| parse_date(&s))
                 .transpose()
-                .unwrap_or(None),
+                .unwrap_or(None);
+
+            (want_date(&hm, "DTSTART")?, dtend)
+        };
+
+        Ok(ParsedEntry {
+            dtstart,
+            dtend,
             uid: want_prop(&hm, "UID")?,
             description: hm
                 .get("DESCRIPTION")
                 .and_then(|e| e.value.as_ref())
                 .map(|s| s.as_str()),
             summary: want_prop(&hm, "SUMMARY")?,
+            location: hm
+                .get("LOCATION")
+                .and_then(|e| e.value.as_ref())
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty()),
+            geo: hm
+                .get("GEO")
+                .and_then(|e| e.value.as_ref())
+                .and_then(|s| parse_geo(s)),
+            categories: hm
+                .get("CATEGORIES")
+                .and_then(|e| e.value.as_ref())
+                .map(|s| s.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).collect())
+                .unwrap_or_default(),
+            transparent: hm
+                .get("TRANSP")
+                .and_then(|e| e.value.as_ref())
+                .map(|s| s.trim().eq_ignore_ascii_case("TRANSPARENT"))
+                .unwrap_or(false),
+            cancelled: hm
+                .get("STATUS")
+                .and_then(|e| e.value.as_ref())
+                .map(|s| s.trim().eq_ignore_ascii_case("CANCELLED"))
+                .unwrap_or(false),
+            all_day,
+            recurrence: hm
+                .get("RRULE")
+                .and_then(|e| e.value.as_ref())
+                .and_then(|s| match parse_rrule(s) {
+                    Ok(rule) => Some(rule),
+                    Err(e) => {
+                        warn!("Showing only the first instance of an event with RRULE {:?}: {:#}", s, e);
+                        None
+                    }
+                }),
+            exdates: event
+                .properties
+                .iter()
+                .filter(|p| p.name == "EXDATE")
+                .flat_map(parse_date_list)
+                .collect(),
+            recurrence_id: hm.get("RECURRENCE-ID").and_then(|&p| parse_date_list(p).first().copied()),
         })
     }
 }
@@ -115,17 +578,276 @@ fn cal_error(e: ical::parser::ParserError) -> anyhow::Error {
     CalendarFetchError::ParserError(e).into()
 }
 
-#[tracing::instrument]
-fn get_calendar_data() -> Result<IcalCalendar> {
+/// Runs a user-supplied shell command and returns its stdout, killing it if it runs too long
+fn run_fetch_command(command: &str, timeout: std::time::Duration) -> Result<Vec<u8>, CalendarUpdaterError> {
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+
+    info!("Running fetch command {:?}", command);
+
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+
+    let mut child = shell
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to start fetch command {:?}", command))
+        .map_err(CalendarUpdaterError::FetchError)?;
+
+    // Drain the pipes on other threads so a chatty child can't block on a full pipe
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stdout = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).map(|_| buf)
+    });
+    let stderr = std::thread::spawn(move || {
+        let mut buf = String::new();
+        let _ = stderr.read_to_string(&mut buf);
+        buf
+    });
+
+    let deadline = std::time::Instant::now() + timeout;
+    let status = loop {
+        let status = child
+            .try_wait()
+            .with_context(|| format!("Waiting for fetch command {:?}", command))
+            .map_err(CalendarUpdaterError::FetchError)?;
+        if let Some(status) = status {
+            break status;
+        }
+        if std::time::Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(CalendarUpdaterError::FetchTimeout(anyhow!(
+                "Fetch command {:?} timed out after {:?}",
+                command,
+                timeout
+            )));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    };
+
+    let stdout = stdout
+        .join()
+        .map_err(|_| anyhow!("Fetch command reader panicked"))
+        .and_then(|read| read.with_context(|| format!("Reading output of fetch command {:?}", command)))
+        .map_err(CalendarUpdaterError::FetchError)?;
+    let stderr = stderr.join().unwrap_or_default();
+
+    if !status.success() {
+        return Err(CalendarUpdaterError::FetchError(anyhow!(
+            "Fetch command {:?} failed ({}): {}",
+            command,
+            status,
+            stderr.trim()
+        )));
+    }
+
+    Ok(stdout)
+}
+
+/// Extracts the charset parameter from a Content-Type header value
+fn content_type_charset(content_type: &str) -> Option<String> {
+    content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next()?.trim(), kv.next()) {
+                (k, Some(v)) if k.eq_ignore_ascii_case("charset") => {
+                    Some(v.trim().trim_matches('"').to_string())
+                }
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Converts raw ICS bytes to UTF-8 text, stripping any byte order mark. Latin-1 is supported
+/// when declared; undeclared data which isn't valid UTF-8 is also assumed to be Latin-1.
+fn decode_ics(data: Vec<u8>, charset: Option<&str>) -> Result<String> {
+    let latin1 = |data: &[u8]| data.iter().map(|&b| b as char).collect::<String>();
+
+    let declared_latin1 = charset
+        .map(|c| ["iso-8859-1", "latin1", "latin-1", "us-ascii"].iter().any(|l| c.eq_ignore_ascii_case(l)))
+        .unwrap_or(false);
+
+    match charset {
+        Some(c) if !declared_latin1 && !c.eq_ignore_ascii_case("utf-8") && !c.eq_ignore_ascii_case("utf8") => {
+            bail!("Unsupported calendar charset {:?}", c)
+        }
+        _ => (),
+    }
+
+    let text = if declared_latin1 {
+        latin1(&data)
+    } else {
+        match String::from_utf8(data) {
+            Ok(text) => text,
+            Err(e) => {
+                warn!("Calendar data is not valid UTF-8 ({}); decoding as Latin-1", e.utf8_error());
+                latin1(e.as_bytes())
+            }
+        }
+    };
+
+    Ok(text.strip_prefix('\u{feff}').map(|s| s.to_string()).unwrap_or(text))
+}
+
+/// Fetches every configured calendar. When merging several, one failing is only a warning;
+/// the run fails only if none could be fetched.
+#[tracing::instrument(skip(opts))]
+fn get_calendar_data(opts: &FetchOptions) -> Result<Vec<IcalCalendar>, CalendarUpdaterError> {
     info!("Fetching ical data...");
 
-    let data = reqwest::blocking::get(CALENDAR_URL)?
-        .error_for_status()?
-        .bytes()?;
+    if let Some(command) = &opts.fetch_command {
+        let data = run_fetch_command(command, opts.fetch_timeout)?;
+        return Ok(vec![parse_ical(data, None).map_err(CalendarUpdaterError::ParseError)?]);
+    }
+
+    if let Some(path) = &opts.calendar_file {
+        let data = std::fs::read(path)
+            .with_context(|| format!("Reading calendar file {}", path))
+            .map_err(CalendarUpdaterError::FetchError)?;
+        return Ok(vec![parse_ical(data, None).map_err(CalendarUpdaterError::ParseError)?]);
+    }
+
+    let urls: Vec<&str> = if opts.calendar_urls.is_empty() {
+        vec![CALENDAR_URL]
+    } else {
+        opts.calendar_urls.iter().map(|url| url.as_str()).collect()
+    };
+
+    let client = http_client(opts).map_err(CalendarUpdaterError::FetchError)?;
+    let mut calendars = Vec::with_capacity(urls.len());
+    let mut last_error = None;
+    for url in urls.iter().copied() {
+        match fetch_url(&client, url, opts.cache.as_ref()) {
+            Ok(calendar) => calendars.push(calendar),
+            Err(e) if urls.len() > 1 => {
+                warn!("Failed to fetch calendar {}: {:#}", url, e);
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    match last_error {
+        Some(e) if calendars.is_empty() => {
+            error!("Failed to fetch any calendar");
+            Err(e)
+        }
+        _ => Ok(calendars),
+    }
+}
+
+fn http_client(opts: &FetchOptions) -> Result<reqwest::blocking::Client> {
+    let mut builder = reqwest::blocking::Client::builder().timeout(opts.fetch_timeout);
+    if let Some(proxy) = &opts.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy.as_str()).with_context(|| format!("Bad proxy {:?}", proxy))?);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Returns the ICS bytes at `url`, along with the charset from the response's Content-Type
+fn download(client: &reqwest::blocking::Client, url: &str) -> reqwest::Result<(Vec<u8>, Option<String>)> {
+    let response = client.get(url).send()?.error_for_status()?;
+    let charset = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(content_type_charset);
+
+    Ok((response.bytes()?.to_vec(), charset))
+}
+
+/// Fetches and parses `url`, going through the cache if there is one. A cached copy is used
+/// as-is while fresh, and as a fallback (however old) when the fetch fails.
+fn fetch_url(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    cache: Option<&FetchCache>,
+) -> Result<IcalCalendar, CalendarUpdaterError> {
+    let cached = match cache.map(|cache| cache.load(url)).transpose() {
+        Ok(cached) => cached.flatten(),
+        Err(e) => {
+            warn!("Ignoring unreadable cache for {}: {:#}", url, e);
+            None
+        }
+    };
+
+    let (data, charset) = match (cache, cached) {
+        (Some(cache), Some(cached)) if cache.is_fresh(&cached) => {
+            info!("Using copy of {} cached at {}", url, cached.fetched_at);
+            (cached.data, cached.charset)
+        }
+        (cache, cached) => match download(client, url) {
+            Ok((data, charset)) => {
+                if let Some(cache) = cache {
+                    if let Err(e) = cache.save(url, &data, charset.as_deref()) {
+                        warn!("Failed to cache {}: {:#}", url, e);
+                    }
+                }
+                (data, charset)
+            }
+            Err(e) => match cached {
+                Some(cached) => {
+                    warn!("Failed to fetch {}; using the copy cached at {}: {:#}", url, cached.fetched_at, e);
+                    (cached.data, cached.charset)
+                }
+                None => return Err(CalendarUpdaterError::from_reqwest(e)),
+            },
+        },
+    };
+
+    parse_ical(data, charset.as_deref())
+        .with_context(|| format!("Parsing calendar {}", url))
+        .map_err(CalendarUpdaterError::ParseError)
+}
+
+/// Joins folded lines (RFC 5545 3.1): a line break followed by a space or tab continues the
+/// previous line, with exactly that one whitespace character removed. The ical parser doesn't
+/// always do this itself, which truncated long titles.
+fn unfold(data: &str) -> String {
+    let mut out = String::with_capacity(data.len());
+    let mut rest = data;
+
+    while let Some(pos) = rest.find('\n') {
+        let (line, after) = rest.split_at(pos);
+        let after = &after[1..];
+
+        if after.starts_with(' ') || after.starts_with('\t') {
+            out.push_str(line.strip_suffix('\r').unwrap_or(line));
+            rest = &after[1..];
+        } else {
+            out.push_str(line);
+            out.push('\n');
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+fn parse_ical(data: Vec<u8>, charset: Option<&str>) -> Result<IcalCalendar> {
+    let data = unfold(&decode_ics(data, charset)?);
 
     info!("Parsing ical data...");
 
-    let mut ical = ical::IcalParser::new(&data[..]);
+    let mut ical = ical::IcalParser::new(data.as_bytes());
 
     ical.next()
         .ok_or_else(|| anyhow!("No calendars parsed"))?
@@ -155,80 +877,1074 @@ fn unescape(s: &mut String) {
     *s = s_tmp;
 }
 
-pub(crate) fn fetch_calendar() -> Result<Vec<super::CalendarDay>> {
-    let raw_data = get_calendar_data()?;
+/// Escapes a TEXT value; the inverse of `unescape`
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => (),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Appends a content line, folded so no physical line exceeds 75 octets (RFC 5545 3.1)
+fn push_ics_line(out: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+/// Serializes the processed events back to iCalendar, so the exact set shown on the board can
+/// be subscribed to. Times are written in UTC.
+pub(crate) fn write_ics(days: &[super::CalendarDay], now: DateTime<FixedOffset>) -> String {
+    const UTC_FMT: &str = "%Y%m%dT%H%M%SZ";
+    let utc = |dt: &DateTime<FixedOffset>| dt.with_timezone(&Utc).format(UTC_FMT).to_string();
+
+    let mut out = String::new();
+    push_ics_line(&mut out, "BEGIN:VCALENDAR");
+    push_ics_line(&mut out, "VERSION:2.0");
+    push_ics_line(&mut out, "PRODID:-//bd_//vrc-calendar-updater//EN");
+
+    // Occurrences of a recurring event share its UID, so each is marked as a separate instance
+    let mut uid_counts = HashMap::new();
+    for event in days.iter().flat_map(|day| day.events.iter()) {
+        *uid_counts.entry(event.uid.as_str()).or_insert(0) += 1;
+    }
+
+    for event in days.iter().flat_map(|day| day.events.iter()) {
+        push_ics_line(&mut out, "BEGIN:VEVENT");
+        push_ics_line(&mut out, &format!("UID:{}", event.uid));
+        push_ics_line(&mut out, &format!("DTSTAMP:{}", utc(&now)));
+        if event.all_day {
+            push_ics_line(&mut out, &format!("DTSTART;VALUE=DATE:{}", event.start_time.format("%Y%m%d")));
+            if let Some(end) = event.end_time {
+                push_ics_line(&mut out, &format!("DTEND;VALUE=DATE:{}", end.format("%Y%m%d")));
+            }
+            if uid_counts[event.uid.as_str()] > 1 {
+                push_ics_line(&mut out, &format!("RECURRENCE-ID;VALUE=DATE:{}", event.start_time.format("%Y%m%d")));
+            }
+        } else {
+            push_ics_line(&mut out, &format!("DTSTART:{}", utc(&event.start_time)));
+            if let Some(end) = event.end_time {
+                push_ics_line(&mut out, &format!("DTEND:{}", utc(&end)));
+            }
+            if uid_counts[event.uid.as_str()] > 1 {
+                push_ics_line(&mut out, &format!("RECURRENCE-ID:{}", utc(&event.start_time)));
+            }
+        }
+        push_ics_line(&mut out, &format!("SUMMARY:{}", escape(&event.body)));
+        if let Some(location) = &event.location {
+            push_ics_line(&mut out, &format!("LOCATION:{}", escape(location)));
+        }
+        if event.cancelled {
+            push_ics_line(&mut out, "STATUS:CANCELLED");
+        }
+
+        let mut categories: Vec<&str> = event.category.iter().map(|c| c.as_str()).collect();
+        if event.full_width && !categories.iter().any(|c| c.eq_ignore_ascii_case(FULL_WIDTH_CATEGORY)) {
+            categories.push(FULL_WIDTH_CATEGORY);
+        }
+        if !categories.is_empty() {
+            let categories: Vec<String> = categories.iter().map(|c| escape(c)).collect();
+            push_ics_line(&mut out, &format!("CATEGORIES:{}", categories.join(",")));
+        }
+        if let Some((lat, long)) = event.geo {
+            push_ics_line(&mut out, &format!("GEO:{};{}", lat, long));
+        }
+        push_ics_line(&mut out, "END:VEVENT");
+    }
+
+    push_ics_line(&mut out, "END:VCALENDAR");
+    out
+}
+
+/// Normalizes an event body for duplicate detection: case and runs of whitespace are ignored
+fn normalize_body(body: &str) -> String {
+    body.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Drops events which duplicate an earlier one in the same day; duplicates anywhere in the
+/// day are dropped, not just adjacent ones
+fn remove_duplicates(events: &mut Vec<CalendarEvent>, dedup_by: DedupBy) {
+    let mut seen_uids = HashSet::new();
+    let mut seen_content = HashSet::new();
+
+    events.retain(|event| match dedup_by {
+        DedupBy::Uid => seen_uids.insert((event.uid.clone(), event.start_time)),
+        DedupBy::Content => {
+            seen_content.insert((event.start_time, event.end_time, normalize_body(&event.body)))
+        }
+        DedupBy::None => true,
+    });
+}
+
+/// Whether an event lasts long enough that its end time is probably a data-entry mistake
+fn looks_too_long(duration: chrono::Duration) -> bool {
+    duration > chrono::Duration::hours(LONG_EVENT_WARNING_HOURS)
+}
+
+fn check_event_duration(ev: &ParsedEntry, max_duration: Option<chrono::Duration>) -> bool {
+    let duration = match ev.dtend {
+        Some(dtend) => dtend.signed_duration_since(ev.dtstart),
+        None => return true,
+    };
 
-    let now = Local::now();
-    let one_week_later = now
+    if looks_too_long(duration) {
+        warn!(
+            uid = ev.uid,
+            summary = ev.summary,
+            "Event lasts {} hours; is the end time correct?",
+            duration.num_hours()
+        );
+    }
+
+    match max_duration {
+        Some(max) if duration > max => {
+            warn!(uid = ev.uid, summary = ev.summary, "Dropping event exceeding maximum duration");
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Replaces each recurring event with its occurrences up to `limit`, skipping those listed in
+/// EXDATE or replaced by an edited occurrence (which is listed as an event of its own)
+fn expand_recurrences<'a>(
+    events: Vec<ParsedEntry<'a>>,
+    opts: &FetchOptions,
+    limit: DateTime<FixedOffset>,
+) -> Vec<ParsedEntry<'a>> {
+    let overrides: Vec<(&str, IcalDate)> = events
+        .iter()
+        .filter_map(|e| e.recurrence_id.map(|id| (e.uid, id)))
+        .collect();
+    // Occurrences ending before this can't be shown, however early starts are grouped
+    let cutoff = opts.now - chrono::Duration::days(2);
+
+    let mut expanded = Vec::with_capacity(events.len());
+    for event in events {
+        let rule = match &event.recurrence {
+            Some(rule) => rule,
+            None => {
+                expanded.push(event);
+                continue;
+            }
+        };
+
+        for start in rule.occurrences(event.dtstart, opts.zone, limit) {
+            let cancelled = event.exdates.iter().any(|d| d.matches(start))
+                || overrides.iter().any(|&(uid, id)| uid == event.uid && id.matches(start));
+            if cancelled {
+                continue;
+            }
+
+            let end = event.dtend.map(|dtend| {
+                if event.all_day {
+                    let days = dtend.naive_local().date() - event.dtstart.naive_local().date();
+                    opts.zone.start_of_day(start.naive_local().date() + days)
+                } else {
+                    start + (dtend - event.dtstart)
+                }
+            });
+            if end.unwrap_or(start) < cutoff {
+                continue;
+            }
+
+            expanded.push(ParsedEntry {
+                dtstart: start,
+                dtend: end,
+                recurrence: None,
+                ..event.clone()
+            });
+        }
+    }
+
+    expanded
+}
+
+/// Returns true if this error only affects a single event (missing properties or a bad
+/// date), rather than indicating a broken feed
+fn is_malformed_event(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<CalendarFetchError>().is_some() || e.downcast_ref::<chrono::ParseError>().is_some()
+}
+
+/// Fetches the calendar, returning the days to display along with any events that were skipped
+pub(crate) fn fetch_calendar(
+    opts: &FetchOptions,
+) -> Result<(Vec<super::CalendarDay>, Vec<ParseWarning>), CalendarUpdaterError> {
+    let calendars = in_timed_span(info_span!("fetch", elapsed_ms = field::Empty), |_| get_calendar_data(opts))?;
+    // Each event, with the index of the feed it came from
+    let raw_events: Vec<(usize, &IcalEvent)> = calendars
+        .iter()
+        .enumerate()
+        .flat_map(|(feed, calendar)| calendar.events.iter().map(move |event| (feed, event)))
+        .collect();
+
+    let now = opts.now;
+    // Midnight after the last day shown (the eighth day, by default); events are shown if they
+    // start before this (or exactly at it, with WindowEnd::Inclusive)
+    let lookahead_end = now
         .date()
-        .checked_add_signed(chrono::Duration::days(7))
+        .checked_add_signed(chrono::Duration::days(opts.days_ahead as i64))
         .expect("Date overflow")
         .and_hms(0, 0, 0);
+    let last_day = lookahead_end.date().pred();
+    // Everything left after filtering starts no later than lookahead_end, so this only moves an
+    // event starting exactly at it, which would otherwise start a day of its own
+    let listed_under = |ev: &ParsedEntry| {
+        listing_date(ev.dtstart, opts.group_early_starts && !ev.all_day, opts.rollover_hour).min(last_day)
+    };
 
     info!("Processing entries...");
 
-    let mut events = Vec::with_capacity(raw_data.events.len());
-    let mut parse_errors = 0;
-    for event in raw_data.events.iter() {
-        match ParsedEntry::try_from(event) {
-            Ok(e) => events.push(e),
-            Err(e) => {
-                eprintln!(
-                    "Warning: Failed to parse event: {}; raw event: {:?}",
-                    e, event
-                );
-                parse_errors += 1;
-                if parse_errors > 10 {
-                    bail!("Too many parse errors");
+    let parse_span = info_span!(
+        "parse",
+        entries = raw_events.len() as u64,
+        skipped = field::Empty,
+        elapsed_ms = field::Empty
+    );
+    let (events, warnings) = in_timed_span(parse_span, |span| -> Result<_> {
+        let mut events = Vec::with_capacity(raw_events.len());
+        let mut parse_errors = 0;
+        let mut warnings = Vec::new();
+        // Feed each (UID, start) was first seen in; the same event in another feed is dropped
+        let mut first_feed = HashMap::new();
+        for (index, (feed, event)) in raw_events.iter().copied().enumerate() {
+            match ParsedEntry::try_from(event) {
+                Ok(e) if *first_feed.entry((e.uid, e.dtstart)).or_insert(feed) != feed => {
+                    debug!(uid = e.uid, "Skipping event already present in another feed");
+                }
+                Ok(mut e) => {
+                    if e.all_day {
+                        let zone = opts.zone;
+                        e.dtstart = zone.start_of_day(e.dtstart.naive_utc().date());
+                        e.dtend = e.dtend.map(|dtend| zone.start_of_day(dtend.naive_utc().date()));
+                    } else {
+                        e.dtstart = opts.zone.convert(&e.dtstart);
+                        e.dtend = e.dtend.map(|dtend| opts.zone.convert(&dtend));
+                    }
+                    events.push(e)
+                }
+                Err(e) if opts.strict => {
+                    return Err(e.context(format!("Malformed event: {:?}", event)));
+                }
+                Err(e) if is_malformed_event(&e) => {
+                    warn!("Skipping malformed event: {:#}; raw event: {:?}", e, event);
+                    warnings.push(ParseWarning::new(index, event, &e));
+                }
+                Err(e) => {
+                    warn!("Failed to parse event: {:#}; raw event: {:?}", e, event);
+                    warnings.push(ParseWarning::new(index, event, &e));
+                    parse_errors += 1;
+                    if parse_errors > 10 {
+                        bail!("Too many parse errors");
+                    }
                 }
             }
         }
-    }
+        span.record("skipped", &(warnings.len() as u64));
+        Ok((events, warnings))
+    })
+    .map_err(CalendarUpdaterError::ParseError)?;
+
+    info!("Expanding recurring entries...");
+    let mut events = expand_recurrences(events, opts, lookahead_end);
 
     info!("Filtering entries...");
+    let filter_span = info_span!("filter", remaining = field::Empty, elapsed_ms = field::Empty);
+    in_timed_span(filter_span, |span| {
+        let mut start_date = now.date();
+        if now.time().hour() < opts.rollover_hour {
+            start_date = start_date.pred();
+        }
 
-    let mut start_date = now.date();
-    if now.time().hour() < 3 {
-        start_date = start_date.pred();
-    }
+        events.retain(|ev| {
+            let before_end = match opts.window_end {
+                WindowEnd::Inclusive => {
+                    ev.dtstart < lookahead_end || (ev.dtstart == lookahead_end && !ev.all_day)
+                }
+                WindowEnd::Exclusive => ev.dtstart < lookahead_end,
+            };
 
-    events.retain(|ev| {
-        (ev.dtstart.date() >= start_date && ev.dtstart < one_week_later)
-            || ev
-                .dtend
-                .map(|end| ev.dtstart <= now && end >= now)
-                .unwrap_or(false)
+            (listing_date(ev.dtstart, opts.group_early_starts && !ev.all_day, opts.rollover_hour) >= start_date
+                && before_end)
+                || ev
+                    .dtend
+                    .map(|end| ev.dtstart <= now && (end > now || (end == now && !ev.all_day)))
+                    .unwrap_or(false)
+        });
+        if opts.hide_transparent {
+            events.retain(|ev| !ev.transparent);
+        }
+        if !opts.show_cancelled {
+            events.retain(|ev| !ev.cancelled);
+        }
+        if opts.hide_ended {
+            // Events still in progress have an end time after now, so they are kept
+            events.retain(|ev| ev.dtend.map(|end| end >= now).unwrap_or(true));
+        }
+        events.retain(|ev| check_event_duration(ev, opts.max_event_duration));
+        // Grouped by the day they're listed under; all-day events come first in each day
+        events.sort_by_key(|ev| (listed_under(ev).naive_local(), !ev.all_day, ev.dtstart, ev.dtend, ev.summary));
+        span.record("remaining", &(events.len() as u64));
     });
-    events.sort_by_key(|ev| (ev.dtstart, ev.dtend, ev.summary));
 
     info!("Generating final CalendarEvents...");
 
     let mut days = Vec::new();
-    let group_by = events.iter().group_by(|&ev| ev.dtstart.date());
+    let group_by = events.iter().group_by(|&ev| listed_under(ev));
     for (date, daygroup) in &group_by {
         let mut events = Vec::new();
 
         for event in daygroup {
+            let full_width_prefix = event.summary.starts_with(FULL_WIDTH_PREFIX);
+            let full_width = full_width_prefix
+                || event.categories.iter().any(|c| c.eq_ignore_ascii_case(FULL_WIDTH_CATEGORY));
+            let body = if full_width_prefix {
+                event.summary[FULL_WIDTH_PREFIX.len()..].trim_start()
+            } else {
+                event.summary
+            };
+
             let mut event = CalendarEvent {
+                uid: event.uid.into(),
                 start_time: event.dtstart,
                 end_time: event.dtend,
-                body: event.summary.into(),
+                body: body.into(),
+                location: event.location.map(Into::into),
+                description: event.description.map(Into::into),
+                geo: event.geo,
+                category: event.categories.first().map(|&s| s.into()),
+                full_width,
+                all_day: event.all_day,
+                cancelled: event.cancelled,
             };
 
-            let prior_event = events.len().checked_sub(1)
-                .map(|i| &events[i]);
-            
-            if Some(&event) == prior_event {
-                continue;
-            }
-
             unescape(&mut event.body);
+            if let Some(location) = &mut event.location {
+                unescape(location);
+            }
+            if let Some(description) = &mut event.description {
+                unescape(description);
+            }
 
             events.push(event);
         }
+        remove_duplicates(&mut events, opts.dedup_by);
+
+        // Events are already in time order; these sorts are stable, so ties stay that way
+        match opts.sort_within_day {
+            DaySort::Time => (),
+            DaySort::Alpha => events.sort_by(|a, b| a.body.cmp(&b.body)),
+            DaySort::Category => {
+                events.sort_by(|a, b| match (&a.category, &b.category) {
+                    (Some(a), Some(b)) => a.cmp(b),
+                    (Some(_), None) => std::cmp::Ordering::Less,
+                    (None, Some(_)) => std::cmp::Ordering::Greater,
+                    (None, None) => std::cmp::Ordering::Equal,
+                })
+            }
+        }
+        // Whatever the order, all-day events stay at the top of the day
+        events.sort_by_key(|event| !event.all_day);
 
         days.push(super::CalendarDay { date, events });
     }
 
-    Ok(days)
+    Ok((days, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jst(s: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(s).unwrap()
+    }
+
+    /// Options for showing a week from 2021-06-01 12:00 JST, with no early-start grouping
+    fn fetch_options() -> FetchOptions {
+        FetchOptions {
+            max_event_duration: None,
+            group_early_starts: false,
+            rollover_hour: 0,
+            strict: true,
+            zone: "Asia/Tokyo".parse().unwrap(),
+            now: jst("2021-06-01T12:00:00+09:00"),
+            sort_within_day: DaySort::Time,
+            hide_ended: false,
+            hide_transparent: false,
+            show_cancelled: false,
+            calendar_urls: vec![],
+            fetch_command: None,
+            calendar_file: None,
+            cache: None,
+            fetch_timeout: std::time::Duration::from_secs(30),
+            proxy: None,
+            window_end: WindowEnd::Inclusive,
+            days_ahead: 7,
+            dedup_by: DedupBy::Content,
+        }
+    }
+
+    /// Runs fetch_calendar over the given events, read back from an ICS file
+    fn fetch(name: &str, events: Vec<CalendarEvent>, opts: FetchOptions) -> Vec<crate::CalendarDay> {
+        let path = std::env::temp_dir()
+            .join(format!("calendar-updater-test-{}-{}.ics", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned();
+        let day = crate::CalendarDay { date: opts.now.date(), events };
+        std::fs::write(&path, write_ics(&[day], opts.now)).unwrap();
+
+        let result = fetch_calendar(&FetchOptions { calendar_file: Some(path.clone()), ..opts });
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap().0
+    }
+
+    /// Runs fetch_calendar over the given ICS data, returning the days and the skipped events
+    fn fetch_ics(name: &str, ics: &str, opts: FetchOptions) -> (Vec<crate::CalendarDay>, Vec<ParseWarning>) {
+        let path = std::env::temp_dir()
+            .join(format!("calendar-updater-test-{}-{}.ics", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned();
+        std::fs::write(&path, ics).unwrap();
+
+        let result = fetch_calendar(&FetchOptions { calendar_file: Some(path.clone()), ..opts });
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap()
+    }
+
+    fn event(uid: &str, start: &str, end: &str, body: &str) -> CalendarEvent {
+        CalendarEvent {
+            uid: uid.into(),
+            start_time: jst(start),
+            end_time: Some(jst(end)),
+            body: body.into(),
+            location: None,
+            description: None,
+            geo: None,
+            category: None,
+            full_width: false,
+            all_day: false,
+            cancelled: false,
+        }
+    }
+
+    #[test]
+    fn unfold_joins_crlf_space_continuation() {
+        assert_eq!(unfold("SUMMARY:Long\r\n  title\r\n"), "SUMMARY:Long title\r\n");
+    }
+
+    #[test]
+    fn unfold_joins_lf_tab_continuation() {
+        assert_eq!(unfold("SUMMARY:Long\n\ttitle\n"), "SUMMARY:Longtitle\n");
+    }
+
+    #[test]
+    fn unfold_joins_line_folded_several_times() {
+        assert_eq!(
+            unfold("DESCRIPTION:one\r\n  two\r\n  three\r\nEND:VEVENT\r\n"),
+            "DESCRIPTION:one two three\r\nEND:VEVENT\r\n"
+        );
+    }
+
+    #[test]
+    fn unfold_leaves_unfolded_lines_alone() {
+        let data = "BEGIN:VEVENT\r\nSUMMARY:Title\r\nEND:VEVENT";
+        assert_eq!(unfold(data), data);
+    }
+
+    #[test]
+    fn decode_ics_strips_byte_order_mark() {
+        let text = decode_ics(b"\xef\xbb\xbfBEGIN:VCALENDAR".to_vec(), None).unwrap();
+        assert_eq!(text, "BEGIN:VCALENDAR");
+    }
+
+    #[test]
+    fn decode_ics_falls_back_to_latin1() {
+        assert_eq!(decode_ics(b"SUMMARY:caf\xe9".to_vec(), None).unwrap(), "SUMMARY:café");
+        assert_eq!(decode_ics(b"SUMMARY:caf\xe9".to_vec(), Some("ISO-8859-1")).unwrap(), "SUMMARY:café");
+    }
+
+    #[test]
+    fn decode_ics_passes_utf8_through() {
+        let text = "SUMMARY:集会 café";
+        assert_eq!(decode_ics(text.as_bytes().to_vec(), None).unwrap(), text);
+        assert_eq!(decode_ics(text.as_bytes().to_vec(), Some("utf-8")).unwrap(), text);
+    }
+
+    #[test]
+    fn write_ics_round_trips_through_parser() {
+        let body = "VRChat 技術学術系集会, 定例会; 初心者歓迎 \\ 途中参加・途中退出もお気軽にどうぞ";
+        let days = vec![crate::CalendarDay {
+            date: jst("2021-06-01T00:00:00+09:00").date(),
+            events: vec![
+                event("a@example.com", "2021-06-01T21:00:00+09:00", "2021-06-01T23:00:00+09:00", body),
+                event("b@example.com", "2021-06-01T22:00:00+09:00", "2021-06-01T23:30:00+09:00", "雑談"),
+            ],
+        }];
+
+        let ics = write_ics(&days, jst("2021-06-01T12:00:00+09:00"));
+        // The summary is too long for one line, so it must have been folded
+        assert!(ics.contains("\r\n "));
+        assert!(ics.lines().all(|line| line.len() <= 75));
+        assert!(ics.contains("\\,") && ics.contains("\\;") && ics.contains("\\\\"));
+
+        let calendar = parse_ical(ics.into_bytes(), None).unwrap();
+        assert_eq!(calendar.events.len(), 2);
+
+        let parsed = ParsedEntry::try_from(&calendar.events[0]).unwrap();
+        let mut summary = parsed.summary.to_string();
+        unescape(&mut summary);
+        assert_eq!(summary, body);
+        assert_eq!(parsed.uid, "a@example.com");
+        assert_eq!(parsed.dtstart, jst("2021-06-01T21:00:00+09:00"));
+        assert_eq!(parsed.dtend, Some(jst("2021-06-01T23:00:00+09:00")));
+    }
+
+    /// A day with two events sharing a UID (but not a title), and two copies of one event
+    /// under different UIDs
+    fn duplicated_day() -> Vec<CalendarEvent> {
+        vec![
+            event("reused@example.com", "2021-06-01T21:00:00+09:00", "2021-06-01T22:00:00+09:00", "定例会"),
+            event("reused@example.com", "2021-06-01T21:00:00+09:00", "2021-06-01T22:00:00+09:00", "勉強会"),
+            event("a@example.com", "2021-06-01T22:00:00+09:00", "2021-06-01T23:00:00+09:00", "Social  Night"),
+            event("b@example.com", "2021-06-01T22:00:00+09:00", "2021-06-01T23:00:00+09:00", "social night"),
+        ]
+    }
+
+    fn bodies_after_dedup(dedup_by: DedupBy) -> Vec<String> {
+        let mut events = duplicated_day();
+        remove_duplicates(&mut events, dedup_by);
+        events.into_iter().map(|e| e.body).collect()
+    }
+
+    #[test]
+    fn dedup_by_uid_drops_reused_uids() {
+        assert_eq!(bodies_after_dedup(DedupBy::Uid), vec!["定例会", "Social  Night", "social night"]);
+    }
+
+    #[test]
+    fn dedup_by_content_drops_matching_events() {
+        assert_eq!(bodies_after_dedup(DedupBy::Content), vec!["定例会", "勉強会", "Social  Night"]);
+    }
+
+    #[test]
+    fn dedup_collapses_bodies_equal_after_normalization() {
+        assert_eq!(normalize_body(" Social\n  Night \t"), "social night");
+
+        // The newline is escaped in the ICS data and only restored by unescape
+        let events = vec![
+            event("a@example.com", "2021-06-02T22:00:00+09:00", "2021-06-02T23:00:00+09:00", "Social Night "),
+            event("b@example.com", "2021-06-02T22:00:00+09:00", "2021-06-02T23:00:00+09:00", "social\nnight"),
+        ];
+        let days = fetch("dedup-normalized", events, fetch_options());
+
+        let uids: Vec<&str> = days.iter().flat_map(|day| day.events.iter()).map(|e| e.uid.as_str()).collect();
+        assert_eq!(uids, vec!["a@example.com"]);
+    }
+
+    #[test]
+    fn dedup_by_none_keeps_everything() {
+        assert_eq!(
+            bodies_after_dedup(DedupBy::None),
+            vec!["定例会", "勉強会", "Social  Night", "social night"]
+        );
+    }
+
+    /// An event exactly at the midnight ending the window, and one a minute earlier
+    fn window_boundary_events() -> Vec<CalendarEvent> {
+        vec![
+            event("before@example.com", "2021-06-07T23:59:00+09:00", "2021-06-08T01:00:00+09:00", "直前"),
+            event("at@example.com", "2021-06-08T00:00:00+09:00", "2021-06-08T01:00:00+09:00", "境界"),
+        ]
+    }
+
+    #[test]
+    fn inclusive_window_lists_boundary_event_under_final_day() {
+        let days = fetch("inclusive", window_boundary_events(), fetch_options());
+
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].date.naive_local(), NaiveDate::from_ymd(2021, 6, 7));
+        let bodies: Vec<&str> = days[0].events.iter().map(|e| e.body.as_str()).collect();
+        assert_eq!(bodies, vec!["直前", "境界"]);
+    }
+
+    #[test]
+    fn exclusive_window_drops_boundary_event() {
+        let opts = FetchOptions { window_end: WindowEnd::Exclusive, ..fetch_options() };
+        let days = fetch("exclusive", window_boundary_events(), opts);
+
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].date.naive_local(), NaiveDate::from_ymd(2021, 6, 7));
+        let bodies: Vec<&str> = days[0].events.iter().map(|e| e.body.as_str()).collect();
+        assert_eq!(bodies, vec!["直前"]);
+    }
+
+    #[test]
+    fn days_ahead_limits_the_window() {
+        let events = || {
+            vec![
+                event("ongoing@example.com", "2021-05-30T18:00:00+09:00", "2021-06-02T18:00:00+09:00", "Ongoing"),
+                event("tomorrow@example.com", "2021-06-02T21:00:00+09:00", "2021-06-02T22:00:00+09:00", "Tomorrow"),
+                event("later@example.com", "2021-06-06T21:00:00+09:00", "2021-06-06T22:00:00+09:00", "Five days out"),
+            ]
+        };
+        let bodies = |name: &str, days_ahead: u32| -> Vec<String> {
+            let opts = FetchOptions { days_ahead, ..fetch_options() };
+            fetch(name, events(), opts).into_iter().flat_map(|day| day.events).map(|e| e.body).collect()
+        };
+
+        assert_eq!(bodies("three-days", 3), vec!["Ongoing", "Tomorrow"]);
+        assert_eq!(bodies("seven-days", 7), vec!["Ongoing", "Tomorrow", "Five days out"]);
+    }
+
+    #[test]
+    fn listing_date_follows_rollover_hour() {
+        let date = |s: &str| listing_date(jst(s), true, 3).naive_local();
+        assert_eq!(date("2021-06-02T02:59:00+09:00"), NaiveDate::from_ymd(2021, 6, 1));
+        assert_eq!(date("2021-06-02T03:00:00+09:00"), NaiveDate::from_ymd(2021, 6, 2));
+
+        let date = |s: &str| listing_date(jst(s), true, 0).naive_local();
+        assert_eq!(date("2021-06-02T00:00:00+09:00"), NaiveDate::from_ymd(2021, 6, 2));
+
+        let date = |s: &str| listing_date(jst(s), true, 6).naive_local();
+        assert_eq!(date("2021-06-02T05:59:00+09:00"), NaiveDate::from_ymd(2021, 6, 1));
+        assert_eq!(date("2021-06-02T06:00:00+09:00"), NaiveDate::from_ymd(2021, 6, 2));
+
+        // Without grouping, the rollover hour only affects which day is shown first
+        assert_eq!(
+            listing_date(jst("2021-06-02T02:00:00+09:00"), false, 3).naive_local(),
+            NaiveDate::from_ymd(2021, 6, 2)
+        );
+    }
+
+    #[test]
+    fn previous_day_is_shown_until_rollover_hour() {
+        // At 02:00, the previous evening's events are still shown unless the day rolls over at
+        // midnight
+        let shown_days = |rollover_hour| {
+            let opts = FetchOptions {
+                now: jst("2021-06-02T02:00:00+09:00"),
+                rollover_hour,
+                group_early_starts: true,
+                ..fetch_options()
+            };
+            let events =
+                vec![event("late@example.com", "2021-06-01T23:00:00+09:00", "2021-06-02T01:00:00+09:00", "深夜")];
+            fetch(&format!("rollover-{}", rollover_hour), events, opts)
+                .iter()
+                .map(|day| day.date.naive_local())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(shown_days(0).is_empty());
+        assert_eq!(shown_days(3), vec![NaiveDate::from_ymd(2021, 6, 1)]);
+        assert_eq!(shown_days(6), vec![NaiveDate::from_ymd(2021, 6, 1)]);
+    }
+
+    #[test]
+    fn parse_geo_reads_latitude_and_longitude() {
+        assert_eq!(parse_geo("35.6812;139.7671"), Some((35.6812, 139.7671)));
+        assert_eq!(parse_geo(" -33.8688 ; 151.2093 "), Some((-33.8688, 151.2093)));
+    }
+
+    #[test]
+    fn parse_geo_rejects_malformed_values() {
+        assert_eq!(parse_geo(""), None);
+        assert_eq!(parse_geo("35.6812"), None);
+        assert_eq!(parse_geo("35.6812,139.7671"), None);
+        assert_eq!(parse_geo("north;east"), None);
+    }
+
+    #[test]
+    fn parse_geo_rejects_out_of_range_coordinates() {
+        assert_eq!(parse_geo("90.5;0"), None);
+        assert_eq!(parse_geo("-91;0"), None);
+        assert_eq!(parse_geo("0;180.1"), None);
+        assert_eq!(parse_geo("NaN;0"), None);
+        assert_eq!(parse_geo("90;-180"), Some((90.0, -180.0)));
+    }
+
+    /// One good event between two malformed ones: the first has no UID, the last a bad DTSTART
+    const MALFORMED_FIXTURE: &str = "BEGIN:VCALENDAR\r\n\
+        VERSION:2.0\r\n\
+        BEGIN:VEVENT\r\n\
+        DTSTART:20210602T210000+0900\r\n\
+        SUMMARY:No UID\r\n\
+        END:VEVENT\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:good@example.com\r\n\
+        DTSTART:20210602T220000+0900\r\n\
+        SUMMARY:Good\r\n\
+        END:VEVENT\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:bad-date@example.com\r\n\
+        DTSTART:tomorrow\r\n\
+        SUMMARY:Bad date\r\n\
+        END:VEVENT\r\n\
+        END:VCALENDAR\r\n";
+
+    #[test]
+    fn malformed_events_are_collected_as_warnings() {
+        let opts = FetchOptions { strict: false, ..fetch_options() };
+        let (days, warnings) = fetch_ics("malformed", MALFORMED_FIXTURE, opts);
+
+        let bodies: Vec<&str> = days.iter().flat_map(|day| day.events.iter()).map(|e| e.body.as_str()).collect();
+        assert_eq!(bodies, vec!["Good"]);
+
+        let skipped: Vec<&str> = warnings.iter().map(|w| w.event.as_str()).collect();
+        assert_eq!(skipped, vec!["#0", "bad-date@example.com"]);
+        assert!(warnings[0].reason.contains("UID"), "{}", warnings[0].reason);
+        assert!(warnings[1].reason.contains("DTSTART"), "{}", warnings[1].reason);
+    }
+
+    #[test]
+    fn hide_ended_drops_finished_events_but_keeps_running_ones() {
+        // At 12:00, one event ended an hour ago and one is still in progress
+        let today = || vec![
+            event("ended@example.com", "2021-06-01T10:00:00+09:00", "2021-06-01T11:00:00+09:00", "Ended"),
+            event("running@example.com", "2021-06-01T11:30:00+09:00", "2021-06-01T13:00:00+09:00", "Running"),
+        ];
+        let bodies = |days: Vec<crate::CalendarDay>| -> Vec<String> {
+            days.into_iter().flat_map(|day| day.events).map(|e| e.body).collect()
+        };
+
+        assert_eq!(bodies(fetch("show-ended", today(), fetch_options())), vec!["Ended", "Running"]);
+
+        let opts = FetchOptions { hide_ended: true, ..fetch_options() };
+        assert_eq!(bodies(fetch("hide-ended", today(), opts)), vec!["Running"]);
+    }
+
+    const TRANSPARENT_FIXTURE: &str = "BEGIN:VCALENDAR\r\n\
+        VERSION:2.0\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:busy@example.com\r\n\
+        DTSTART:20210602T210000+0900\r\n\
+        SUMMARY:Busy\r\n\
+        TRANSP:OPAQUE\r\n\
+        END:VEVENT\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:free@example.com\r\n\
+        DTSTART:20210602T220000+0900\r\n\
+        SUMMARY:Free\r\n\
+        TRANSP:TRANSPARENT\r\n\
+        END:VEVENT\r\n\
+        END:VCALENDAR\r\n";
+
+    #[test]
+    fn transparent_events_are_dropped_only_when_hidden() {
+        let bodies = |name: &str, opts: FetchOptions| -> Vec<String> {
+            let (days, _) = fetch_ics(name, TRANSPARENT_FIXTURE, opts);
+            days.into_iter().flat_map(|day| day.events).map(|e| e.body).collect()
+        };
+
+        assert_eq!(bodies("show-transparent", fetch_options()), vec!["Busy", "Free"]);
+
+        let opts = FetchOptions { hide_transparent: true, ..fetch_options() };
+        assert_eq!(bodies("hide-transparent", opts), vec!["Busy"]);
+    }
+
+    #[test]
+    fn cancelled_events_are_dropped_unless_shown() {
+        let ics = "BEGIN:VCALENDAR\r\n\
+            VERSION:2.0\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:on@example.com\r\n\
+            DTSTART:20210602T210000+0900\r\n\
+            SUMMARY:Going ahead\r\n\
+            STATUS:CONFIRMED\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:off@example.com\r\n\
+            DTSTART:20210602T220000+0900\r\n\
+            SUMMARY:Called off\r\n\
+            STATUS:CANCELLED\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:plain@example.com\r\n\
+            DTSTART:20210603T210000+0900\r\n\
+            SUMMARY:No status\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        let events = |name: &str, opts: FetchOptions| -> Vec<(String, bool)> {
+            let (days, _) = fetch_ics(name, ics, opts);
+            days.into_iter().flat_map(|day| day.events).map(|e| (e.body, e.cancelled)).collect()
+        };
+
+        assert_eq!(
+            events("hide-cancelled", fetch_options()),
+            vec![("Going ahead".to_string(), false), ("No status".to_string(), false)]
+        );
+
+        let opts = FetchOptions { show_cancelled: true, ..fetch_options() };
+        assert_eq!(
+            events("show-cancelled", opts),
+            vec![
+                ("Going ahead".to_string(), false),
+                ("Called off".to_string(), true),
+                ("No status".to_string(), false)
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fetch_command_output_is_parsed() {
+        let path = std::env::temp_dir()
+            .join(format!("calendar-updater-test-{}-fetch-command.ics", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        std::fs::write(&path, TRANSPARENT_FIXTURE).unwrap();
+
+        let opts = FetchOptions { fetch_command: Some(format!("cat '{}'", path)), ..fetch_options() };
+        let result = fetch_calendar(&opts);
+        std::fs::remove_file(&path).unwrap();
+
+        let bodies: Vec<String> = result.unwrap().0.into_iter().flat_map(|day| day.events).map(|e| e.body).collect();
+        assert_eq!(bodies, vec!["Busy", "Free"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fetch_command_failure_reports_stderr() {
+        match run_fetch_command("echo 'token expired' >&2; exit 3", std::time::Duration::from_secs(10)) {
+            Err(CalendarUpdaterError::FetchError(e)) => {
+                assert!(e.to_string().contains("token expired"), "{}", e);
+            }
+            other => panic!("expected FetchError, got {:?}", other.map(|data| data.len())),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fetch_command_times_out() {
+        match run_fetch_command("sleep 10", std::time::Duration::from_millis(100)) {
+            Err(CalendarUpdaterError::FetchTimeout(_)) => (),
+            other => panic!("expected FetchTimeout, got {:?}", other.map(|data| data.len())),
+        }
+    }
+
+    /// Serves `body` to a single HTTP request on localhost, returning the URL to fetch and a
+    /// handle yielding the request line that was received
+    fn serve_once(path: &str, body: &'static str) -> (reqwest::Url, std::thread::JoinHandle<String>) {
+        use std::io::{BufRead, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}{}", listener.local_addr().unwrap(), path);
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            // Skip the headers
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 200 OK\r\nContent-Type: text/calendar\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            request_line.trim_end().to_string()
+        });
+
+        (parse_calendar_url(&url).unwrap(), handle)
+    }
+
+    #[test]
+    fn calendar_url_is_fetched_instead_of_default() {
+        let (url, server) = serve_once("/other/basic.ics", TRANSPARENT_FIXTURE);
+        let opts = FetchOptions { calendar_urls: vec![url], ..fetch_options() };
+        let result = fetch_calendar(&opts);
+
+        assert_eq!(server.join().unwrap(), "GET /other/basic.ics HTTP/1.1");
+        let bodies: Vec<String> = result.unwrap().0.into_iter().flat_map(|day| day.events).map(|e| e.body).collect();
+        assert_eq!(bodies, vec!["Busy", "Free"]);
+    }
+
+    #[test]
+    fn calendar_url_must_be_http() {
+        assert!(parse_calendar_url("https://example.com/basic.ics").is_ok());
+        assert!(parse_calendar_url("example.com/basic.ics").is_err());
+        assert!(parse_calendar_url("ftp://example.com/basic.ics").is_err());
+    }
+
+    #[test]
+    fn calendar_file_is_parsed_into_days() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/sample.ics");
+        let opts = FetchOptions { calendar_file: Some(path.into()), ..fetch_options() };
+        let (days, warnings) = fetch_calendar(&opts).unwrap();
+        assert!(warnings.is_empty());
+
+        let dates: Vec<_> = days.iter().map(|day| day.date).collect();
+        assert_eq!(dates, vec![jst("2021-06-01T00:00:00+09:00").date(), jst("2021-06-03T00:00:00+09:00").date()]);
+
+        let meetup = &days[0].events[0];
+        assert_eq!(meetup.body, "Evening meetup");
+        assert_eq!(meetup.location.as_deref(), Some("Great Pug"));
+        assert_eq!(meetup.start_time, jst("2021-06-01T20:00:00+09:00"));
+        assert_eq!(meetup.end_time, Some(jst("2021-06-01T22:00:00+09:00")));
+
+        let bodies: Vec<&str> = days[1].events.iter().map(|e| e.body.as_str()).collect();
+        assert_eq!(bodies, vec!["Anniversary", "Lunch world hop"]);
+        assert!(days[1].events[0].all_day);
+    }
+
+    #[test]
+    fn missing_calendar_file_is_a_fetch_error() {
+        let opts = FetchOptions { calendar_file: Some("/nonexistent/calendar.ics".into()), ..fetch_options() };
+        match fetch_calendar(&opts) {
+            Err(CalendarUpdaterError::FetchError(e)) => assert!(format!("{:#}", e).contains("/nonexistent/calendar.ics")),
+            other => panic!("expected FetchError, got {:?}", other.map(|(days, _)| days.len())),
+        }
+    }
+
+    #[test]
+    fn date_only_dtend_is_exclusive() {
+        let ics = "BEGIN:VCALENDAR\r\n\
+            VERSION:2.0\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:weekend@example.com\r\n\
+            DTSTART;VALUE=DATE:20210530\r\n\
+            DTEND;VALUE=DATE:20210601\r\n\
+            SUMMARY:Weekend\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        let opts = FetchOptions { now: jst("2021-05-30T12:00:00+09:00"), ..fetch_options() };
+        let (days, _) = fetch_ics("date-only-dtend", ics, opts);
+
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].date, jst("2021-05-30T00:00:00+09:00").date());
+        let event = &days[0].events[0];
+        assert!(event.all_day);
+        assert_eq!(event.end_time, Some(jst("2021-06-01T00:00:00+09:00")));
+
+        // Shown as covering the 30th through the 31st, not the 1st
+        let labels = crate::labels::Labels::default();
+        assert_eq!(crate::format_end(event, 3, &labels).as_deref(), Some("~05/31 (月)"));
+    }
+
+    #[test]
+    fn all_day_events_come_before_timed_events() {
+        let day = || {
+            let mut all_day = event("b@example.com", "2021-06-02T00:00:00+09:00", "2021-06-03T00:00:00+09:00", "Zoo day");
+            all_day.all_day = true;
+            vec![
+                event("a@example.com", "2021-06-02T00:00:00+09:00", "2021-06-02T01:00:00+09:00", "Midnight"),
+                all_day,
+                event("c@example.com", "2021-06-02T21:00:00+09:00", "2021-06-02T22:00:00+09:00", "Evening"),
+            ]
+        };
+        let bodies = |name: &str, sort: DaySort| -> Vec<String> {
+            let opts = FetchOptions { sort_within_day: sort, ..fetch_options() };
+            fetch(name, day(), opts).into_iter().flat_map(|day| day.events).map(|e| e.body).collect()
+        };
+
+        assert_eq!(bodies("all-day-time", DaySort::Time), vec!["Zoo day", "Midnight", "Evening"]);
+        assert_eq!(bodies("all-day-alpha", DaySort::Alpha), vec!["Zoo day", "Evening", "Midnight"]);
+    }
+
+    /// Three events on one day, in time order: (body, category)
+    fn unsorted_day() -> Vec<CalendarEvent> {
+        [("Zeta", Some("music")), ("Alpha", None), ("Mid", Some("art"))]
+            .iter()
+            .enumerate()
+            .map(|(i, &(body, category))| CalendarEvent {
+                category: category.map(Into::into),
+                ..event(
+                    &format!("{}@example.com", i),
+                    &format!("2021-06-02T{}:00:00+09:00", 20 + i),
+                    &format!("2021-06-02T{}:30:00+09:00", 20 + i),
+                    body,
+                )
+            })
+            .collect()
+    }
+
+    fn bodies_sorted_by(name: &str, sort: DaySort) -> Vec<String> {
+        let opts = FetchOptions { sort_within_day: sort, ..fetch_options() };
+        fetch(name, unsorted_day(), opts).into_iter().flat_map(|day| day.events).map(|e| e.body).collect()
+    }
+
+    #[test]
+    fn sort_within_day_by_time() {
+        assert_eq!(bodies_sorted_by("sort-time", DaySort::Time), vec!["Zeta", "Alpha", "Mid"]);
+    }
+
+    #[test]
+    fn sort_within_day_alphabetically() {
+        assert_eq!(bodies_sorted_by("sort-alpha", DaySort::Alpha), vec!["Alpha", "Mid", "Zeta"]);
+    }
+
+    #[test]
+    fn sort_within_day_by_category() {
+        // Uncategorized events come last
+        assert_eq!(bodies_sorted_by("sort-category", DaySort::Category), vec!["Mid", "Zeta", "Alpha"]);
+    }
+
+    #[test]
+    fn bad_dates_are_skipped_without_using_the_error_budget() {
+        let good = |uid: &str, start: &str| {
+            format!("BEGIN:VEVENT\r\nUID:{}\r\nDTSTART:{}\r\nSUMMARY:{}\r\nEND:VEVENT\r\n", uid, start, uid)
+        };
+        let mut ics = String::from("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n");
+        ics += &good("first", "20210602T210000+0900");
+        // More than the ten errors that would abandon the whole calendar
+        for i in 0..12 {
+            ics += &good(&format!("bad-{}", i), "not a date");
+        }
+        ics += &good("second", "20210603T210000+0900");
+        ics += "END:VCALENDAR\r\n";
+
+        let opts = FetchOptions { strict: false, ..fetch_options() };
+        let (days, warnings) = fetch_ics("bad-dates", &ics, opts);
+
+        let bodies: Vec<&str> = days.iter().flat_map(|day| day.events.iter()).map(|e| e.body.as_str()).collect();
+        assert_eq!(bodies, vec!["first", "second"]);
+        assert_eq!(warnings.len(), 12);
+    }
+
+    #[test]
+    fn month_long_event_is_flagged_and_dropped_when_capped() {
+        let long = || vec![
+            event("month@example.com", "2021-05-25T20:00:00+09:00", "2021-06-24T20:00:00+09:00", "Month long"),
+            event("short@example.com", "2021-06-02T20:00:00+09:00", "2021-06-02T22:00:00+09:00", "Short"),
+        ];
+        let bodies = |days: Vec<crate::CalendarDay>| -> Vec<String> {
+            days.into_iter().flat_map(|day| day.events).map(|e| e.body).collect()
+        };
+
+        assert!(looks_too_long(chrono::Duration::days(30)));
+        assert!(!looks_too_long(chrono::Duration::hours(2)));
+
+        // Still in progress, so it is shown unless the cap is set
+        assert_eq!(bodies(fetch("uncapped", long(), fetch_options())), vec!["Month long", "Short"]);
+
+        let capped = FetchOptions { max_event_duration: Some(chrono::Duration::hours(24)), ..fetch_options() };
+        assert_eq!(bodies(fetch("capped", long(), capped)), vec!["Short"]);
+    }
 }
@@ -18,6 +18,7 @@
 // SOFTWARE.
 
 use super::{rgb, RGBInt};
+use crate::datastream::{PaletteIndex, PALETTE_SIZE};
 
 pub const RGB_TEXT_ENDED: RGBInt = rgb(0x9BAEC0);
 pub const RGB_TIME_ENDED: RGBInt = rgb(0x7D8D93);
@@ -25,24 +26,36 @@ pub const RGB_TEXT: RGBInt = rgb(0x694342);
 pub const RGB_TIME: RGBInt = rgb(0x7D5757);
 pub const RGB_DATE: RGBInt = rgb(0xEFD4A5);
 pub const RGB_TIME_DASH: RGBInt = rgb(0xC28979);
+pub const RGB_TODAY: RGBInt = rgb(0xFFE27A);
+pub const RGB_SOON: RGBInt = rgb(0xD9603B);
 
-pub const PALETTE: [RGBInt;8] = [
+pub const PALETTE: [RGBInt;PALETTE_SIZE] = [
     RGB_DATE,
     RGB_TEXT_ENDED,
     RGB_TIME_ENDED,
     RGB_TEXT,
     RGB_TIME,
     RGB_TIME_DASH,
-    rgb(0xFF00FF),
-    rgb(0x00FFFF),
+    RGB_EVENT_MARKER,
+    RGB_EVENT_MARKER_ENDED,
 ];
 
-pub const PAL_DATE: u8 = 0;
-pub const PAL_TEXT_ENDED : u8 = 1;
-pub const PAL_TIME_ENDED: u8 = 2;
-pub const PAL_TEXT: u8 = 3;
-pub const PAL_TIME: u8 = 4;
-pub const PAL_TIME_DASH: u8 = 5;
+/// Default background fill; the shader treats this color as transparent.
+pub const RGB_BACKGROUND_KEY: RGBInt = rgb(0xFF00FF);
+
+pub const PAL_DATE: PaletteIndex = PaletteIndex::constant(0);
+pub const PAL_TEXT_ENDED : PaletteIndex = PaletteIndex::constant(1);
+pub const PAL_TIME_ENDED: PaletteIndex = PaletteIndex::constant(2);
+pub const PAL_TEXT: PaletteIndex = PaletteIndex::constant(3);
+pub const PAL_TIME: PaletteIndex = PaletteIndex::constant(4);
+pub const PAL_TIME_DASH: PaletteIndex = PaletteIndex::constant(5);
+pub const PAL_MARKER: PaletteIndex = PaletteIndex::constant(6);
+pub const PAL_MARKER_ENDED: PaletteIndex = PaletteIndex::constant(7);
+/// Appended to the palette only when today's header is highlighted
+pub const PAL_TODAY: PaletteIndex = PaletteIndex::constant(8);
+/// Time column of events starting soon. Appended only with --highlight-soon, after PAL_TODAY
+/// (which is then always present, so this index doesn't move).
+pub const PAL_SOON: PaletteIndex = PaletteIndex::constant(9);
 
 pub const VIEWPORT_HEIGHT : u32 = 1447;
 pub const VIEWPORT_WIDTH  : u32 = 1024;
@@ -79,18 +92,51 @@ pub const FONT_DAY_HEADER: &str = "M+ 1m bold 21.6";
 pub const FONT_TIME: &str = "M+ 1m bold 16.2";
 pub const FONT_END_TIME: &str = "M+ 1m regular 10.8";
 pub const FONT_EVENT_INFO: &str = "M+ 1m medium 16.2";
+pub const FONT_LOCATION: &str = "M+ 1m regular 12.6";
+/// Descriptions use FONT_EVENT_INFO, scaled by this
+pub const DESCRIPTION_FONT_SCALE: f64 = 0.75;
 pub const FONT_CONFIG_INFO: &str = "M+ 1m regular 10.8";
 
+/// Tried, in order, for characters the font itself lacks (M+ has no emoji). The event list is
+/// drawn into an alpha-only layer and recolored by the palette, so color emoji show up there
+/// as silhouettes in the text color; a monochrome emoji font looks much the same.
+pub const EMOJI_FALLBACK_FAMILIES: &str = "Noto Color Emoji,Noto Emoji,Symbola";
+
+/// Font sizes (in points) outside this range are clamped
+pub const MIN_FONT_SIZE: f64 = 4.0;
+pub const MAX_FONT_SIZE: f64 = 72.0;
+
+/// Events in this category, or whose title starts with this prefix, span the whole width
+/// with no time column (e.g. maintenance announcements). The prefix is removed from the title.
+pub const FULL_WIDTH_CATEGORY: &str = "FULL-WIDTH";
+pub const FULL_WIDTH_PREFIX: &str = "[full]";
+
+/// Space around the footer link and its QR code
+pub const FOOTER_LINK_MARGIN: f64 = 4.0;
+
+/// Size of the "?" box drawn in place of text pango fails to lay out
+pub const PLACEHOLDER_SIZE: f64 = 16.0;
+
+/// Smallest scale the day header font will be shrunk to in order to fit
+pub const MIN_DAY_HEADER_FONT_SCALE: f64 = 0.5;
+
 pub const EVENT_MARKER_HEIGHT: f64 = 16.0;
 pub const EVENT_MARKER_WIDTH: f64 = EVENT_MARKER_HEIGHT * 0.866;
 pub const EVENT_MARKER_CLIP: f64 = 4.0;
 
 pub const RGB_EVENT_MARKER: RGBInt = rgb(0x5A494F);
+pub const RGB_EVENT_MARKER_ENDED: RGBInt = RGB_TEXT_ENDED;
+
+pub const LOCATION_PIN_SIZE: f64 = 12.0;
+pub const LOCATION_PIN_MARGIN: f64 = 4.0;
 
 pub const SWATCH_SIZE: i32 = 32;
 
 pub const BG_SAMPLE_HEIGHT: u32 = 32;
 
+/// Events starting before this hour are considered part of the previous night
+pub const DAY_ROLLOVER_HOUR: u32 = 3;
+
 pub const SCROLL_SPLIT_POINT: i32 = VARIABLE_BOTTOM;
 
 pub const HEADER_BLEND_START: i32 = 8;
@@ -99,10 +145,7 @@ pub const HEADER_BLEND_END: i32 = 16;
 pub fn config_datastream_info() -> crate::datastream::DatastreamElements {
     use crate::datastream::ByteColor;
 
-    let mut palette : [ByteColor;8] = [ByteColor::default();8];
-    for i in 0..8 {
-        palette[i] = PALETTE[i].into();
-    }
+    let palette : Vec<ByteColor> = PALETTE.iter().copied().map(Into::into).collect();
 
     crate::datastream::DatastreamElements {
         datastream_width: u32::max_value(),
@@ -130,7 +173,10 @@ pub fn config_datastream_info() -> crate::datastream::DatastreamElements {
         day_header_tex_y: u32::max_value(),
         day_header_side_width: u32::max_value(),
         day_header_true_width: u32::max_value(),
+        channel_packing: u32::max_value(),
         vdata: vec![],
+        day_event_counts: vec![],
+        event_rows: vec![],
         palette: palette,
 
     }
@@ -20,6 +20,7 @@
 use crate::render_prims::*;
 
 use anyhow::{bail, Result, Context};
+use serde::{Deserialize, Serialize};
 
 use std::convert::{TryFrom, TryInto};
 use tracing::{debug, info, trace};
@@ -27,7 +28,16 @@ use tracing::{debug, info, trace};
 const DATA_COL_WIDTH: i32 = 64;
 const HEADER_HEIGHT: u32 = 128;
 
-#[derive(Copy, Clone, Debug, Default)]
+/// Written as the first two pixels of the datastream, so the shader (or a human looking at the
+/// texture) can tell a real datastream from a texture that merely happens to be in the slot.
+pub const DATASTREAM_MAGIC: [u32; 2] = [0x3CA1E, 0x1DA7A];
+
+/// Follows the magic. Bump this whenever elements are added, removed or reordered in
+/// `DatastreamElements::encode`, so a shader built against an older layout can refuse the
+/// texture instead of reading garbage.
+pub const DATASTREAM_VERSION: u32 = 1;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
 pub struct ByteColor {
     b: u8,
     g: u8,
@@ -49,15 +59,28 @@ impl SelectBit for usize {
     }
 }
 
+/// Expands a 6-bit value into an 8-bit channel. Values are placed in the upper six bits, with
+/// brighter values nudged into the middle of their bucket; the low two bits are never carried
+/// into the upper six, so `decode_part` recovers the original value.
 fn convert_part(v: u32) -> Result<u8> {
+    let orig = v;
     let v: u8 = v.try_into()?;
+    if v >= 64 {
+        bail!("Value {} does not fit in six bits", v);
+    }
     let mut v = v << 2;
     if v > 96 {
         v += 2;
     }
+    debug_assert_eq!(decode_part(v) as u32, orig);
     Ok(v)
 }
 
+/// Recovers the 6-bit value from a channel produced by `convert_part`
+fn decode_part(v: u8) -> u8 {
+    v >> 2
+}
+
 impl ByteColor {
     fn from_value(value: u32) -> Result<Self> {
         if value >= (1 << 18) {
@@ -75,6 +98,15 @@ impl ByteColor {
         Ok(Self { r, g, b, a: 0xFF })
     }
 
+    /// Recovers the value passed to `from_value`
+    fn to_value(self) -> u32 {
+        let r = decode_part(self.r) as u32;
+        let g = decode_part(self.g) as u32;
+        let b = decode_part(self.b) as u32;
+
+        (r << 12) | (g << 6) | b
+    }
+
     fn to_array(self) -> [u8; 4] {
         let ByteColor { r, g, b, a } = self;
 
@@ -131,7 +163,7 @@ macro_rules! write_elem {
 }
 
 /// The metadata that will be encoded into the output image
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct DatastreamElements {
     // Parameters for identifying datastream elements
     pub datastream_width: u32,
@@ -165,8 +197,8 @@ pub struct DatastreamElements {
     // Coordinates of ByteColor column dividers
     pub col_divs: [u32;3],
 
-    // Main palette
-    pub palette: [ByteColor;8],
+    // Main palette; at most MAX_PALETTE_SIZE entries
+    pub palette: Vec<ByteColor>,
 
     // The following coordinates locate items in the texture space.
     // Our texture space coordinates place the origin at the upper left, and are expressed in texels.
@@ -202,31 +234,201 @@ pub struct DatastreamElements {
     // The size we stretch the day header to
     pub day_header_true_width: u32,
 
-    pub vdata: Vec<VerticalData>
+    // Which channels the thirds of the scrollable section are packed into (see ChannelPacking::code)
+    pub channel_packing: u32,
+
+    pub vdata: Vec<VerticalData>,
+
+    /// Number of events under each day header in vdata, in order
+    pub day_event_counts: Vec<u32>,
+
+    /// Which rows of vdata each event covers, for resolving a row back to an event
+    pub event_rows: Vec<EventRowRange>,
+}
+
+/// The rows `start_row..end_row` of the scrolling section were drawn for the event with this id
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EventRowRange {
+    pub start_row: u32,
+    pub end_row: u32,
+    pub id: u32,
+}
+
+/// A stable id for an event, derived from its UID and small enough to fit in one datastream
+/// element. Distinct UIDs can collide, so this only narrows down which event a row belongs to;
+/// instances of a recurring event share a UID, and so an id.
+pub fn event_id(uid: &str) -> u32 {
+    // Fold the 32-bit hash down to 18 bits
+    let hash = fnv1a(uid.bytes());
+    (hash ^ (hash >> 18)) & ((1 << 18) - 1)
 }
 
 pub const FLAG_IS_DAY_HEADER : u32 = (1 << 17);
+/// Day header rows carry the palette index of the header text in these bits, above the offset
+pub const DAY_HEADER_COLOR_SHIFT : u32 = 12;
+
+/// Which color channel each vertical third of the text layer is packed into. The variant name
+/// lists the channels receiving the top, middle and bottom thirds, in that order.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChannelPacking {
+    Bgr,
+    Brg,
+    Gbr,
+    Grb,
+    Rbg,
+    Rgb,
+}
+
+impl ChannelPacking {
+    /// Byte offsets within a (little-endian) BGRA pixel for the top, middle and bottom thirds
+    pub fn byte_offsets(self) -> [usize; 3] {
+        const B: usize = 0;
+        const G: usize = 1;
+        const R: usize = 2;
+
+        match self {
+            ChannelPacking::Bgr => [B, G, R],
+            ChannelPacking::Brg => [B, R, G],
+            ChannelPacking::Gbr => [G, B, R],
+            ChannelPacking::Grb => [G, R, B],
+            ChannelPacking::Rbg => [R, B, G],
+            ChannelPacking::Rgb => [R, G, B],
+        }
+    }
+
+    /// Identifier written to the datastream
+    pub fn code(self) -> u32 {
+        match self {
+            ChannelPacking::Bgr => 0,
+            ChannelPacking::Brg => 1,
+            ChannelPacking::Gbr => 2,
+            ChannelPacking::Grb => 3,
+            ChannelPacking::Rbg => 4,
+            ChannelPacking::Rgb => 5,
+        }
+    }
+}
+
+impl std::str::FromStr for ChannelPacking {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "bgr" => Ok(ChannelPacking::Bgr),
+            "brg" => Ok(ChannelPacking::Brg),
+            "gbr" => Ok(ChannelPacking::Gbr),
+            "grb" => Ok(ChannelPacking::Grb),
+            "rbg" => Ok(ChannelPacking::Rbg),
+            "rgb" => Ok(ChannelPacking::Rgb),
+            _ => bail!("Unknown channel packing {:?}", s),
+        }
+    }
+}
+
+/// Number of palette entries in the default configuration
+pub const PALETTE_SIZE: usize = 8;
+
+/// Four palette indices are packed into each row info pixel below FLAG_IS_DAY_HEADER, so each
+/// index can use at most four bits.
+pub const MAX_PALETTE_SIZE: usize = 16;
+
+/// An index into the datastream palette. Only values below `MAX_PALETTE_SIZE` can be
+/// constructed; `encode` additionally checks against the actual palette length.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct PaletteIndex(u8);
+
+impl PaletteIndex {
+    pub fn new(index: u8) -> Result<Self> {
+        if index as usize >= MAX_PALETTE_SIZE {
+            bail!("Palette index {} out of range", index);
+        }
+
+        Ok(PaletteIndex(index))
+    }
+
+    /// Constructs an index for use in constants; out-of-range values fail const evaluation.
+    pub const fn constant(index: u8) -> Self {
+        let _range_check = [(); MAX_PALETTE_SIZE][index as usize];
+        PaletteIndex(index)
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl TryFrom<u8> for PaletteIndex {
+    type Error = anyhow::Error;
 
-#[derive(Clone,Copy,Debug,Eq, PartialEq)]
+    fn try_from(index: u8) -> Result<Self> {
+        PaletteIndex::new(index)
+    }
+}
+
+impl From<PaletteIndex> for u8 {
+    fn from(index: PaletteIndex) -> u8 {
+        index.get()
+    }
+}
+
+#[derive(Clone,Copy,Debug,Eq, PartialEq, Serialize, Deserialize)]
 pub enum RowColorInfo {
-    Colors([u8;4]),
-    DayHeader { offset: u32 }
+    Colors([PaletteIndex;4]),
+    DayHeader { offset: u32, color: PaletteIndex }
 }
 
 // Information for a specific row in the scrollable section
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct VerticalData {
     // y-coordinate of the day header before us
     pub prev_day_header: u32,
     pub col_info: RowColorInfo,
 }
 
+/// Reads a datastream back element by element, for `DatastreamElements::decode`
+struct DatastreamReader<'a> {
+    data: &'a [ByteColor],
+    pos: usize,
+}
+
+impl DatastreamReader<'_> {
+    fn next_color(&mut self) -> Result<ByteColor> {
+        let col = *self
+            .data
+            .get(self.pos)
+            .ok_or_else(|| anyhow::anyhow!("Datastream ends at element {}", self.pos))?;
+        self.pos += 1;
+
+        Ok(col)
+    }
+
+    fn next(&mut self) -> Result<u32> {
+        Ok(self.next_color()?.to_value())
+    }
+}
+
 impl DatastreamElements {
+    /// Number of bits used for each palette index in the row info. The shader must derive the
+    /// same value from the encoded palette length: 3 bits for up to 8 entries, 4 for up to 16.
+    pub fn palette_index_bits(&self) -> Result<u32> {
+        if self.palette.len() > MAX_PALETTE_SIZE {
+            bail!("Palette has {} entries; at most {} are supported", self.palette.len(), MAX_PALETTE_SIZE);
+        }
+
+        Ok(if self.palette.len() <= 8 { 3 } else { 4 })
+    }
+
     pub fn encode(&self) -> Result<Vec<ByteColor>> {
         let mut ds = Vec::new();
 
         //return Ok(vec![]);
 
+        for magic in DATASTREAM_MAGIC.iter().copied() {
+            write_elem!(ds, magic);
+        }
+        write_elem!(ds, DATASTREAM_VERSION);
+
         write_elem!(ds, self.datastream_width);
         write_elem!(ds, self.datastream_height);
 
@@ -243,6 +445,10 @@ impl DatastreamElements {
         for div in self.col_divs.iter().copied() {
             write_elem!(ds, div);
         }
+        let index_bits = self.palette_index_bits()?;
+        let palette_len : u32 = self.palette.len().try_into()?;
+        write_elem!(ds, palette_len);
+
         eprintln!("#define SCROLLCAL_DSOFF_PALETTE {}", ds.len());
         for col in self.palette.iter().copied() {
             ds.push(col);
@@ -264,6 +470,7 @@ impl DatastreamElements {
         write_elem!(ds, self.header_blend_start);
         write_elem!(ds, self.header_blend_end);
         write_elem!(ds, self.scroll_split_point);
+        write_elem!(ds, self.channel_packing);
 
         let vdata_len : u32 = self.vdata.len().try_into().context("vdata.len() conversion")?;
 
@@ -279,28 +486,181 @@ impl DatastreamElements {
         for (i, vd) in self.vdata.iter().enumerate() {            
             match vd.col_info {
                 RowColorInfo::Colors(colors) => {
-                    // Encode colors into a single pixel
-                    let mut tmp_colors : Vec<u32> = vec![];
-                    for (j, col) in colors.iter().copied().enumerate() {
-                        if col >= 8 {
-                            bail!("Color out of range");
+                    // Encode colors into a single pixel, first column in the most significant bits
+                    let mut col_info : u32 = 0;
+                    for col in colors.iter().copied() {
+                        if col.get() as usize >= self.palette.len() {
+                            bail!("Palette index {} out of range for {} entry palette", col.get(), self.palette.len());
                         }
 
-                        tmp_colors.push(col as u32);
+                        col_info = (col_info << index_bits) | col.get() as u32;
                     }
 
-                    let col_info : u32 = (tmp_colors[0] << 9) | (tmp_colors[1] << 6) | (tmp_colors[2] << 3) | tmp_colors[3];
-
                     ds.push(col_info.try_into().context("color_info")?);
                 },
-                RowColorInfo::DayHeader{offset} => ds.push((offset | FLAG_IS_DAY_HEADER).try_into().unwrap())
+                RowColorInfo::DayHeader{offset, color} => {
+                    if offset >= (1 << DAY_HEADER_COLOR_SHIFT) {
+                        bail!("Day header offset {} out of range", offset);
+                    }
+                    if color.get() as usize >= self.palette.len() {
+                        bail!("Palette index {} out of range for {} entry palette", color.get(), self.palette.len());
+                    }
+
+                    let info = offset | ((color.get() as u32) << DAY_HEADER_COLOR_SHIFT) | FLAG_IS_DAY_HEADER;
+                    ds.push(info.try_into().context("day_header_info")?)
+                }
             }
         }
 
+        let day_count : u32 = self.day_event_counts.len().try_into().context("day_event_counts.len() conversion")?;
+        write_elem!(ds, day_count);
+
+        trace!("#define SCROLLCAL_DSOFF_DAYCOUNTS {}", ds.len());
+        for count in self.day_event_counts.iter().copied() {
+            ds.push(count.try_into().context("day_event_count")?);
+        }
+
+        let day_header_rows = self.day_header_rows();
+        let day_header_count : u32 = day_header_rows.len().try_into().context("day_header_rows.len() conversion")?;
+        write_elem!(ds, day_header_count);
+
+        trace!("#define SCROLLCAL_DSOFF_DAYHEADERS {}", ds.len());
+        for row in day_header_rows {
+            ds.push(row.try_into().context("day_header_row")?);
+        }
+
+        let event_row_count : u32 = self.event_rows.len().try_into().context("event_rows.len() conversion")?;
+        write_elem!(ds, event_row_count);
+
+        trace!("#define SCROLLCAL_DSOFF_EVENTROWS {}", ds.len());
+        for range in self.event_rows.iter() {
+            ds.push(range.start_row.try_into().context("event start_row")?);
+            ds.push(range.end_row.try_into().context("event end_row")?);
+            ds.push(range.id.try_into().context("event id")?);
+        }
+
         Ok(ds)
     }
 
-        
+    /// Unpacks a datastream produced by `encode`, the way the shader reads it. The day header
+    /// rows are derived from vdata, so they are checked against it rather than returned.
+    pub fn decode(data: &[ByteColor]) -> Result<DatastreamElements> {
+        let mut reader = DatastreamReader { data, pos: 0 };
+        let mut ds = DatastreamElements::default();
+
+        for (i, magic) in DATASTREAM_MAGIC.iter().copied().enumerate() {
+            let found = reader.next()?;
+            if found != magic {
+                bail!("Not a datastream: magic {} is {:#x}, expected {:#x}", i, found, magic);
+            }
+        }
+        let version = reader.next()?;
+        if version != DATASTREAM_VERSION {
+            bail!("Datastream version {} is not supported (expected {})", version, DATASTREAM_VERSION);
+        }
+
+        ds.datastream_width = reader.next()?;
+        ds.datastream_height = reader.next()?;
+
+        ds.viewport_w = reader.next()?;
+        ds.viewport_h = reader.next()?;
+
+        ds.header_h = reader.next()?;
+        ds.footer_h = reader.next()?;
+        ds.border_l = reader.next()?;
+        ds.border_r = reader.next()?;
+
+        ds.day_header_height = reader.next()?;
+
+        for div in ds.col_divs.iter_mut() {
+            *div = reader.next()?;
+        }
+
+        let palette_len = reader.next()?;
+        for _ in 0..palette_len {
+            ds.palette.push(reader.next_color()?);
+        }
+        let index_bits = ds.palette_index_bits()?;
+
+        ds.section_pad = reader.next()?;
+        ds.scroll_height = reader.next()?;
+        ds.scroll_tex_y = reader.next()?;
+        ds.bg_sample_y = reader.next()?;
+        ds.bg_sample_h = reader.next()?;
+        ds.header_tex_y = reader.next()?;
+        ds.footer_tex_y = reader.next()?;
+        ds.day_header_tex_x = reader.next()?;
+        ds.day_header_tex_alpha_x = reader.next()?;
+        ds.day_header_tex_y = reader.next()?;
+        ds.day_header_side_width = reader.next()?;
+        ds.day_header_true_width = reader.next()?;
+
+        ds.header_blend_start = reader.next()?;
+        ds.header_blend_end = reader.next()?;
+        ds.scroll_split_point = reader.next()?;
+        ds.channel_packing = reader.next()?;
+
+        let vdata_len = reader.next()?;
+        let mut prev_day_headers = vec![];
+        for _ in 0..vdata_len {
+            prev_day_headers.push(reader.next()?);
+        }
+
+        let index_mask = (1 << index_bits) - 1;
+        for prev_day_header in prev_day_headers {
+            let info = reader.next()?;
+            let col_info = if info & FLAG_IS_DAY_HEADER != 0 {
+                let info = info & !FLAG_IS_DAY_HEADER;
+                RowColorInfo::DayHeader {
+                    offset: info & ((1 << DAY_HEADER_COLOR_SHIFT) - 1),
+                    color: PaletteIndex::new((info >> DAY_HEADER_COLOR_SHIFT) as u8)?,
+                }
+            } else {
+                // First column in the most significant bits
+                let mut colors = [PaletteIndex::constant(0); 4];
+                for (i, col) in colors.iter_mut().enumerate() {
+                    let shift = index_bits * (3 - i as u32);
+                    *col = PaletteIndex::new(((info >> shift) & index_mask) as u8)?;
+                }
+                RowColorInfo::Colors(colors)
+            };
+
+            ds.vdata.push(VerticalData { prev_day_header, col_info });
+        }
+
+        let day_count = reader.next()?;
+        for _ in 0..day_count {
+            ds.day_event_counts.push(reader.next()?);
+        }
+
+        let day_header_count = reader.next()?;
+        let mut day_header_rows = vec![];
+        for _ in 0..day_header_count {
+            day_header_rows.push(reader.next()?);
+        }
+        if day_header_rows != ds.day_header_rows() {
+            bail!("Day header rows {:?} don't match the row data", day_header_rows);
+        }
+
+        let event_row_count = reader.next()?;
+        for _ in 0..event_row_count {
+            ds.event_rows.push(EventRowRange {
+                start_row: reader.next()?,
+                end_row: reader.next()?,
+                id: reader.next()?,
+            });
+        }
+
+        Ok(ds)
+    }
+
+
+    /// Writes the encoded datastream into the upper-right corner of the viewport, filling
+    /// right-to-left then top-to-bottom.
+    ///
+    /// The shader locates the datastream relative to the viewport width, not the texture
+    /// width, so the rightmost datastream column is always at `viewport_w - 1` even if the
+    /// surface has been padded out (e.g. to a power of two).
     pub fn write(&self, surf: &mut cairo::ImageSurface) -> Result<()> {
         let data = self.encode()?;
 
@@ -310,6 +670,11 @@ impl DatastreamElements {
 
         let stride_size : usize = surf.get_stride().try_into()?;
         let img_width : usize = surf.get_width().try_into()?;
+        let origin_x : usize = self.viewport_w.try_into()?;
+        if origin_x > img_width {
+            bail!("Surface width {} is narrower than the viewport width {}", img_width, origin_x);
+        }
+
         let mut img_data = surf.get_data()?;
 
         let strides = data.chunks(self.datastream_width.try_into()?);
@@ -318,7 +683,7 @@ impl DatastreamElements {
             let mut row = &mut img_data[stride_size * y .. stride_size * (y + 1)];
 
             for (rx, col) in stride.iter().copied().enumerate() {
-                let x = img_width - rx - 1;
+                let x = origin_x - rx - 1;
                 let v = col.to_array();
 
                 row[x*4..(x+1)*4].copy_from_slice(&v);
@@ -328,4 +693,207 @@ impl DatastreamElements {
         Ok(())
     }
 
+    /// The first row of each day header in vdata, i.e. y-coordinates within the (unpacked)
+    /// scrolling section, in the same space as prev_day_header
+    pub fn day_header_rows(&self) -> Vec<u32> {
+        self.vdata
+            .iter()
+            .enumerate()
+            .filter(|(_, vd)| match vd.col_info {
+                RowColorInfo::DayHeader { offset, .. } => offset == 0,
+                _ => false,
+            })
+            .map(|(y, _)| y as u32)
+            .collect()
+    }
+
+    /// Reads the datastream region back out of a rendered surface and checks that it matches
+    /// what `write` would have written, failing on the first mismatched pixel. The alpha
+    /// channel is ignored, as it does not survive every surface format.
+    pub fn verify(&self, surf: &mut cairo::ImageSurface) -> Result<()> {
+        let data = self.encode()?;
+
+        let stride_size : usize = surf.get_stride().try_into()?;
+        let img_width : usize = surf.get_width().try_into()?;
+        let img_height : usize = surf.get_height().try_into()?;
+        let origin_x : usize = self.viewport_w.try_into()?;
+        let ds_width : usize = self.datastream_width.try_into()?;
+        if origin_x > img_width {
+            bail!("Surface width {} is narrower than the viewport width {}", img_width, origin_x);
+        }
+        if (data.len() + ds_width - 1) / ds_width > img_height {
+            bail!("Surface height {} is too small to hold the datastream", img_height);
+        }
+
+        let img_data = surf.get_data()?;
+        const RGB_MASK: u32 = 0x00FF_FFFF;
+
+        for (i, col) in data.iter().copied().enumerate() {
+            let (y, rx) = (i / ds_width, i % ds_width);
+            let x = origin_x - rx - 1;
+            let offset = stride_size * y + x * 4;
+
+            let expected = u32::from_ne_bytes(col.to_array()) & RGB_MASK;
+            let actual = u32::from_ne_bytes(img_data[offset..offset + 4].try_into()?) & RGB_MASK;
+
+            if expected != actual {
+                bail!(
+                    "Datastream mismatch at element {} (x={}, y={}): expected {:06x}, found {:06x}",
+                    i, x, y, expected, actual
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_part_round_trips() {
+        for v in 0..64 {
+            let encoded = convert_part(v).unwrap();
+            assert_eq!(decode_part(encoded) as u32, v, "encoded as {}", encoded);
+        }
+    }
+
+    #[test]
+    fn convert_part_rejects_values_over_six_bits() {
+        assert!(convert_part(64).is_err());
+    }
+
+    #[test]
+    fn palette_index_rejects_out_of_range_values() {
+        // 8 was the limit with the original three-bit indices; up to 15 is valid now
+        assert_eq!(PaletteIndex::new(8).unwrap().get(), 8);
+        assert_eq!(PaletteIndex::new(15).unwrap().get(), 15);
+
+        assert!(PaletteIndex::new(16).is_err());
+        assert!(PaletteIndex::new(255).is_err());
+        assert!(serde_json::from_str::<PaletteIndex>("16").is_err());
+    }
+
+    fn index(i: u8) -> PaletteIndex {
+        PaletteIndex::new(i).unwrap()
+    }
+
+    fn palette(len: u8) -> Vec<ByteColor> {
+        // Distinct colors for every length tested, including the oversized 17 entry palette
+        (0..len).map(|i| ByteColor::from((i * 15, 255 - i * 15, 0x80))).collect()
+    }
+
+    fn colors_row(prev_day_header: u32, colors: [u8; 4]) -> VerticalData {
+        VerticalData {
+            prev_day_header,
+            col_info: RowColorInfo::Colors([index(colors[0]), index(colors[1]), index(colors[2]), index(colors[3])]),
+        }
+    }
+
+    fn header_row(prev_day_header: u32, offset: u32, color: u8) -> VerticalData {
+        VerticalData {
+            prev_day_header,
+            col_info: RowColorInfo::DayHeader { offset, color: index(color) },
+        }
+    }
+
+    #[test]
+    fn palette_index_bits_follow_documented_widths() {
+        for (len, bits) in [(1, 3), (2, 3), (4, 3), (8, 3), (9, 4), (12, 4), (16, 4)].iter().copied() {
+            let ds = DatastreamElements { palette: palette(len), ..Default::default() };
+            assert_eq!(ds.palette_index_bits().unwrap(), bits, "{} entries", len);
+        }
+
+        let ds = DatastreamElements { palette: palette(17), ..Default::default() };
+        assert!(ds.palette_index_bits().is_err());
+        assert!(ds.encode().is_err());
+    }
+
+    #[test]
+    fn twelve_entry_palette_round_trips() {
+        let ds = DatastreamElements {
+            palette: palette(12),
+            vdata: vec![
+                header_row(0, 0, 11),
+                header_row(0, 1, 11),
+                colors_row(0, [9, 10, 11, 0]),
+                colors_row(0, [11, 11, 3, 7]),
+            ],
+            day_event_counts: vec![1],
+            ..Default::default()
+        };
+
+        let decoded = DatastreamElements::decode(&ds.encode().unwrap()).unwrap();
+        assert_eq!(decoded, ds);
+    }
+
+    #[test]
+    fn datastream_starts_with_magic_and_version() {
+        let ds = DatastreamElements { palette: palette(8), ..Default::default() };
+        let encoded = ds.encode().unwrap();
+
+        let header: Vec<u32> = encoded[..3].iter().map(|c| c.to_value()).collect();
+        assert_eq!(header, vec![DATASTREAM_MAGIC[0], DATASTREAM_MAGIC[1], DATASTREAM_VERSION]);
+    }
+
+    #[test]
+    fn decode_rejects_wrong_magic() {
+        let ds = DatastreamElements { palette: palette(8), ..Default::default() };
+        let mut encoded = ds.encode().unwrap();
+        encoded[1] = ByteColor::from_value(DATASTREAM_MAGIC[1] ^ 1).unwrap();
+
+        assert!(DatastreamElements::decode(&encoded).is_err());
+    }
+
+    /// Three days, with headers starting at rows 0, 2 and 5
+    fn three_day_rows() -> Vec<VerticalData> {
+        vec![
+            header_row(0, 0, 1),
+            colors_row(0, [2, 3, 4, 5]),
+            header_row(2, 0, 1),
+            header_row(2, 1, 1),
+            colors_row(2, [2, 2, 2, 2]),
+            header_row(5, 0, 1),
+        ]
+    }
+
+    #[test]
+    fn day_event_counts_round_trip() {
+        let ds = DatastreamElements {
+            palette: palette(8),
+            vdata: three_day_rows(),
+            day_event_counts: vec![3, 0, 12],
+            ..Default::default()
+        };
+
+        let decoded = DatastreamElements::decode(&ds.encode().unwrap()).unwrap();
+        assert_eq!(decoded.day_event_counts, vec![3, 0, 12]);
+        assert_eq!(decoded, ds);
+    }
+
+    #[test]
+    fn day_header_rows_round_trip() {
+        let ds = DatastreamElements {
+            palette: palette(8),
+            vdata: three_day_rows(),
+            day_event_counts: vec![1, 1, 0],
+            ..Default::default()
+        };
+        assert_eq!(ds.day_header_rows(), vec![0, 2, 5]);
+
+        // With no event rows, the stream ends with the header count, the header rows and a
+        // zero event row count
+        let mut encoded = ds.encode().unwrap();
+        let tail: Vec<u32> = encoded[encoded.len() - 5..].iter().map(|c| c.to_value()).collect();
+        assert_eq!(tail, vec![3, 0, 2, 5, 0]);
+        assert_eq!(DatastreamElements::decode(&encoded).unwrap().day_header_rows(), vec![0, 2, 5]);
+
+        // Header rows which disagree with vdata are rejected
+        let second_header = encoded.len() - 3;
+        encoded[second_header] = ByteColor::from_value(3).unwrap();
+        assert!(DatastreamElements::decode(&encoded).is_err());
+    }
 }
@@ -0,0 +1,105 @@
+// Copyright 2020-2021 bd_
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions: The above copyright
+// notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Raw calendar data kept between runs, so regenerating every few minutes doesn't download
+//! an unchanged feed each time, and a failed fetch can fall back to the last good copy.
+//!
+//! Each URL gets two files in the cache directory, named by a hash of the URL: the ICS bytes
+//! as served, and a JSON file recording when they were fetched.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::render_prims::fnv1a;
+
+pub struct FetchCache {
+    pub dir: PathBuf,
+    /// Cached data younger than this is used without fetching
+    pub ttl: chrono::Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheMeta {
+    url: String,
+    /// RFC 3339
+    fetched_at: String,
+    charset: Option<String>,
+}
+
+pub struct CachedFeed {
+    pub data: Vec<u8>,
+    /// Charset from the response's Content-Type
+    pub charset: Option<String>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+impl FetchCache {
+    fn paths(&self, url: &str) -> (PathBuf, PathBuf) {
+        let name = format!("{:08x}", fnv1a(url.bytes()));
+        (self.dir.join(format!("{}.ics", name)), self.dir.join(format!("{}.json", name)))
+    }
+
+    /// Returns None if nothing usable is cached for `url`
+    pub fn load(&self, url: &str) -> Result<Option<CachedFeed>> {
+        let (data_path, meta_path) = self.paths(url);
+
+        let f = match std::fs::File::open(&meta_path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Opening {}", meta_path.display())),
+        };
+        let meta: CacheMeta = serde_json::from_reader(std::io::BufReader::new(f))
+            .with_context(|| format!("Reading {}", meta_path.display()))?;
+        // Another URL with the same hash
+        if meta.url != url {
+            return Ok(None);
+        }
+
+        let fetched_at = DateTime::parse_from_rfc3339(&meta.fetched_at)
+            .with_context(|| format!("Bad fetch time in {}", meta_path.display()))?
+            .with_timezone(&Utc);
+        let data = std::fs::read(&data_path).with_context(|| format!("Reading {}", data_path.display()))?;
+
+        Ok(Some(CachedFeed { data, charset: meta.charset, fetched_at }))
+    }
+
+    pub fn is_fresh(&self, cached: &CachedFeed) -> bool {
+        Utc::now().signed_duration_since(cached.fetched_at) < self.ttl
+    }
+
+    pub fn save(&self, url: &str, data: &[u8], charset: Option<&str>) -> Result<()> {
+        std::fs::create_dir_all(&self.dir).with_context(|| format!("Creating {}", self.dir.display()))?;
+
+        let (data_path, meta_path) = self.paths(url);
+        std::fs::write(&data_path, data).with_context(|| format!("Writing {}", data_path.display()))?;
+
+        // Written last, so a partial save leaves the old timestamp (or none) in place
+        let meta = CacheMeta {
+            url: url.into(),
+            fetched_at: Utc::now().to_rfc3339(),
+            charset: charset.map(Into::into),
+        };
+        let f = std::fs::File::create(&meta_path).with_context(|| format!("Creating {}", meta_path.display()))?;
+        serde_json::to_writer(std::io::BufWriter::new(f), &meta)
+            .with_context(|| format!("Writing {}", meta_path.display()))
+    }
+}
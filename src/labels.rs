@@ -0,0 +1,78 @@
+// Copyright 2020-2021 bd_
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions: The above copyright
+// notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! User-facing strings drawn onto the board, so boards in other languages can replace them.
+
+use anyhow::{Context, Result};
+use chrono::Weekday;
+use serde::Deserialize;
+
+/// Loaded from a JSON file with --labels. Missing keys keep their default (Japanese) text;
+/// in the "updated" labels, `{}` is replaced with the number.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Labels {
+    /// Weekday abbreviations, starting from Monday
+    pub weekdays: [String; 7],
+    /// Prefix for times which fall on the day after the one the event is listed under
+    pub next_day: String,
+    /// Shown in place of the start time for all-day events
+    pub all_day: String,
+    /// Shown under the header of a day with no events
+    pub no_events: String,
+    /// Shown instead of any events when the calendar couldn't be fetched (--error-board)
+    pub fetch_failed: String,
+    pub updated_minutes_ago: String,
+    pub updated_hours_ago: String,
+    pub updated_days_ago: String,
+}
+
+impl Default for Labels {
+    fn default() -> Self {
+        Labels {
+            weekdays: [
+                "月".into(), "火".into(), "水".into(), "木".into(), "金".into(), "土".into(), "日".into(),
+            ],
+            next_day: "翌".into(),
+            all_day: "終日".into(),
+            no_events: "【イベント情報がありません】".into(),
+            fetch_failed: "【データ取得に失敗しました】".into(),
+            updated_minutes_ago: "更新 {}分前".into(),
+            updated_hours_ago: "更新 {}時間前".into(),
+            updated_days_ago: "更新 {}日前".into(),
+        }
+    }
+}
+
+impl Labels {
+    pub fn load(path: &str) -> Result<Labels> {
+        let f = std::fs::File::open(path).with_context(|| format!("Opening {}", path))?;
+        serde_json::from_reader(std::io::BufReader::new(f))
+            .with_context(|| format!("Reading labels from {}", path))
+    }
+
+    pub fn weekday(&self, wd: Weekday) -> &str {
+        &self.weekdays[wd.num_days_from_monday() as usize]
+    }
+}
+
+/// Substitutes `n` into a label such as `updated_minutes_ago`
+pub fn fill(label: &str, n: i64) -> String {
+    label.replace("{}", &n.to_string())
+}
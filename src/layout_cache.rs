@@ -0,0 +1,86 @@
+// Copyright 2020-2021 bd_
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions: The above copyright
+// notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Reuse of the scrolling section between runs whose events haven't changed.
+//!
+//! The event list is drawn into the alpha-only text layer; whether an event has ended (or a
+//! day is today) only changes which palette entries its rows use. So when the events match
+//! the previous run, its rendered scroll texture can be reused and only the row colors
+//! recomputed, skipping pango layout of every event.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::datastream::VerticalData;
+use crate::render_prims::load_png_surface;
+use crate::snapshot::EventSnapshot;
+
+/// What a row of the scrolling section was drawn for
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum RowOwner {
+    /// Padding, separators and other rows whose colors don't depend on the time
+    None,
+    /// The header of the day at this index
+    Day(usize),
+    /// The event at this index, counting across all days
+    Event(usize),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LayoutCache {
+    /// Settings the layout was generated with; the cache is only used if these still match
+    pub key: String,
+    pub events: Vec<EventSnapshot>,
+    /// The height budget the scroll section was laid out in
+    pub max_height: u32,
+    pub vdata: Vec<VerticalData>,
+    /// One entry per row of `vdata`
+    pub owners: Vec<RowOwner>,
+    pub day_event_counts: Vec<u32>,
+}
+
+/// The rendered scroll texture is kept next to the cache file
+pub fn surface_path(path: &str) -> String {
+    format!("{}.png", path)
+}
+
+pub fn save(path: &str, cache: &LayoutCache, surface: &cairo::ImageSurface) -> Result<()> {
+    let f = std::fs::File::create(path).with_context(|| format!("Creating {}", path))?;
+    serde_json::to_writer(std::io::BufWriter::new(f), cache)
+        .with_context(|| format!("Writing layout cache to {}", path))?;
+
+    let png_path = surface_path(path);
+    let f = std::fs::File::create(&png_path).with_context(|| format!("Creating {}", png_path))?;
+    surface.write_to_png(&mut std::io::BufWriter::new(f))
+        .with_context(|| format!("Writing scroll texture to {}", png_path))
+}
+
+/// Returns None if there is no cache yet
+pub fn load(path: &str) -> Result<Option<(LayoutCache, cairo::ImageSurface)>> {
+    let f = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Opening {}", path)),
+    };
+    let cache: LayoutCache = serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Reading layout cache from {}", path))?;
+    let surface = load_png_surface(&surface_path(path))?;
+
+    Ok(Some((cache, surface)))
+}
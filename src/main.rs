@@ -23,8 +23,13 @@ mod calendar;
 mod datastream;
 mod render_prims;
 mod event_info;
+mod timezone;
+mod snapshot;
+mod labels;
+mod layout_cache;
+mod fetch_cache;
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 use thiserror::Error;
 
 use pango::FontDescription;
@@ -33,15 +38,19 @@ use chrono::prelude::*;
 
 use datastream::*;
 use render_prims::*;
+use timezone::DisplayZone;
+use labels::Labels;
+use layout_cache::RowOwner;
 
 use cairo::Rectangle;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::rc::Rc;
 use std::convert::{TryInto, TryFrom};
 
 mod config;
 use config::*;
 
-use tracing::{debug, error, info, span, Level};
+use tracing::{debug, error, field, info, info_span, span, warn, Level};
 
 use clap::Clap;
 
@@ -54,14 +63,456 @@ struct Opts {
     #[clap(short, long)]
     template_image: String,
 
-    #[clap(short, long)]
-    header_image: String,
+    /// Day header image. May be repeated, with an optional date range (PATH@MM-DD..MM-DD);
+    /// the first image whose range contains the current date is used, else the first given.
+    #[clap(short, long, required = true)]
+    header_image: Vec<HeaderImage>,
     
     #[clap(short, long)]
     output: String,
 
     #[clap(short, long)]
     sample_data: bool,
+
+    /// Print the fetched schedule to stdout and exit without rendering
+    #[clap(long)]
+    list_events: bool,
+
+    /// Interpret event titles as pango markup
+    #[clap(long)]
+    markup: bool,
+
+    /// Background color (RRGGBB) which the shader treats as transparent
+    #[clap(long, parse(try_from_str = parse_rgb))]
+    background_key: Option<RGBInt>,
+
+    /// How to show early-morning events listed under the previous day: plain, next-day (翌01:30)
+    /// or extended (25:30)
+    #[clap(long, default_value = "plain")]
+    early_start_style: EarlyStartStyle,
+
+    /// Hour (0-23) at which a new day begins: earlier events are grouped with the previous day,
+    /// and earlier end times are written past 24:00. Defaults to 3.
+    #[clap(long, parse(try_from_str = parse_hour))]
+    rollover_hour: Option<u32>,
+
+    /// Extra vertical space between consecutive events, in pixels
+    #[clap(long, default_value = "0")]
+    event_spacing: f64,
+
+    /// Fail instead of skipping malformed events
+    #[clap(long)]
+    strict: bool,
+
+    /// Channels which the top, middle and bottom thirds of the text layer are packed into
+    #[clap(long, default_value = "bgr")]
+    channel_packing: ChannelPacking,
+
+    /// Mark events which have a GEO location with a pin
+    #[clap(long)]
+    show_location_pin: bool,
+
+    /// Draw all events as active or ended regardless of the current time (active, ended, auto)
+    #[clap(long, default_value = "auto")]
+    force_state: ForceState,
+
+    /// Additional palette entries (RRGGBB) appended after the built-in ones
+    #[clap(long, parse(try_from_str = parse_rgb))]
+    extra_palette_color: Vec<RGBInt>,
+
+    /// Time zone to display events in, as an IANA name (e.g. Asia/Tokyo), or "local"
+    #[clap(long, alias = "display-tz", default_value = "local")]
+    timezone: DisplayZone,
+
+    /// When the calendar data was last updated (RFC 3339); adds a "更新 X分前" label to the footer
+    #[clap(long, parse(try_from_str = DateTime::parse_from_rfc3339))]
+    updated_at: Option<DateTime<FixedOffset>>,
+
+    /// Event layout: wide (time beside the body) or compact (time above the body)
+    #[clap(long, default_value = "wide")]
+    layout_mode: LayoutMode,
+
+    /// Write the set of characters used in the rendered text to this path, as JSON
+    #[clap(long)]
+    glyph_report: Option<String>,
+
+    /// Order of events within each day: time, alpha or category. Hour separators are only
+    /// drawn when sorting by time.
+    #[clap(long, default_value = "time")]
+    sort_within_day: calendar::DaySort,
+
+    /// How duplicate events within a day are detected: content (same times and title; the
+    /// default, matching earlier versions), uid (same UID and start time) or none
+    #[clap(long, default_value = "content")]
+    dedup_by: calendar::DedupBy,
+
+    /// Leave out events which have already ended
+    #[clap(long)]
+    hide_ended: bool,
+
+    /// Whether events starting exactly at midnight at the end of the window are shown
+    /// (inclusive, exclusive). With inclusive, they are listed under the final day.
+    #[clap(long, default_value = "inclusive")]
+    window_end: calendar::WindowEnd,
+
+    /// Number of days of events to show, counting today
+    #[clap(long, default_value = "7")]
+    days_ahead: u32,
+
+    /// URL shown in the footer so viewers can subscribe to the calendar (also as a QR code,
+    /// when built with the qr feature)
+    #[clap(long)]
+    footer_link: Option<String>,
+
+    /// Color (RRGGBB) of the marker beside events which have not yet ended
+    #[clap(long, parse(try_from_str = parse_rgb))]
+    marker_color: Option<RGBInt>,
+
+    /// Color (RRGGBB) of the marker beside events which have ended
+    #[clap(long, parse(try_from_str = parse_rgb))]
+    marker_ended_color: Option<RGBInt>,
+
+    /// Only lay out the given day (YYYY-MM-DD); may be repeated. Useful for debugging a single
+    /// day's layout.
+    #[clap(long)]
+    only_day: Vec<NaiveDate>,
+
+    /// Exit without writing the output file if there are no events at all, leaving any
+    /// previous image in place
+    #[clap(long)]
+    skip_if_empty: bool,
+
+    /// Generate the calendar as of this time (RFC 3339) rather than the current time. If not
+    /// given, the VRC_CAL_NOW environment variable is used; failing that, the system clock.
+    #[clap(long, env = "VRC_CAL_NOW", parse(try_from_str = DateTime::parse_from_rfc3339))]
+    now: Option<DateTime<FixedOffset>>,
+
+    /// Save the fetched events to this path as JSON, for use with --prev-json on a later run
+    #[clap(long)]
+    json_out: Option<String>,
+
+    /// Compare the fetched events against a file saved with --json-out, and report added,
+    /// removed and modified events
+    #[clap(long)]
+    prev_json: Option<String>,
+
+    /// Write the --prev-json report to this path instead of stdout
+    #[clap(long)]
+    diff_out: Option<String>,
+
+    /// Text layer alpha values below this (0-255) are dropped, to keep antialiasing fringes
+    /// from bleeding into neighbouring channels
+    #[clap(long, default_value = "0")]
+    alpha_threshold: u8,
+
+    /// Write an SVG preview of the board, as it would appear in-world, to this path instead of
+    /// rendering the packed texture
+    #[clap(long)]
+    preview: Option<String>,
+
+    /// Render each event on its own into this directory (as 000.png, 001.png, ...) instead of
+    /// rendering the board
+    #[clap(long)]
+    event_tiles: Option<String>,
+
+    /// Leave out events marked as transparent (free time, TRANSP:TRANSPARENT)
+    #[clap(long)]
+    hide_transparent: bool,
+
+    /// Draw today's day header in a brighter accent color. This adds a palette entry before
+    /// any --extra-palette-color entries.
+    #[clap(long)]
+    highlight_today: bool,
+
+    /// Run this shell command and read the ICS data from its stdout, instead of fetching the
+    /// calendar over HTTP
+    #[clap(long)]
+    fetch_command: Option<String>,
+
+    /// Seconds to wait for --fetch-command, or for each HTTP request, before giving up
+    #[clap(long, alias = "fetch-timeout-secs", default_value = "30")]
+    fetch_timeout: u64,
+
+    /// Fetch calendars through this proxy. Without it, the HTTP_PROXY and HTTPS_PROXY
+    /// environment variables are honored.
+    #[clap(long)]
+    proxy: Option<String>,
+
+    /// Don't show an event's end time when the next event starts exactly then
+    #[clap(long)]
+    hide_implied_end_times: bool,
+
+    /// Solid color (RRGGBB) drawn behind the template in the scroll area's background sample,
+    /// for templates which are transparent there
+    #[clap(long, parse(try_from_str = parse_rgb))]
+    scroll_backdrop: Option<RGBInt>,
+
+    /// After writing the output, read it back and check the datastream survived intact
+    #[clap(long)]
+    verify: bool,
+
+    /// Mirror the event layout: times on the right, bodies on the left
+    #[clap(long)]
+    mirror: bool,
+
+    /// Limit the scrolling section to this many texture rows, even if more would fit
+    #[clap(long)]
+    max_scroll_height: Option<u32>,
+
+    /// JSON file overriding the text drawn on the board (weekday names, "no events", ...)
+    #[clap(long)]
+    labels: Option<String>,
+
+    /// Cache the laid-out event list here, and reuse it on later runs if the events haven't
+    /// changed (only updating which ones have ended)
+    #[clap(long)]
+    layout_cache: Option<String>,
+
+    /// Draw a divider between each day's all-day events (which are always listed first) and
+    /// the rest
+    #[clap(long, alias = "all-day-first")]
+    all_day_divider: bool,
+
+    /// Break text too wide to word-wrap (such as long URLs) between characters, rather than
+    /// clipping it
+    #[clap(long)]
+    wrap_overflow: bool,
+
+    /// Distance between lines of wrapped text, relative to the font's normal line height
+    #[clap(long, default_value = "1.0")]
+    line_spacing: f64,
+
+    /// Accent image drawn over the finished texture, at an optional position (PATH@X,Y).
+    /// The datastream is never covered.
+    #[clap(long)]
+    overlay_image: Option<OverlayImage>,
+
+    /// Write the row range and a hash of the UID of each event into the datastream, so clicks
+    /// on the board can be mapped back to events
+    #[clap(long)]
+    event_ids: bool,
+
+    /// If the calendar can't be fetched, render a board saying so (with no events) instead
+    /// of exiting with an error
+    #[clap(long)]
+    error_board: bool,
+
+    /// Draw the times of events starting within this many minutes in an accent color
+    #[clap(long)]
+    highlight_soon: Option<i64>,
+
+    /// Tint every element of the layout with a color derived from its position and size, to
+    /// see how the layout is put together
+    #[clap(long)]
+    debug_colors: bool,
+
+    /// Round each row of the event list up to a whole number of pixels, so rows start on
+    /// integer texels and line up exactly with the datastream's row data
+    #[clap(long)]
+    snap_rows: bool,
+
+    /// Write the events shown on the board (after filtering and deduplication) to this path
+    /// as an iCalendar file
+    #[clap(long)]
+    ics_out: Option<String>,
+
+    /// iCalendar feed to fetch events from, instead of the built-in community calendar. May be
+    /// repeated to merge several feeds.
+    #[clap(long, parse(try_from_str = calendar::parse_calendar_url))]
+    calendar_url: Vec<reqwest::Url>,
+
+    /// Read the ICS data from this file instead of fetching the calendar over HTTP
+    #[clap(long)]
+    calendar_file: Option<String>,
+
+    /// Show events marked as cancelled (in the ended colors) instead of hiding them
+    #[clap(long)]
+    show_cancelled: bool,
+
+    /// Draw each event's description below its title
+    #[clap(long)]
+    show_description: bool,
+
+    /// Maximum number of lines of description to draw, with --show-description
+    #[clap(long, default_value = "2")]
+    description_lines: usize,
+
+    /// Keep a copy of each fetched calendar in this directory. It is reused instead of
+    /// fetching again within --cache-ttl, and whenever a fetch fails.
+    #[clap(long)]
+    cache_dir: Option<String>,
+
+    /// Seconds a cached calendar is used for before fetching it again
+    #[clap(long, default_value = "300")]
+    cache_ttl: i64,
+
+    /// Drop events lasting longer than this many hours
+    #[clap(long)]
+    max_event_duration: Option<i64>,
+
+    /// Write how many events were shown, and which were skipped as malformed, to this path
+    /// as JSON
+    #[clap(long)]
+    stats_out: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct HeaderImage {
+    path: String,
+    /// Inclusive (month, day) range; the range wraps around the new year if start > end
+    active: Option<((u32, u32), (u32, u32))>,
+}
+
+impl HeaderImage {
+    fn applies_to<Tz: TimeZone>(&self, date: &Date<Tz>) -> bool {
+        let (start, end) = match self.active {
+            Some(range) => range,
+            None => return true,
+        };
+        let md = (date.month(), date.day());
+
+        if start <= end {
+            start <= md && md <= end
+        } else {
+            md >= start || md <= end
+        }
+    }
+}
+
+/// An accent image drawn over the finished layout, at (x, y) in texture coordinates
+#[derive(Clone, Debug)]
+struct OverlayImage {
+    path: String,
+    x: f64,
+    y: f64,
+}
+
+impl std::str::FromStr for OverlayImage {
+    type Err = anyhow::Error;
+
+    /// PATH, or PATH@X,Y
+    fn from_str(s: &str) -> Result<Self> {
+        let (path, pos) = match s.rfind('@') {
+            Some(at) => (&s[..at], Some(&s[at + 1..])),
+            None => (s, None),
+        };
+
+        let (x, y) = match pos {
+            Some(pos) => {
+                let mut parts = pos.splitn(2, ',');
+                let x = parts.next().unwrap_or("").trim().parse()
+                    .with_context(|| format!("Invalid overlay position {:?}", pos))?;
+                let y = parts.next().unwrap_or("").trim().parse()
+                    .with_context(|| format!("Invalid overlay position {:?}", pos))?;
+                (x, y)
+            }
+            None => (0.0, 0.0),
+        };
+
+        Ok(OverlayImage { path: path.into(), x, y })
+    }
+}
+
+fn parse_hour(s: &str) -> Result<u32> {
+    let hour: u32 = s.parse().with_context(|| format!("Invalid hour {:?}", s))?;
+    if hour > 23 {
+        anyhow::bail!("Hour {} is out of range (0-23)", hour);
+    }
+    Ok(hour)
+}
+
+fn parse_month_day(s: &str) -> Result<(u32, u32)> {
+    let date = NaiveDate::parse_from_str(&format!("2000-{}", s), "%Y-%m-%d")
+        .with_context(|| format!("Invalid month-day {:?}", s))?;
+    Ok((date.month(), date.day()))
+}
+
+impl std::str::FromStr for HeaderImage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (path, range) = match s.rfind('@') {
+            Some(at) => (&s[..at], Some(&s[at + 1..])),
+            None => (s, None),
+        };
+
+        let active = range
+            .map(|range| -> Result<_> {
+                let mut parts = range.splitn(2, "..");
+                let start = parse_month_day(parts.next().unwrap_or(""))?;
+                let end = parse_month_day(
+                    parts.next().ok_or_else(|| anyhow::anyhow!("Expected MM-DD..MM-DD, got {:?}", range))?,
+                )?;
+                Ok((start, end))
+            })
+            .transpose()?;
+
+        Ok(HeaderImage { path: path.into(), active })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LayoutMode {
+    /// Start/end times in their own column, to the left of the event body
+    Wide,
+    /// Times on their own line above the body, which uses the full width; for narrow displays
+    Compact,
+}
+
+impl std::str::FromStr for LayoutMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "wide" => Ok(LayoutMode::Wide),
+            "compact" => Ok(LayoutMode::Compact),
+            _ => Err(anyhow::anyhow!("Unknown layout mode {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum EarlyStartStyle {
+    /// Early-morning events are listed under their own date, with no marker
+    Plain,
+    /// Listed under the previous day, as 翌01:30
+    NextDay,
+    /// Listed under the previous day, as 25:30
+    Extended,
+}
+
+impl std::str::FromStr for EarlyStartStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "plain" => Ok(EarlyStartStyle::Plain),
+            "next-day" => Ok(EarlyStartStyle::NextDay),
+            "extended" => Ok(EarlyStartStyle::Extended),
+            _ => Err(anyhow::anyhow!("Unknown early start style {:?}", s)),
+        }
+    }
+}
+
+/// Overrides whether events are drawn as ended, for previewing the board
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ForceState {
+    Auto,
+    Active,
+    Ended,
+}
+
+impl std::str::FromStr for ForceState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(ForceState::Auto),
+            "active" => Ok(ForceState::Active),
+            "ended" => Ok(ForceState::Ended),
+            _ => Err(anyhow::anyhow!("Unknown state {:?}", s)),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -76,6 +527,59 @@ impl From<cairo::Status> for UpdaterError {
     }
 }
 
+/// Errors surfaced by the top-level fetch/layout/render pipeline, categorized so that callers
+/// can decide how to react (e.g. retry on network failure, but not on a broken feed).
+#[derive(Error, Debug)]
+pub enum CalendarUpdaterError {
+    #[error(transparent)]
+    Updater(#[from] UpdaterError),
+    #[error("Failed to fetch calendar: {0:#}")]
+    FetchError(anyhow::Error),
+    #[error("Timed out fetching calendar: {0:#}")]
+    FetchTimeout(anyhow::Error),
+    #[error("Failed to parse calendar: {0:#}")]
+    ParseError(anyhow::Error),
+    #[error("Layout does not fit in the texture: need {needed} datastream pixels, have {available}")]
+    LayoutOverflow { needed: usize, available: usize },
+    #[error("Layout {what} of {value} exceeds the limit of {limit}")]
+    DimensionLimit { what: &'static str, value: f64, limit: f64 },
+    #[error("Failed to lay out calendar: {0:#}")]
+    LayoutError(anyhow::Error),
+    #[error("Failed to render calendar: {0:#}")]
+    RenderError(anyhow::Error),
+}
+
+impl CalendarUpdaterError {
+    /// Checks that a layout dimension is finite, non-negative and at most `limit`
+    fn check_dimension(what: &'static str, value: f64, limit: u32) -> Result<(), Self> {
+        let limit = std::cmp::min(limit, i32::max_value() as u32) as f64;
+        if value.is_finite() && value >= 0.0 && value <= limit {
+            Ok(())
+        } else {
+            Err(CalendarUpdaterError::DimensionLimit { what, value, limit })
+        }
+    }
+
+    /// Timeouts are reported separately from other network failures
+    fn from_reqwest(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            CalendarUpdaterError::FetchTimeout(err.into())
+        } else {
+            CalendarUpdaterError::FetchError(err.into())
+        }
+    }
+}
+
+/// Runs `f` inside `span`, recording how long it took in the span's `elapsed_ms` field
+pub(crate) fn in_timed_span<T>(span: tracing::Span, f: impl FnOnce(&tracing::Span) -> T) -> T {
+    let _enter = span.enter();
+    let start = std::time::Instant::now();
+    let result = f(&span);
+    span.record("elapsed_ms", &(start.elapsed().as_millis() as u64));
+
+    result
+}
+
 fn convert_err<E>(err: E) -> anyhow::Error
 where
     UpdaterError: From<E>,
@@ -85,30 +589,81 @@ where
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct CalendarEvent {
-    start_time: DateTime<Local>,
-    end_time: Option<DateTime<Local>>,
+    uid: String,
+    start_time: DateTime<FixedOffset>,
+    end_time: Option<DateTime<FixedOffset>>,
     body: String,
+    /// Drawn on its own line below the body
+    location: Option<String>,
+    /// Only drawn with --show-description
+    description: Option<String>,
+    /// Latitude and longitude, from the GEO property
+    geo: Option<(f64, f64)>,
+    category: Option<String>,
+    /// Render the body across the whole width, with no times or marker
+    full_width: bool,
+    /// Starts at midnight and ends at the (exclusive) midnight after its last day
+    all_day: bool,
+    /// STATUS:CANCELLED; only kept with --show-cancelled, and drawn as if ended
+    cancelled: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct CalendarDay {
-    date: Date<Local>,
+    date: Date<FixedOffset>,
     events: Vec<CalendarEvent>,
 }
 
-fn sample_data() -> Vec<CalendarDay> {
+fn sample_data(zone: DisplayZone) -> Vec<CalendarDay> {
     vec![CalendarDay {
-        date: Local.ymd(2020, 5, 30),
+        date: zone.now().timezone().ymd(2020, 5, 30),
         events: vec![]
     }]
 }
 
-struct SetupInfo {
+pub struct SetupInfo {
     branch_name: String,
+
+    /// The time this calendar is generated for
+    now: DateTime<FixedOffset>,
+    force_state: ForceState,
+
+    /// When the calendar data was last updated, if the age label should be shown
+    updated_at: Option<DateTime<FixedOffset>>,
+
     font_day_header: FontDescription,
     font_time: FontDescription,
     font_end_time: FontDescription,
     font_event_info: FontDescription,
+    font_location: FontDescription,
+    font_description: FontDescription,
+    /// The footer's generation info and link
+    font_config_info: FontDescription,
+    /// Lines of description drawn below each event's body; zero to leave descriptions out
+    description_lines: usize,
+
+    /// Whether event bodies are rendered as pango markup
+    markup: bool,
+
+    /// Color filled in behind everything else
+    background_key: RGBInt,
+
+    /// Color filled in behind the template in the scroll background sample
+    scroll_backdrop: Option<RGBInt>,
+
+    /// Upper bound on the scrolling section's height in the texture
+    max_scroll_height: Option<u32>,
+
+    labels: Labels,
+
+    early_start_style: EarlyStartStyle,
+    /// Hour at which the next day begins, for early-morning end times
+    rollover_hour: u32,
+
+    layout_mode: LayoutMode,
+
+    /// Swap the time and body columns
+    mirror: bool,
 
     /// Template image used for the background
     template: RcRenderable,
@@ -118,25 +673,74 @@ struct SetupInfo {
     /// Minimum amount of blank (background) space between the header and subsequent body data
     /// This is applied above and below the main event list, not to the header itself.
     header_template_margin: f64,
+
+    /// Blank space inserted between consecutive events within a day
+    event_spacing: f64,
+
+    /// Whether to draw a separator between events starting in different hours
+    hour_separators: bool,
+
+    /// Draw the header for `now`'s date in the today accent color
+    highlight_today: bool,
+
+    /// Omit end times which equal the following event's start time
+    hide_implied_end_times: bool,
+
+    /// Separate each day's all-day events (listed first by fetch_calendar) from the rest
+    all_day_divider: bool,
+
+    /// Drawn over the finished layout, outside the datastream
+    overlay: Option<RcRenderable>,
+
+    /// Encode which rows each event covers in the datastream
+    event_ids: bool,
+
+    /// Events starting within this long after `now` have their times highlighted
+    highlight_soon: Option<chrono::Duration>,
+
+    /// Round event list entries up to whole pixels
+    snap_rows: bool,
+
+    channel_packing: ChannelPacking,
+
+    /// Alpha values below this are zeroed when packing the text layer
+    alpha_threshold: u8,
+
+    show_location_pin: bool,
+
+    /// Palette entries beyond the built-in PALETTE
+    extra_palette: Vec<RGBInt>,
+
+    marker_color: RGBInt,
+    marker_ended_color: RGBInt,
+
+    /// Link rendered in the footer
+    footer_link: Option<String>,
+
+    /// The calendar couldn't be fetched; the event list says so instead of "no events"
+    fetch_failed: bool,
 }
 
-fn weekday_sigil(wd: chrono::Weekday) -> &'static str {
-    match wd {
-        Weekday::Mon => "月",
-        Weekday::Tue => "火",
-        Weekday::Wed => "水",
-        Weekday::Thu => "木",
-        Weekday::Fri => "金",
-        Weekday::Sat => "土",
-        Weekday::Sun => "日",
+fn format_start(event: &CalendarEvent, date: Date<FixedOffset>, style: EarlyStartStyle, labels: &Labels) -> String {
+    let start_time = event.start_time.time();
+
+    if event.all_day {
+        return labels.all_day.clone();
+    }
+
+    if event.start_time.date() == date {
+        return start_time.format("%H:%M").to_string();
     }
-}
 
-fn format_start(event: &CalendarEvent) -> String {
-    event.start_time.time().format("%H:%M").to_string()
+    // This event has been listed under the previous day
+    match style {
+        EarlyStartStyle::Plain => start_time.format("%H:%M").to_string(),
+        EarlyStartStyle::NextDay => format!("{}{}", labels.next_day, start_time.format("%H:%M")),
+        EarlyStartStyle::Extended => format!("{:02}:{:02}", start_time.hour() + 24, start_time.minute()),
+    }
 }
 
-fn format_end(event: &CalendarEvent) -> Option<String> {
+fn format_end(event: &CalendarEvent, rollover_hour: u32, labels: &Labels) -> Option<String> {
     if event.end_time.is_none() {
         return None;
     }
@@ -145,26 +749,42 @@ fn format_end(event: &CalendarEvent) -> Option<String> {
     let start_date = event.start_time.date();
     let end_date = end_time.date();
 
-    if start_date == end_date {
-        Some(format!("~{}", end_time.time().format("%H:%M")))
-    } else if start_date.succ() == end_date && end_time.time().hour() <= 3 {
-        Some(format!("~{:02}:{:02}", end_time.time().hour() + 24, end_time.time().minute()))
-    } else if start_date.succ() == end_date {
-        Some(format!("~翌{}", end_time.time().format("%H:%M")))
-    } else {
-        Some(format!(
+    if event.all_day {
+        // The end is exclusive; show the last day covered, if that isn't the first
+        let last_date = end_date.pred();
+        return if last_date <= start_date {
+            None
+        } else {
+            Some(format!("~{} ({})", last_date.format("%m/%d"), labels.weekday(last_date.weekday())))
+        };
+    }
+
+    let end = end_time.time();
+    let days_later = end_date.signed_duration_since(start_date).num_days();
+
+    match days_later {
+        // ~22:00
+        0 => Some(format!("~{}", end.format("%H:%M"))),
+        // Still part of the same night: ~26:00
+        1 if end.hour() <= rollover_hour => Some(format!("~{:02}:{:02}", end.hour() + 24, end.minute())),
+        // ~翌10:00
+        1 => Some(format!("~{}{}", labels.next_day, end.format("%H:%M"))),
+        // Two or more days later (or, for a malformed event, before it starts): ~06/02 (水) 10:00
+        _ => Some(format!(
             "~{} ({}) {}",
-            end_time.date().format("%m/%d"),
-            weekday_sigil(end_date.weekday()),
-            end_time.time().format("%H:%M")
-        ))
+            end_date.format("%m/%d"),
+            labels.weekday(end_date.weekday()),
+            end.format("%H:%M")
+        )),
     }
 }
 
 struct EventStackEntry {
     renderable: RcRenderable,
-    colors: [u8; 4],
-    is_day_header: bool
+    colors: [PaletteIndex; 4],
+    is_day_header: bool,
+    /// Index of the event this was drawn for, counting across all days
+    event: Option<usize>,
 }
 
 impl Renderable for EventStackEntry {
@@ -202,17 +822,20 @@ impl Renderable for Vec<EventStackEntry> {
 }
 
 struct EventMarker {
-    is_ended: bool,
+    color: Color,
+    /// Point left from the mirrored time column, rather than right
+    mirrored: bool,
 }
 
 impl Renderable for EventMarker {
     fn render_internal(&self, cr: &mut cairo::Context) -> Result<()> {
-        let marker_color: Color = if !self.is_ended {
-            RGB_EVENT_MARKER.into()
+        let marker_color = self.color;
+        if self.mirrored {
+            cr.translate(MIRROR_AXIS_X2 - TIME_COL_RIGHT as f64, 0.0);
+            cr.scale(-1.0, 1.0);
         } else {
-            RGB_TEXT_ENDED.into()
-        };
-        cr.translate(TIME_COL_RIGHT as f64, 0.0);
+            cr.translate(TIME_COL_RIGHT as f64, 0.0);
+        }
 
         // Set up clip mask first
         cr.new_path();
@@ -240,20 +863,133 @@ impl Renderable for EventMarker {
     }
 }
 
+/// A small map pin, marking events which have a location
+struct LocationPin;
+
+impl Renderable for LocationPin {
+    fn render_internal(&self, cr: &mut cairo::Context) -> Result<()> {
+        let radius = LOCATION_PIN_SIZE / 3.0;
+        let cx = LOCATION_PIN_SIZE / 2.0;
+
+        cr.set_source_rgb(1.0, 1.0, 1.0);
+        cr.new_path();
+        cr.arc(cx, radius, radius, std::f64::consts::PI, 0.0);
+        cr.line_to(cx, LOCATION_PIN_SIZE);
+        cr.close_path();
+        cr.fill();
+
+        Ok(())
+    }
+
+    fn bounds(&self) -> (f64, f64) {
+        (LOCATION_PIN_SIZE, LOCATION_PIN_SIZE)
+    }
+}
+
 use std::sync::atomic::{AtomicBool, Ordering};
 
+/// Twice the x coordinate that mirrored layouts are reflected about: the center of the
+/// variable region
+const MIRROR_AXIS_X2: f64 = (VARIABLE_OUTER_LEFT + VARIABLE_OUTER_RIGHT) as f64;
+
+/// Returns the x position for an element of width `w` laid out at `x`, reflected if the
+/// layout is mirrored
+fn mirror_x(setup: &SetupInfo, x: f64, w: f64) -> f64 {
+    if setup.mirror {
+        MIRROR_AXIS_X2 - x - w
+    } else {
+        x
+    }
+}
+
+fn event_is_ended(setup: &SetupInfo, event: &CalendarEvent) -> bool {
+    if event.cancelled {
+        return true;
+    }
+
+    match setup.force_state {
+        ForceState::Auto => event.end_time.map(|et| et < setup.now).unwrap_or(false),
+        ForceState::Active => false,
+        ForceState::Ended => true,
+    }
+}
+
+fn event_starts_soon(setup: &SetupInfo, event: &CalendarEvent) -> bool {
+    let window = match setup.highlight_soon {
+        Some(window) if setup.force_state == ForceState::Auto => window,
+        _ => return false,
+    };
+
+    event.start_time > setup.now && event.start_time.signed_duration_since(setup.now) <= window
+}
+
+/// Maps the text, time and marker colors to their variants for an event in the given state,
+/// leaving any other palette index alone
+fn with_event_state(pal: PaletteIndex, ended: bool, soon: bool) -> PaletteIndex {
+    if pal == PAL_TIME || pal == PAL_TIME_ENDED || pal == PAL_SOON {
+        return if ended {
+            PAL_TIME_ENDED
+        } else if soon {
+            PAL_SOON
+        } else {
+            PAL_TIME
+        };
+    }
+
+    const PAIRS: [(PaletteIndex, PaletteIndex); 2] = [
+        (PAL_TEXT, PAL_TEXT_ENDED),
+        (PAL_MARKER, PAL_MARKER_ENDED),
+    ];
+
+    for (active, ended_pal) in PAIRS.iter().copied() {
+        if pal == active || pal == ended_pal {
+            return if ended { ended_pal } else { active };
+        }
+    }
+
+    pal
+}
+
 fn layout_single_event(
     sample_context: &cairo::Context,
     setup: &SetupInfo,
+    date: Date<FixedOffset>,
     event: &CalendarEvent,
-) -> Result<EventStackEntry> {
-    let start_time_text = format_start(event);
-    let end_time_text = format_end(event);
+    show_end: bool,
+) -> Result<Vec<EventStackEntry>> {
+    let start_time_text = format_start(event, date, setup.early_start_style, &setup.labels);
+    let end_time_text = if show_end { format_end(event, setup.rollover_hour, &setup.labels) } else { None };
 
-    let is_ended = event.end_time.map(|et| et < Local::now()).unwrap_or(false);
+    let is_ended = event_is_ended(setup, event);
+    let starts_soon = event_starts_soon(setup, event);
 
     let color_text: Color = if is_ended { RGB_TEXT_ENDED } else { RGB_TEXT }.into();
-    let color_time: Color = if is_ended { RGB_TIME_ENDED } else { RGB_TIME }.into();
+    let color_time: Color = if is_ended {
+        RGB_TIME_ENDED
+    } else if starts_soon {
+        RGB_SOON
+    } else {
+        RGB_TIME
+    }.into();
+
+    if event.full_width {
+        let desc_text = TextBox::ellipsized(
+            sample_context,
+            event.body.clone(),
+            (VARIABLE_OUTER_RIGHT - VARIABLE_OUTER_LEFT) as f64,
+            color_text,
+            &setup.font_event_info,
+            2,
+            setup.markup,
+        )?;
+
+        return Ok(vec![EventStackEntry {
+            renderable: desc_text.offset(VARIABLE_OUTER_LEFT as f64, 0.0).into_rc(),
+            is_day_header: false,
+            event: None,
+            colors: [if is_ended { PAL_TEXT_ENDED } else { PAL_TEXT }; 4],
+        }]);
+    }
 
     let start_time_text = TextBox::new(
         sample_context,
@@ -262,6 +998,7 @@ fn layout_single_event(
         color_time,
         &setup.font_time,
         1,
+        false,
     )?;
 
     let mut end_baseline = 0.0;
@@ -273,6 +1010,7 @@ fn layout_single_event(
             color_time,
             &setup.font_end_time,
             1,
+            false,
         )?;
         end_baseline = text.min_baseline();
         text.into_rc()
@@ -285,125 +1023,353 @@ fn layout_single_event(
 
     let start_offset = TIME_COL_LEFT as f64 + 8.0;
     let end_offset = start_offset + start_width;
+    let compact = setup.layout_mode == LayoutMode::Compact;
 
-    let end_time_text = if end_offset + end_time_text.width() < TIME_COL_RIGHT as f64 {
-        end_time_text.offset(end_offset, start_time_text.min_baseline() - end_baseline)
+    // In compact mode the time line has the full width to itself, so the end time always
+    // follows the start time directly.
+    let end_time_text = if compact || end_offset + end_time_text.width() < TIME_COL_RIGHT as f64 {
+        end_time_text.offset(
+            mirror_x(setup, end_offset, end_width),
+            start_time_text.min_baseline() - end_baseline,
+        )
     } else {
-        // Place this on the next line instead
+        // Place this on the next line instead, below the whole start time line (not just its
+        // baseline) so that the two don't overlap. The entry's bounds, and therefore its
+        // height in the event stack, then include this second line.
         end_time_text.offset(
-            TIME_COL_RIGHT as f64 - end_width,
-            start_time_text.min_baseline(),
+            mirror_x(setup, TIME_COL_RIGHT as f64 - end_width, end_width),
+            start_time_text.height(),
         )
     };
-    let start_time_text = start_time_text.offset(start_offset, 0.0);
+    let start_time_text = start_time_text.offset(mirror_x(setup, start_offset, start_width), 0.0);
+
+    let show_pin = setup.show_location_pin && event.geo.is_some();
+    let desc_left = if compact { start_offset } else { EVENT_INFO_LEFT as f64 };
+    let mut desc_width = EVENT_INFO_RIGHT as f64 - desc_left;
+    if show_pin {
+        desc_width -= LOCATION_PIN_SIZE + LOCATION_PIN_MARGIN;
+    }
 
-    let desc_text = TextBox::new(
+    let desc_text = TextBox::ellipsized(
         sample_context,
         event.body.clone(),
-        (EVENT_INFO_RIGHT - EVENT_INFO_LEFT) as f64,
+        desc_width,
         color_text,
         &setup.font_event_info,
         2,
+        setup.markup,
     )?;
 
+    // Directly below the body, in the same color
+    let desc_text: RcRenderable = match &event.description {
+        Some(description) if setup.description_lines > 0 => {
+            let description_text = TextBox::ellipsized(
+                sample_context,
+                description.clone(),
+                desc_width,
+                color_text,
+                &setup.font_description,
+                setup.description_lines,
+                false,
+            )?;
+            let body_height = desc_text.height();
+
+            let mut group = RenderGroup::new();
+            group.push(desc_text);
+            group.push(description_text.offset(0.0, body_height));
+            group.into_rc()
+        }
+        _ => desc_text.into_rc(),
+    };
+
+    // In the (dimmer) time color, so it needs rows of its own
+    let location_entry = match &event.location {
+        Some(location) => {
+            let text = TextBox::ellipsized(
+                sample_context,
+                location.clone(),
+                desc_width,
+                color_time,
+                &setup.font_location,
+                2,
+                false,
+            )?;
+            let x = mirror_x(setup, desc_left, text.width());
+            Some(text.offset(x, 0.0).into_rc())
+        }
+        None => None,
+    };
+
     //let is_ended = desc_text.height() > 36.0; // XXX hack
 
+    let (pal_time, pal_text, pal_marker) = if is_ended {
+        (PAL_TIME_ENDED, PAL_TEXT_ENDED, PAL_MARKER_ENDED)
+    } else if starts_soon {
+        (PAL_SOON, PAL_TEXT, PAL_MARKER)
+    } else {
+        (PAL_TIME, PAL_TEXT, PAL_MARKER)
+    };
+
     let mut render_group = RenderGroup::new();
 
-    render_group.push(EventMarker { is_ended }.offset(0.0, start_time_text.height() / 2.0));
+    let marker_color = if is_ended { setup.marker_ended_color } else { setup.marker_color };
+    render_group.push(EventMarker { color: marker_color.into(), mirrored: setup.mirror }.offset(0.0, start_time_text.height() / 2.0));
     render_group.push(start_time_text);
     render_group.push(end_time_text);
-    render_group.push(desc_text.offset(EVENT_INFO_LEFT as f64, 0.0));
+
+    let mut entries = if compact {
+        // Colors are assigned per row, so the time line and the body are separate entries;
+        // the time line may run past the time column.
+        let mut body_group = RenderGroup::new();
+        let desc_x = mirror_x(setup, desc_left, desc_text.width());
+        body_group.push(desc_text.offset(desc_x, 0.0));
+        if show_pin {
+            let pin_x = mirror_x(setup, EVENT_INFO_RIGHT as f64 - LOCATION_PIN_SIZE, LOCATION_PIN_SIZE);
+            body_group.push(LocationPin.offset(pin_x, 0.0));
+        }
+
+        vec![
+            EventStackEntry {
+                renderable: render_group.into_rc(),
+                is_day_header: false,
+                event: None,
+                colors: [pal_time, pal_marker, pal_time, pal_time],
+            },
+            EventStackEntry {
+                renderable: body_group.into_rc(),
+                is_day_header: false,
+                event: None,
+                colors: [pal_text; 4],
+            },
+        ]
+    } else {
+        let desc_x = mirror_x(setup, desc_left, desc_text.width());
+        render_group.push(desc_text.offset(desc_x, 0.0));
+        if show_pin {
+            let pin_x = mirror_x(setup, EVENT_INFO_RIGHT as f64 - LOCATION_PIN_SIZE, LOCATION_PIN_SIZE);
+            render_group.push(LocationPin.offset(pin_x, 0.0));
+        }
+
+        vec![EventStackEntry {
+            renderable: render_group.into_rc(),
+            is_day_header: false,
+            event: None,
+            colors: [pal_time, pal_marker, pal_text, pal_text],
+        }]
+    };
+
+    if let Some(location) = location_entry {
+        entries.push(EventStackEntry {
+            renderable: location,
+            is_day_header: false,
+            event: None,
+            colors: [pal_time, pal_marker, pal_time, pal_time],
+        });
+    }
+
+    Ok(entries)
+}
+
+
+/// Renders a single event on its own, in its real colors over the background key color,
+/// cropped to the event column. Useful for checking individual event states in isolation.
+pub fn render_event_tile(event: &CalendarEvent, setup: &SetupInfo) -> Result<cairo::ImageSurface> {
+    let tmp_surface =
+        cairo::ImageSurface::create(cairo::Format::Rgb24, 512, 512).map_err(convert_err)?;
+    let tmp_context = cairo::Context::new(&tmp_surface);
+
+    let entries = layout_single_event(&tmp_context, setup, event.start_time.date(), event, true)?;
+    let height = entries.height().ceil();
+    let tile = entries.clip_to(Rectangle {
+        x: VARIABLE_OUTER_LEFT as f64,
+        y: 0.0,
+        width: (VARIABLE_OUTER_RIGHT - VARIABLE_OUTER_LEFT) as f64,
+        height,
+    });
+
+    let (width, height) = tile.bounds();
+    let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, width.ceil() as i32, height.ceil() as i32)
+        .map_err(convert_err)?;
+
+    let mut context = cairo::Context::new(&surface);
+    let background: Color = setup.background_key.into();
+    context.set_source_rgb(background.r, background.g, background.b);
+    context.paint();
+    tile.render(&mut context)?;
+    std::mem::drop(context);
+
+    surface.flush();
+    Ok(surface)
+}
+
+fn day_title_palette(setup: &SetupInfo, day: &CalendarDay) -> PaletteIndex {
+    if setup.highlight_today && day.date.naive_local() == setup.now.date().naive_local() {
+        PAL_TODAY
+    } else {
+        PAL_DATE
+    }
+}
+
+/// The "no events" message, centered across the event list. Shown under a day with no events,
+/// or on its own when there are no days at all. On an error board, the fetch failure message
+/// is shown instead.
+fn no_events_entry(sample_context: &cairo::Context, setup: &SetupInfo) -> Result<EventStackEntry> {
+    let message = if setup.fetch_failed { &setup.labels.fetch_failed } else { &setup.labels.no_events };
+    let filler_text = TextBox::with_align(
+        sample_context,
+        message.clone(),
+        (VARIABLE_OUTER_RIGHT - VARIABLE_OUTER_LEFT) as f64,
+        RGB_TEXT.into(),
+        &setup.font_event_info,
+        2,
+        false,
+        Align::Center,
+    )?
+    .offset(VARIABLE_OUTER_LEFT as f64, 0.0);
 
     Ok(EventStackEntry {
-        renderable: render_group.into_rc(),
+        renderable: filler_text.into_rc(),
         is_day_header: false,
-        colors: if is_ended {
-            [PAL_TIME_ENDED, PAL_TEXT_ENDED, PAL_TEXT_ENDED, PAL_TEXT_ENDED]
-        } else {
-            [PAL_TIME, PAL_TEXT, PAL_TEXT, PAL_TEXT]
-        }
+        event: None,
+        colors: [PAL_TEXT;4]
     })
 }
 
+/// Lays out text with `layout`, shrinking `font` in steps until the text fits in `width` or
+/// reaches MIN_DAY_HEADER_FONT_SCALE. Returns the text and the scale it was laid out at.
+fn shrink_to_fit(
+    font: &FontDescription,
+    width: f64,
+    layout: impl Fn(&FontDescription) -> Result<TextBox>,
+) -> Result<(TextBox, f64)> {
+    let mut font = font.clone();
+    let base_size = font.get_size() as f64;
+    let mut scale = 1.0;
+    let mut text = layout(&font)?;
+    while text.width() > width && scale > MIN_DAY_HEADER_FONT_SCALE {
+        // Clamped, as repeated subtraction drifts just above the minimum
+        scale = f64::max(scale - 0.1, MIN_DAY_HEADER_FONT_SCALE);
+        font.set_size((base_size * scale) as i32);
+        text = layout(&font)?;
+    }
+
+    Ok((text, scale))
+}
 
+/// `first_event` is the index of the day's first event, counting across all days
 fn layout_day(
     sample_context: &cairo::Context,
     setup: &SetupInfo,
     day: &CalendarDay,
+    first_event: usize,
     mut entries: &mut Vec<EventStackEntry>,
-) -> Result<()> {    
-    let mut render_col = RenderColumn::new();
-
+) -> Result<()> {
     let date_string = format!(
         "{} ({})",
         day.date.format("%m/%d"),
-        weekday_sigil(day.date.weekday())
+        setup.labels.weekday(day.date.weekday())
     );
 
     // First, slap down the header
     // TODO: Adjust x-pos
 
-    let day_title = TextBox::new(
+    let center_width = (VARIABLE_OUTER_RIGHT - VARIABLE_OUTER_LEFT) as f64;
+    let title_palette = day_title_palette(setup, day);
+    let title_color = if title_palette == PAL_TODAY { RGB_TODAY } else { RGB_DATE };
+
+    let layout_title = |font: &FontDescription| TextBox::new(
         sample_context,
-        date_string,
+        date_string.clone(),
         setup.day_header_template.width(),
-        RGB_DATE.into(),
-        &setup.font_day_header,
+        title_color.into(),
+        font,
         1,
-    )?;
-    let center_width = (VARIABLE_OUTER_RIGHT - VARIABLE_OUTER_LEFT) as f64;
-    let x_offset = (center_width - day_title.width()) / 2.0;
-    let y_offset = (DAY_HEADER_HEIGHT as f64 - day_title.height()) / 2.0;
+        false,
+    );
+
+    let (day_title, _scale) = shrink_to_fit(&setup.font_day_header, center_width, layout_title)?;
+
+    let align_x = if day_title.width() > center_width {
+        warn!("Day header {:?} does not fit even at reduced size; clipping", date_string);
+        Align::Start
+    } else {
+        Align::Center
+    };
 
     let day_title = day_title
-        .offset(VARIABLE_OUTER_LEFT as f64 + x_offset, y_offset);
-    render_col.push(day_title);
-    render_col.push(Pad::new(0.0, y_offset));
+        .fixed_size(center_width, DAY_HEADER_HEIGHT as f64, align_x, Align::Center)
+        .offset(VARIABLE_OUTER_LEFT as f64, 0.0);
 
     entries.push(EventStackEntry {
-        renderable: render_col.into_rc(),
+        renderable: day_title.into_rc(),
         is_day_header: true,
-        colors: [PAL_DATE; 4]
+        event: None,
+        colors: [title_palette; 4]
     });
 
     entries.push(
         EventStackEntry {
             renderable: Pad::new(0.0, setup.header_template_margin).into_rc(),
             is_day_header: false,
+            event: None,
             colors: [PAL_TEXT;4]
         }
     );
 
     if day.events.is_empty() {
-        let filler_text = TextBox::new(
-            sample_context,
-            "【イベント情報がありません】".into(),
-            (VARIABLE_OUTER_RIGHT - VARIABLE_OUTER_LEFT) as f64,
-            RGB_TEXT.into(),
-            &setup.font_event_info,
-            2,
-        )?;
-
-        let w = filler_text.width();
-
-        let filler_text = filler_text.offset(
-            VARIABLE_OUTER_LEFT as f64
-                + ((VARIABLE_OUTER_RIGHT - VARIABLE_OUTER_LEFT) as f64 - w) / 2.0,
-            0.0,
-        );
-
-        entries.push(EventStackEntry {
-            renderable: filler_text.into_rc(),
-            is_day_header: false,
-            colors: [PAL_TEXT;4]
-        });
+        entries.push(no_events_entry(sample_context, setup)?);
     }
 
     // Render each event
     let mut prior_hour = None;
-    for event in day.events.iter() {
-        if let Some(prior_hour) = prior_hour {
+    for (i, event) in day.events.iter().enumerate() {
+        // A back-to-back event's end time is implied by the start of the one after it
+        let next_start = day.events.get(i + 1).map(|next| next.start_time);
+        let show_end = !(setup.hide_implied_end_times && event.end_time.is_some() && event.end_time == next_start);
+
+        let ends_all_day_section = setup.all_day_divider
+            && !event.all_day
+            && i > 0
+            && day.events[i - 1].all_day;
+        if ends_all_day_section {
+            entries.push(
+                EventStackEntry {
+                    renderable: Separator {
+                            color: RGB_TIME_DASH.into(),
+                            width: (VARIABLE_RIGHT - VARIABLE_LEFT) as f64,
+                            thickness: 2.0,
+                            dash: vec![],
+                            dash_offset: 0.0,
+                            cap: cairo::LineCap::Butt,
+                            margin: 4.0,
+                            center_gap: 0.0,
+                        }
+                        .offset(
+                            mirror_x(setup, VARIABLE_LEFT as f64, (VARIABLE_RIGHT - VARIABLE_LEFT) as f64),
+                            0.0,
+                        )
+                        .into_rc(),
+                    is_day_header: false,
+                    event: None,
+                    colors: [PAL_TIME_DASH;4]
+                }
+            );
+            // The divider already separates these; don't add an hour separator too
+            prior_hour = None;
+        }
+
+        if prior_hour.is_some() && setup.event_spacing > 0.0 {
+            entries.push(
+                EventStackEntry {
+                    renderable: Pad::new(0.0, setup.event_spacing).into_rc(),
+                    is_day_header: false,
+                    event: None,
+                    colors: [PAL_TEXT;4]
+                }
+            );
+        }
+
+        if let Some(prior_hour) = prior_hour.filter(|_| setup.hour_separators) {
             if prior_hour != event.start_time.hour() {
                 entries.push(
                     EventStackEntry {
@@ -411,12 +1377,19 @@ fn layout_day(
                                 color: RGB_TIME_DASH.into(),
                                 width: (TIME_COL_RIGHT - TIME_COL_LEFT) as f64,
                                 thickness: 2.0,
-                                dash: 4.0,
+                                dash: vec![4.0],
+                                dash_offset: 6.0,
+                                cap: cairo::LineCap::Round,
                                 margin: 4.0,
+                                center_gap: 0.0,
                             }
-                            .offset(TIME_COL_LEFT as f64, 0.0)
+                            .offset(
+                                mirror_x(setup, TIME_COL_LEFT as f64, (TIME_COL_RIGHT - TIME_COL_LEFT) as f64),
+                                0.0,
+                            )
                             .into_rc(),
                         is_day_header: false,
+                        event: None,
                         colors: [PAL_TIME_DASH;4]
                     }
                 );
@@ -424,13 +1397,18 @@ fn layout_day(
         }
         prior_hour = Some(event.start_time.hour());
 
-        entries.push(layout_single_event(sample_context, setup, event)?);
+        entries.extend(
+            layout_single_event(sample_context, setup, day.date, event, show_end)?
+                .into_iter()
+                .map(|entry| EventStackEntry { event: Some(first_event + i), ..entry })
+        );
     }
 
     entries.push(
         EventStackEntry {
             renderable: Pad::new(0.0, setup.header_template_margin).into_rc(),
             is_day_header: false,
+            event: None,
             colors: [PAL_TEXT;4]
         }
     );
@@ -438,31 +1416,76 @@ fn layout_day(
     Ok(())
 }
 
+/// Pads each entry out to a whole number of pixels. Each entry then starts at an integer y,
+/// so no row of vdata straddles two entries.
+fn snap_entries(entries: &mut [EventStackEntry]) {
+    for entry in entries.iter_mut() {
+        let height = entry.renderable.height();
+        if height.fract() != 0.0 {
+            let mut snapped = RenderGroup::new();
+            snapped.push(entry.renderable.clone());
+            snapped.push(Pad::new(0.0, height.ceil()));
+            entry.renderable = snapped.into_rc();
+        }
+    }
+}
+
 fn generate_variable_layout(
     sample_context: &cairo::Context,
     setup: &SetupInfo,
     days: &[CalendarDay],
     vdata: &mut Vec<VerticalData>,
+    day_event_counts: &mut Vec<u32>,
+    owners: &mut Vec<RowOwner>,
     height_limit: usize
 ) -> Result<RcRenderable> {  
     let mut entries = vec![];
     let vdata_limit = height_limit;
 
+    let mut first_event = 0;
     for day in days {
-        layout_day(sample_context, setup, day, &mut entries)?;
+        layout_day(sample_context, setup, day, first_event, &mut entries)?;
+        first_event += day.events.len();
     }
 
-    let mut y : f64 = 0.0;
-    vdata.reserve(entries.height().ceil() as usize);
-    let mut prev_header = 0;
-
-    'outer: for entry in entries.iter() {
-        let initial_y = y.floor() as u32;
-        y += entry.height();
+    if days.is_empty() {
+        // Nothing at all in the window. Rather than an empty scroll section, show the message
+        // with the same margins a day's events get, so the section has some height.
+        let margin = || EventStackEntry {
+            renderable: Pad::new(0.0, setup.header_template_margin).into_rc(),
+            is_day_header: false,
+            event: None,
+            colors: [PAL_TEXT;4]
+        };
+        entries.push(margin());
+        entries.push(no_events_entry(sample_context, setup)?);
+        entries.push(margin());
+    }
+
+    if setup.snap_rows {
+        snap_entries(&mut entries);
+    }
+
+    let mut y : f64 = 0.0;
+    vdata.reserve(entries.height().ceil() as usize);
+    let mut prev_header = 0;
+
+    'outer: for entry in entries.iter() {
+        let initial_y = y.floor() as u32;
+        y += entry.height();
 
         if entry.is_day_header {
             prev_header = vdata.len() as u32;
+
+            // layout_day emits exactly one header per day, in order
+            let day = &days[day_event_counts.len()];
+            day_event_counts.push(day.events.len() as u32);
         }
+        let owner = match entry.event {
+            Some(index) => RowOwner::Event(index),
+            None if entry.is_day_header => RowOwner::Day(day_event_counts.len() - 1),
+            None => RowOwner::None,
+        };
 
         eprintln!("[{}..{}@{}] [dh={:?}] colors={:?}", initial_y, y, vdata.len(), entry.is_day_header, &entry.colors);
 
@@ -473,15 +1496,21 @@ fn generate_variable_layout(
 
             let col_info = if entry.is_day_header {
                 let y : u32 = vdata.len().try_into()?;
-                RowColorInfo::DayHeader { offset: y - initial_y }
+                RowColorInfo::DayHeader { offset: y - initial_y, color: entry.colors[0] }
             } else {
-                RowColorInfo::Colors(entry.colors.clone())
+                let mut colors = entry.colors.clone();
+                if setup.mirror {
+                    // The time and body columns trade places; see compute_full_layout
+                    colors.swap(0, 2);
+                }
+                RowColorInfo::Colors(colors)
             };
 
             vdata.push(VerticalData {
                 prev_day_header: prev_header,
                 col_info: col_info
             });
+            owners.push(owner);
         }
     }
 
@@ -489,7 +1518,13 @@ fn generate_variable_layout(
 }
 
 #[inline(never)]
-fn squash_surface(mut surf: cairo::ImageSurface) -> Result<cairo::ImageSurface> {
+/// Packs the three vertical thirds of an A8 surface into the channels of an RGB surface.
+/// Alpha values below `alpha_threshold` are written as zero.
+fn squash_surface(
+    mut surf: cairo::ImageSurface,
+    packing: ChannelPacking,
+    alpha_threshold: u8,
+) -> Result<cairo::ImageSurface> {
     let tex_height_div = surf.get_height() / 3;
 
     let input_stride : usize = surf.get_stride().try_into()?;
@@ -514,12 +1549,15 @@ fn squash_surface(mut surf: cairo::ImageSurface) -> Result<cairo::ImageSurface>
     dbg!(input_stride);
     dbg!(tex_height_div as usize * input_stride);
 
+    let offsets = packing.byte_offsets();
+    let snap = |a: u8| if a < alpha_threshold { 0 } else { a };
+
     for (y, out_row) in out_data.chunks_exact_mut(output_stride).enumerate().take(tex_height_div as usize) {
         // B G R A
         for (x, px) in out_row.chunks_exact_mut(4).enumerate().take(width as usize) {
-            px[0] = in_data[y * input_stride + x + 0 * input_chunk];
-            px[1] = in_data[y * input_stride + x + 1 * input_chunk];
-            px[2] = in_data[y * input_stride + x + 2 * input_chunk];
+            px[offsets[0]] = snap(in_data[y * input_stride + x + 0 * input_chunk]);
+            px[offsets[1]] = snap(in_data[y * input_stride + x + 1 * input_chunk]);
+            px[offsets[2]] = snap(in_data[y * input_stride + x + 2 * input_chunk]);
             px[3] = 0xFF;
         }
     }
@@ -529,13 +1567,25 @@ fn squash_surface(mut surf: cairo::ImageSurface) -> Result<cairo::ImageSurface>
     Ok(col_surf)
 }
 
+/// The laid-out scrolling section: the channel-packed text layer and the per-row data for it
+struct ScrollSection {
+    surface: cairo::ImageSurface,
+    /// The height budget this was laid out in
+    max_height: u32,
+    vdata: Vec<VerticalData>,
+    owners: Vec<RowOwner>,
+    day_event_counts: Vec<u32>,
+}
+
 fn compute_layout(
     days: &[CalendarDay],
     setup: &SetupInfo,
-    mut vdata: &mut Vec<VerticalData>,
     max_height: f64
-) -> Result<RcRenderable> {
+) -> Result<ScrollSection> {
     let mut max_height = max_height.floor() as i32;
+    let mut vdata = vec![];
+    let mut owners = vec![];
+    let mut day_event_counts = vec![];
 
     info!("Generating layout");
 
@@ -543,7 +1593,15 @@ fn compute_layout(
         cairo::ImageSurface::create(cairo::Format::Rgb24, 512, 512).map_err(convert_err)?;
     let tmp_context = cairo::Context::new(&tmp_surface);
 
-    let layout = generate_variable_layout(&tmp_context, setup, days, vdata, max_height as usize * 3)?;
+    let layout = generate_variable_layout(
+        &tmp_context, setup, days, &mut vdata, &mut day_event_counts, &mut owners, max_height as usize * 3
+    )?;
+    if layout.height() > (max_height * 3) as f64 {
+        warn!(
+            "Event list needs {} rows but the scroll region only has {}; later events will be cut off",
+            layout.height().ceil(), max_height * 3
+        );
+    }
 
     // Now render to a temporary image so we can split across RGB channels.
     let mut tex_height = layout.height().ceil() as i32;
@@ -565,38 +1623,180 @@ fn compute_layout(
 
     alpha_surf.flush();
 
-    Ok(squash_surface(alpha_surf)?.into_rc())
+    Ok(ScrollSection {
+        surface: squash_surface(alpha_surf, setup.channel_packing, setup.alpha_threshold)?,
+        max_height: max_height as u32,
+        vdata,
+        owners,
+        day_event_counts,
+    })
+}
+
+/// Template and header images (loaded and scaled once) and label text; these can be reused across runs
+/// in a long-lived process.
+struct Assets {
+    template: RcRenderable,
+    /// Every candidate header image; one is chosen by date on each run
+    headers: Vec<(HeaderImage, RcRenderable)>,
+    labels: Labels,
+    /// Already positioned; drawn unscaled since it is in texture coordinates
+    overlay: Option<RcRenderable>,
 }
 
-fn setup_environment(opts: &Opts) -> Result<SetupInfo> {
-    info!("Performing environment setup");
+fn load_assets(opts: &Opts) -> Result<Assets> {
+    info!("Loading template images");
 
     let template = load_png_surface(&opts.template_image)?;
-    let day_title = load_png_surface(&opts.header_image)?;
 
     // Determine scale factor
     let w_scale = 1024.0 / template.width();
-    let template = template.scale_by(w_scale, w_scale);
-    let day_title = day_title.scale_by(w_scale, w_scale);
+    let template = template.scale_by(w_scale, w_scale).into_rc();
+
+    let headers = opts
+        .header_image
+        .iter()
+        .map(|img| -> Result<_> {
+            let surface = load_png_surface(&img.path)?;
+            Ok((img.clone(), surface.scale_by(w_scale, w_scale).into_rc()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let labels = match &opts.labels {
+        Some(path) => Labels::load(path)?,
+        None => Labels::default(),
+    };
 
-    let template = template.into_rc();
-    let day_title = day_title.into_rc();
+    let overlay = opts
+        .overlay_image
+        .as_ref()
+        .map(|img| -> Result<_> {
+            Ok(load_png_surface(&img.path)?.offset(img.x, img.y).into_rc())
+        })
+        .transpose()?;
+
+    Ok(Assets { template, headers, labels, overlay })
+}
+
+/// Parses a font description, clamping its size to MIN_FONT_SIZE..=MAX_FONT_SIZE
+fn load_font(desc: &str) -> FontDescription {
+    let mut font = FontDescription::from_string(desc);
+    let size = font.get_size() as f64 / pango::SCALE as f64;
+
+    // A size of zero means none was given, and pango will use its default
+    if size != 0.0 && (size < MIN_FONT_SIZE || size > MAX_FONT_SIZE) {
+        let clamped = size.max(MIN_FONT_SIZE).min(MAX_FONT_SIZE);
+        warn!("Font {:?} has size {}; clamping to {}", desc, size, clamped);
+        font.set_size((clamped * pango::SCALE as f64) as i32);
+    }
+
+    // Without this, emoji fall back to whatever font fontconfig finds, often drawing a box
+    if let Some(family) = font.get_family() {
+        font.set_family(&format!("{},{}", family, EMOJI_FALLBACK_FAMILIES));
+    }
+
+    font
+}
+
+fn setup_environment(opts: &Opts) -> Result<SetupInfo> {
+    let assets = load_assets(opts)?;
+    setup_run(opts, &assets)
+}
+
+/// The time to generate the calendar for: --now, then VRC_CAL_NOW, then the system clock
+fn current_time(opts: &Opts) -> DateTime<FixedOffset> {
+    match opts.now {
+        Some(now) => opts.timezone.convert(&now),
+        None => opts.timezone.now(),
+    }
+}
+
+/// The first header whose date range contains `date`, else the first header
+fn choose_header<'a, T>(headers: &'a [(HeaderImage, T)], date: &Date<FixedOffset>) -> Option<&'a (HeaderImage, T)> {
+    headers.iter().find(|(img, _)| img.applies_to(date)).or_else(|| headers.first())
+}
+
+/// Per-run setup: picks up the current time and the header for today's date
+fn setup_run(opts: &Opts, assets: &Assets) -> Result<SetupInfo> {
+    info!("Performing environment setup");
+
+    let now = current_time(opts);
+    let (header_image, day_title) = choose_header(&assets.headers, &now.date())
+        .ok_or_else(|| anyhow::anyhow!("No header image given"))?;
+    info!("Using header image {}", header_image.path);
+
+    let template = assets.template.clone();
+    let day_title = day_title.clone();
 
     Ok(SetupInfo {
         branch_name: opts.branch_name.clone().unwrap_or("DEVEL".into()),
-        font_day_header: FontDescription::from_string(FONT_DAY_HEADER),
-        font_time: FontDescription::from_string(FONT_TIME),
-        font_end_time: FontDescription::from_string(FONT_END_TIME),
-        font_event_info: FontDescription::from_string(FONT_EVENT_INFO),
+        now,
+        force_state: opts.force_state,
+        updated_at: opts.updated_at,
+        font_day_header: load_font(FONT_DAY_HEADER),
+        font_time: load_font(FONT_TIME),
+        font_end_time: load_font(FONT_END_TIME),
+        font_event_info: load_font(FONT_EVENT_INFO),
+        font_location: load_font(FONT_LOCATION),
+        font_description: {
+            let mut font = load_font(FONT_EVENT_INFO);
+            font.set_size((font.get_size() as f64 * DESCRIPTION_FONT_SCALE) as i32);
+            font
+        },
+        font_config_info: load_font(FONT_CONFIG_INFO),
+        description_lines: if opts.show_description { opts.description_lines } else { 0 },
+        markup: opts.markup,
+        background_key: opts.background_key.unwrap_or(RGB_BACKGROUND_KEY),
+        scroll_backdrop: opts.scroll_backdrop,
+        max_scroll_height: opts.max_scroll_height,
+        all_day_divider: opts.all_day_divider,
+        overlay: assets.overlay.clone(),
+        event_ids: opts.event_ids,
+        highlight_soon: opts.highlight_soon.map(chrono::Duration::minutes),
+        snap_rows: opts.snap_rows,
+        labels: assets.labels.clone(),
+        early_start_style: opts.early_start_style,
+        rollover_hour: opts.rollover_hour.unwrap_or(DAY_ROLLOVER_HOUR),
+        layout_mode: opts.layout_mode,
+        mirror: opts.mirror,
         template,
         day_header_template: day_title,
         header_template_margin: 16.0,
+        event_spacing: opts.event_spacing,
+        hour_separators: opts.sort_within_day == calendar::DaySort::Time,
+        highlight_today: opts.highlight_today,
+        hide_implied_end_times: opts.hide_implied_end_times,
+        channel_packing: opts.channel_packing,
+        alpha_threshold: opts.alpha_threshold,
+        show_location_pin: opts.show_location_pin,
+        extra_palette: opts.extra_palette_color.clone(),
+        marker_color: opts.marker_color.unwrap_or(RGB_EVENT_MARKER),
+        marker_ended_color: opts.marker_ended_color.unwrap_or(RGB_EVENT_MARKER_ENDED),
+        footer_link: opts.footer_link.clone(),
+        fetch_failed: false,
     })
 }
 
+/// Formats how long ago `updated_at` was, relative to `now`, in minutes, hours or days
+fn age_label(updated_at: DateTime<FixedOffset>, now: DateTime<FixedOffset>, labels: &Labels) -> String {
+    let age = now.signed_duration_since(updated_at);
+    let age = std::cmp::max(age, chrono::Duration::zero());
+
+    if age < chrono::Duration::hours(1) {
+        labels::fill(&labels.updated_minutes_ago, age.num_minutes())
+    } else if age < chrono::Duration::days(1) {
+        labels::fill(&labels.updated_hours_ago, age.num_hours())
+    } else {
+        labels::fill(&labels.updated_days_ago, age.num_days())
+    }
+}
+
 fn info_text(setup: &SetupInfo, bounds: (f64, f64)) -> Result<RcRenderable> {
     dbg!(bounds);
-    let info_str = format!("{} {}", Local::now().to_rfc3339(), &setup.branch_name);
+    let mut info_str = format!("{} {}", setup.now.to_rfc3339(), &setup.branch_name);
+    if let Some(updated_at) = setup.updated_at {
+        info_str.push(' ');
+        info_str.push_str(&age_label(updated_at, setup.now, &setup.labels));
+    }
 
     let tmp_surface =
     cairo::ImageSurface::create(cairo::Format::Rgb24, 512, 512).map_err(convert_err)?;
@@ -607,8 +1807,9 @@ fn info_text(setup: &SetupInfo, bounds: (f64, f64)) -> Result<RcRenderable> {
         info_str,
         bounds.0,
         RGB_TEXT.into(),
-        &FontDescription::from_string(FONT_CONFIG_INFO),
-        1
+        &setup.font_config_info,
+        1,
+        false,
     )?;
     let baseline = info_text.height();
     let info_text = info_text.offset(0.0, bounds.1 - baseline);
@@ -616,6 +1817,67 @@ fn info_text(setup: &SetupInfo, bounds: (f64, f64)) -> Result<RcRenderable> {
     Ok(info_text.into_rc())
 }
 
+/// Lays out the footer link (if any) in the bottom right corner of a footer of the given bounds
+fn footer_link(setup: &SetupInfo, bounds: (f64, f64)) -> Result<Option<RcRenderable>> {
+    let link = match &setup.footer_link {
+        Some(link) => link,
+        None => return Ok(None),
+    };
+
+    let tmp_surface =
+    cairo::ImageSurface::create(cairo::Format::Rgb24, 512, 512).map_err(convert_err)?;
+    let tmp_context = cairo::Context::new(&tmp_surface);
+
+    let mut group = RenderGroup::new();
+    #[allow(unused_mut)]
+    let mut right = bounds.0;
+
+    #[cfg(feature = "qr")]
+    {
+        let size = (bounds.1 - 2.0 * FOOTER_LINK_MARGIN).max(0.0);
+        let code = QrCode::new(link, size, RGB_TEXT.into())?;
+        right -= size + FOOTER_LINK_MARGIN;
+        group.push(code.offset(right, FOOTER_LINK_MARGIN));
+    }
+
+    let text = TextBox::new(
+        &tmp_context,
+        link.clone(),
+        bounds.0 / 2.0,
+        RGB_TEXT.into(),
+        &setup.font_config_info,
+        1,
+        false,
+    )?;
+    let (w, h) = text.bounds();
+    group.push(text.offset(right - w - FOOTER_LINK_MARGIN, bounds.1 - h));
+
+    Ok(Some(group.into_rc()))
+}
+
+/// The strip sampled by the shader as the backdrop of the scrolling region: the template's own
+/// background, drawn over the configured backdrop color (if any) so that transparent areas of
+/// the template don't show the background key through the event list.
+fn scroll_background(setup: &SetupInfo, height: f64) -> RcRenderable {
+    let width = setup.template.width();
+    let template_strip = setup.template.clone().clip_to(Rectangle {
+        x: 0.0,
+        y: VARIABLE_TEMPLATE_TOP as f64,
+        height,
+        width,
+    });
+
+    match setup.scroll_backdrop {
+        Some(color) => {
+            let mut group = RenderGroup::new();
+            group.push(FillRect::rect(color.into(), width, height));
+            group.push(template_strip);
+            group.into_rc()
+        }
+        None => template_strip.into_rc(),
+    }
+}
+
 fn template_column(setup: &SetupInfo, col: i32) -> (RcRenderable, f64, f64) {
     let clip = setup.template.clone().clip_to(Rectangle {
         x: (col * VARIABLE_OUTER_RIGHT) as f64,
@@ -762,7 +2024,11 @@ fn layout_template(setup: &SetupInfo, data: &mut DatastreamElements) -> Result<(
         width: template.width(),
         height: footer_height,
     }));
-    footer_tex.push(info_text(setup, footer_tex.bounds())?);
+    let footer_bounds = footer_tex.bounds();
+    footer_tex.push(info_text(setup, footer_bounds)?);
+    if let Some(link) = footer_link(setup, footer_bounds)? {
+        footer_tex.push(link);
+    }
 
     let y = column.height();
     column.push(footer_tex.pad_vertical(SECTION_PAD, SECTION_PAD));
@@ -776,12 +2042,9 @@ fn layout_template(setup: &SetupInfo, data: &mut DatastreamElements) -> Result<(
     data.footer_tex_y = footer.y as u32;
 
     data.bg_sample_y = (column.height() + SECTION_PAD) as u32;
-    let bg_sample_tex = setup.template.clone().clip_to(Rectangle {
-        x: 0.0,
-        y: VARIABLE_TEMPLATE_TOP as f64,
-        height: data.bg_sample_h as f64,
-        width: setup.template.width()
-    });
+    // The template strip is repeated to fill however many rows the shader samples
+    let bg_sample_tex = scroll_background(setup, BG_SAMPLE_HEIGHT as f64)
+        .tile(TileDirection::Vertical, template.width(), data.bg_sample_h as f64);
     column.push(
         bg_sample_tex.pad_vertical(SECTION_PAD, SECTION_PAD));
 
@@ -798,18 +2061,180 @@ fn layout_template(setup: &SetupInfo, data: &mut DatastreamElements) -> Result<(
     ))
 }
 
-fn compute_full_layout(setup: &SetupInfo, days: &Vec<CalendarDay>) -> Result<(RcRenderable, DatastreamElements)> {
+/// Groups consecutive rows drawn for the same event into ranges
+fn event_row_ranges(days: &[CalendarDay], owners: &[RowOwner]) -> Vec<EventRowRange> {
+    let events: Vec<&CalendarEvent> = days.iter().flat_map(|day| day.events.iter()).collect();
+    let mut ranges: Vec<(u32, u32, usize)> = vec![];
+
+    for (row, owner) in owners.iter().copied().enumerate() {
+        let index = match owner {
+            RowOwner::Event(index) => index,
+            _ => continue,
+        };
+        let row = row as u32;
+
+        match ranges.last_mut() {
+            Some((_, end, last)) if *end == row && *last == index => *end += 1,
+            _ => ranges.push((row, row + 1, index)),
+        }
+    }
+
+    ranges
+        .into_iter()
+        .map(|(start_row, end_row, index)| EventRowRange {
+            start_row,
+            end_row,
+            id: event_id(&events[index].uid),
+        })
+        .collect()
+}
+
+/// Everything besides the events themselves that affects the scroll section's layout
+fn scroll_layout_key(setup: &SetupInfo) -> String {
+    format!("{:?}", (
+        (setup.layout_mode, setup.early_start_style, setup.rollover_hour, setup.mirror, setup.markup),
+        setup.show_location_pin,
+        (setup.event_spacing, setup.header_template_margin, setup.day_header_template.width()),
+        (setup.hour_separators, setup.hide_implied_end_times, setup.highlight_today, setup.all_day_divider, setup.snap_rows),
+        setup.fetch_failed,
+        setup.description_lines,
+        (setup.channel_packing, setup.alpha_threshold, &setup.labels, render_prims::wrap_overflow(), render_prims::debug_colors()),
+        render_prims::line_spacing(),
+    ))
+}
+
+/// Loads the scroll section cached at `path` if it was laid out from the same events and
+/// settings, with its row colors brought up to date for the current time. A damaged cache is
+/// only a warning; the layout is then done from scratch.
+fn load_cached_scroll(
+    path: &str,
+    setup: &SetupInfo,
+    days: &[CalendarDay],
+    events: &[snapshot::EventSnapshot],
+) -> Option<ScrollSection> {
+    let (cache, surface) = match layout_cache::load(path) {
+        Ok(Some(loaded)) => loaded,
+        Ok(None) => return None,
+        Err(e) => {
+            warn!("Ignoring unreadable layout cache {}: {:#}", path, e);
+            return None;
+        }
+    };
+
+    if cache.key != scroll_layout_key(setup) {
+        info!("Layout settings changed since {} was written; laying out from scratch", path);
+        return None;
+    }
+    if !snapshot::diff(&cache.events, events).is_empty() {
+        info!("Events changed since {} was written; laying out from scratch", path);
+        return None;
+    }
+
+    let mut scroll = ScrollSection {
+        surface,
+        max_height: cache.max_height,
+        vdata: cache.vdata,
+        owners: cache.owners,
+        day_event_counts: cache.day_event_counts,
+    };
+    refresh_row_colors(setup, days, &mut scroll);
+
+    Some(scroll)
+}
+
+/// Recomputes the palette indexes which depend on the current time: which events have ended
+/// and which day is today
+fn refresh_row_colors(setup: &SetupInfo, days: &[CalendarDay], scroll: &mut ScrollSection) {
+    let events: Vec<&CalendarEvent> = days.iter().flat_map(|day| day.events.iter()).collect();
+
+    for (vd, owner) in scroll.vdata.iter_mut().zip(scroll.owners.iter().copied()) {
+        match (&mut vd.col_info, owner) {
+            (RowColorInfo::DayHeader { color, .. }, RowOwner::Day(index)) => {
+                *color = day_title_palette(setup, &days[index]);
+            }
+            (RowColorInfo::Colors(colors), RowOwner::Event(index)) => {
+                let ended = event_is_ended(setup, events[index]);
+                let soon = event_starts_soon(setup, events[index]);
+                for color in colors.iter_mut() {
+                    *color = with_event_state(*color, ended, soon);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn save_cached_scroll(
+    path: &str,
+    setup: &SetupInfo,
+    events: &[snapshot::EventSnapshot],
+    scroll: &ScrollSection,
+) -> Result<()> {
+    let cache = layout_cache::LayoutCache {
+        key: scroll_layout_key(setup),
+        events: events.to_vec(),
+        max_height: scroll.max_height,
+        vdata: scroll.vdata.clone(),
+        owners: scroll.owners.clone(),
+        day_event_counts: scroll.day_event_counts.clone(),
+    };
+
+    layout_cache::save(path, &cache, &scroll.surface)
+}
+
+/// Lays out the whole texture. If `reuse` was laid out in the same height budget it is used
+/// instead of laying out the event list again.
+fn compute_full_layout(
+    setup: &SetupInfo,
+    days: &Vec<CalendarDay>,
+    reuse: Option<ScrollSection>,
+) -> Result<(RcRenderable, DatastreamElements, ScrollSection), CalendarUpdaterError> {
     let mut data = config_datastream_info();
+    data.channel_packing = setup.channel_packing.code();
+    if setup.mirror {
+        let marker_right = TIME_COL_RIGHT + EVENT_MARKER_WIDTH.ceil() as i32;
+        data.col_divs = [
+            (MIRROR_AXIS_X2 as i32 - marker_right) as u32,
+            (MIRROR_AXIS_X2 as i32 - TIME_COL_RIGHT) as u32,
+            VIEWPORT_WIDTH as u32,
+        ];
+    }
+    data.palette[PAL_MARKER.get() as usize] = setup.marker_color.into();
+    data.palette[PAL_MARKER_ENDED.get() as usize] = setup.marker_ended_color.into();
+    if setup.highlight_today || setup.highlight_soon.is_some() {
+        data.palette.push(RGB_TODAY.into());
+    }
+    if setup.highlight_soon.is_some() {
+        data.palette.push(RGB_SOON.into());
+    }
+    data.palette.extend(setup.extra_palette.iter().copied().map(ByteColor::from));
 
     let template = setup.template.clone();
 
     let mut layout = RenderColumn::new();
 
-    let (header, coords) = layout_template(setup, &mut data)?;
+    let (header, coords) = layout_template(setup, &mut data).map_err(CalendarUpdaterError::LayoutError)?;
     layout.push(header);
 
     let base_offset = layout.height();
-    let event_info = compute_layout(&days, &setup, &mut data.vdata, TEXTURE_HEIGHT as f64 - layout.height() - SECTION_PAD)?;
+    CalendarUpdaterError::check_dimension("header height", base_offset + SECTION_PAD, TEXTURE_HEIGHT)?;
+    let mut max_scroll_height = TEXTURE_HEIGHT as f64 - layout.height() - SECTION_PAD;
+    if let Some(limit) = setup.max_scroll_height {
+        max_scroll_height = max_scroll_height.min(limit as f64);
+    }
+    let scroll = match reuse {
+        Some(scroll) if scroll.max_height == max_scroll_height.floor() as u32 => {
+            info!("Reusing cached event list layout");
+            scroll
+        }
+        _ => compute_layout(&days, &setup, max_scroll_height).map_err(CalendarUpdaterError::LayoutError)?,
+    };
+    data.vdata = scroll.vdata.clone();
+    data.day_event_counts = scroll.day_event_counts.clone();
+    if setup.event_ids {
+        data.event_rows = event_row_ranges(days, &scroll.owners);
+    }
+    let event_info = scroll.surface.clone().into_rc();
     let (event_w, event_h) = event_info.bounds();
 
     data.scroll_height = event_h.ceil() as u32;
@@ -827,28 +2252,80 @@ fn compute_full_layout(setup: &SetupInfo, days: &Vec<CalendarDay>) -> Result<(Rc
         .pad_sides(0.0, SECTION_PAD)
     );
 
-    let (_width, height) = layout.bounds();
+    let (width, height) = layout.bounds();
+    // The width includes side padding and is rounded up to a power of two when rendering
+    CalendarUpdaterError::check_dimension("width", width, i32::max_value() as u32 / 2)?;
+    CalendarUpdaterError::check_dimension("height", height, TEXTURE_HEIGHT)?;
+
+    check_datastream_fits(&data)?;
+
+    Ok((layout.into_rc(), data, scroll))
+}
+
+/// Fails with LayoutOverflow if the encoded datastream needs more pixels than its region has
+fn check_datastream_fits(data: &DatastreamElements) -> Result<(), CalendarUpdaterError> {
+    let encode_span = info_span!("encode", bytes = field::Empty, elapsed_ms = field::Empty);
+    let needed = in_timed_span(encode_span, |span| -> Result<_> {
+        let needed = data.encode()?.len();
+        span.record("bytes", &(needed as u64));
+        Ok(needed)
+    })
+    .map_err(CalendarUpdaterError::LayoutError)?;
+    let available = (data.datastream_width as usize) * (data.datastream_height as usize);
+    if needed > available {
+        return Err(CalendarUpdaterError::LayoutOverflow { needed, available });
+    }
+
+    Ok(())
+}
+
+/// Indexes of the palette entries which match the background key. This covers every color the
+/// text layer is drawn in, including the configured marker colors and any extra entries.
+fn background_key_collisions(key: RGBInt, palette: &[ByteColor]) -> Vec<usize> {
+    let key = ByteColor::from(key);
+    palette
+        .iter()
+        .enumerate()
+        .filter(|(_, col)| **col == key)
+        .map(|(i, _)| i)
+        .collect()
+}
 
-    Ok((layout.into_rc(), data))
+fn check_background_key(key: RGBInt, palette: &[ByteColor]) {
+    for index in background_key_collisions(key, palette) {
+        warn!(
+            "Background key color {:?} is also palette entry {}; anything drawn in it will appear transparent",
+            key, index
+        );
+    }
 }
 
-fn render_to_file(layout: &dyn Renderable, data: &DatastreamElements, filename: &str) -> anyhow::Result<()> {
+/// Renders the layout and writes the datastream, returning the finished image in memory
+fn render_to_surface(
+    layout: &dyn Renderable,
+    overlay: Option<&dyn Renderable>,
+    data: &DatastreamElements,
+    background_key: RGBInt,
+) -> Result<cairo::ImageSurface, CalendarUpdaterError> {
     info!("Rendering...");
 
-    let span = span!(Level::INFO, "render_to_file");
+    let span = span!(Level::INFO, "render_to_surface");
     let _enter = span.enter();
 
+    check_background_key(background_key, &data.palette);
+    let background_key: Color = background_key.into();
+
     let (width, height) = layout.bounds();
     let width = (width as usize).next_power_of_two();
     let height = (height as usize).next_power_of_two();
 
     let mut surface = cairo::ImageSurface::create(cairo::Format::Rgb24, width as i32, height as i32)
-        .map_err(convert_err)?;
+        .map_err(|e| CalendarUpdaterError::RenderError(convert_err(e)))?;
     let mut cairo_context = cairo::Context::new(&surface);
 
     // Fill background
     cairo_context.save();
-    cairo_context.set_source_rgba(1.0, 0.0, 1.0, 1.0);
+    cairo_context.set_source_rgba(background_key.r, background_key.g, background_key.b, 1.0);
     cairo_context.rectangle(0.0, 0.0, width as f64, height as f64);
     cairo_context.set_operator(cairo::Operator::DestOver);
     cairo_context.fill();
@@ -857,26 +2334,170 @@ fn render_to_file(layout: &dyn Renderable, data: &DatastreamElements, filename:
     cairo_context.reset_clip();
     cairo_context.new_path();
 
-    layout.render_to(&mut cairo_context, (0.0, 0.0))?;
+    layout.render_to(&mut cairo_context, (0.0, 0.0)).map_err(CalendarUpdaterError::RenderError)?;
+
+    if let Some(overlay) = overlay {
+        // Clip out the datastream, which is written in the top rows just left of the
+        // viewport's right edge
+        let ds_width = data.datastream_width as f64;
+        cairo_context.save();
+        cairo_context.new_path();
+        cairo_context.rectangle(0.0, 0.0, width as f64, height as f64);
+        cairo_context.rectangle(
+            data.viewport_w as f64 - ds_width,
+            0.0,
+            ds_width,
+            data.datastream_height as f64,
+        );
+        cairo_context.set_fill_rule(cairo::FillRule::EvenOdd);
+        cairo_context.clip();
+        overlay.render_to(&mut cairo_context, (0.0, 0.0)).map_err(CalendarUpdaterError::RenderError)?;
+        cairo_context.restore();
+    }
 
-    // Render to file
+    // Finish drawing before touching the pixel data directly
     std::mem::drop(cairo_context);
     surface.flush();
 
-    data.write(&mut surface)?;
+    data.write(&mut surface).map_err(CalendarUpdaterError::RenderError)?;
+
+    Ok(surface)
+}
+
+fn render_to_file(
+    layout: &dyn Renderable,
+    overlay: Option<&dyn Renderable>,
+    data: &DatastreamElements,
+    background_key: RGBInt,
+    filename: &str,
+) -> Result<(), CalendarUpdaterError> {
+    let surface = render_to_surface(layout, overlay, data, background_key)?;
 
     info!("Writing image...");
 
-    let f = std::fs::File::create(filename)?;
+    let f = std::fs::File::create(filename)
+        .with_context(|| format!("Creating output file {:?}", filename))
+        .map_err(CalendarUpdaterError::RenderError)?;
     let mut f = std::io::BufWriter::new(f);
 
-    surface.write_to_png(&mut f)?;
+    surface
+        .write_to_png(&mut f)
+        .context("Writing PNG")
+        .map_err(CalendarUpdaterError::RenderError)?;
+
+    Ok(())
+}
+
+/// Lays out the board as viewers see it: the event list drawn in its real colors over the
+/// template, with no channel packing or datastream.
+fn preview_layout(setup: &SetupInfo, days: &[CalendarDay]) -> Result<RcRenderable> {
+    let tmp_surface =
+        cairo::ImageSurface::create(cairo::Format::Rgb24, 512, 512).map_err(convert_err)?;
+    let tmp_context = cairo::Context::new(&tmp_surface);
+
+    let mut vdata = vec![];
+    let mut day_event_counts = vec![];
+    let mut owners = vec![];
+    let events = generate_variable_layout(
+        &tmp_context, setup, days, &mut vdata, &mut day_event_counts, &mut owners, usize::max_value()
+    )?;
+
+    let template = &setup.template;
+    let width = template.width();
+
+    let background = scroll_background(setup, BG_SAMPLE_HEIGHT as f64)
+        .tile(TileDirection::Vertical, width, events.height());
+
+    let mut body = RenderGroup::new();
+    body.push(background);
+    body.push(events);
+
+    let mut column = RenderColumn::new();
+    column.push(template.clone().clip_to(Rectangle {
+        x: 0.0,
+        y: 0.0,
+        width,
+        height: VARIABLE_TOP as f64,
+    }));
+    column.push(body);
+    column.push(template.clone().clip_to(Rectangle {
+        x: 0.0,
+        y: VARIABLE_BOTTOM as f64,
+        width,
+        height: template.height() - VARIABLE_BOTTOM as f64,
+    }));
+
+    Ok(column.into_rc())
+}
+
+/// Writes the layout as an SVG. Cairo draws text as glyph outlines, so `description` (e.g. the
+/// event listing) is added as the document's <desc> to keep the preview searchable.
+fn render_preview_svg(layout: &dyn Renderable, description: &str, filename: &str) -> Result<()> {
+    info!("Writing SVG preview to {}", filename);
+
+    let (width, height) = layout.bounds();
+    let surface = cairo::SvgSurface::new(width, height, Some(filename)).map_err(convert_err)?;
+    let mut context = cairo::Context::new(&surface);
+    layout.render(&mut context)?;
+    std::mem::drop(context);
+    surface.finish();
+
+    let svg = std::fs::read_to_string(filename).with_context(|| format!("Reading back {}", filename))?;
+    let insert_at = svg
+        .find("<svg")
+        .and_then(|start| svg[start..].find('>').map(|end| start + end + 1))
+        .ok_or_else(|| anyhow::anyhow!("No <svg> element in {}", filename))?;
+    let description = description.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    let svg = format!("{}\n<desc>{}</desc>{}", &svg[..insert_at], description, &svg[insert_at..]);
+    std::fs::write(filename, svg).with_context(|| format!("Writing {}", filename))?;
+
+    Ok(())
+}
+
+/// Writes each event, rendered with render_event_tile, to its own PNG in `dir`
+fn write_event_tiles(setup: &SetupInfo, days: &[CalendarDay], dir: &str) -> Result<()> {
+    info!("Writing event tiles to {}", dir);
+    std::fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir))?;
+
+    for (i, event) in days.iter().flat_map(|day| day.events.iter()).enumerate() {
+        let path = std::path::Path::new(dir).join(format!("{:03}.png", i));
+        let surface = render_event_tile(event, setup)?;
+
+        let f = std::fs::File::create(&path).with_context(|| format!("Creating {}", path.display()))?;
+        surface
+            .write_to_png(&mut std::io::BufWriter::new(f))
+            .with_context(|| format!("Writing {}", path.display()))?;
+    }
 
     Ok(())
 }
 
+fn event_list_lines(days: &[CalendarDay], setup: &SetupInfo) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for day in days.iter() {
+        lines.push(format!("{} ({})", day.date.format("%Y/%m/%d"), setup.labels.weekday(day.date.weekday())));
+
+        for event in day.events.iter() {
+            lines.push(format!(
+                "  {:<8} {:<20} {}",
+                format_start(event, day.date, setup.early_start_style, &setup.labels),
+                format_end(event, setup.rollover_hour, &setup.labels).unwrap_or_default(),
+                event.body
+            ));
+        }
+    }
+
+    lines
+}
+
+fn print_event_list(days: &[CalendarDay], setup: &SetupInfo) {
+    for line in event_list_lines(days, setup) {
+        println!("{}", line);
+    }
+}
+
 fn print_char_stats(data: &[CalendarDay]) {
-    use std::collections::HashMap;
     let mut map : HashMap<char, u32> = HashMap::new();
 
     for day in data.iter() {
@@ -901,21 +2522,1591 @@ fn print_char_stats(data: &[CalendarDay]) {
     }
 }
 
+/// Written by --glyph-report
+#[derive(serde::Serialize)]
+struct GlyphReport<'a> {
+    /// Every character laid out, in code point order
+    characters: String,
+    /// How many times each cluster was laid out
+    clusters: BTreeMap<&'a str, u32>,
+    /// How many clusters were laid out at each pixel width
+    widths: BTreeMap<u32, u32>,
+}
+
+/// Writes the characters and clusters laid out during this run, for building a font subset
+fn write_glyph_report(
+    path: &str,
+    histogram: &HashMap<String, u32>,
+    widths: &HashMap<u32, u32>,
+) -> Result<()> {
+    let characters: BTreeSet<char> = histogram.keys().flat_map(|k| k.chars()).collect();
+    let report = GlyphReport {
+        characters: characters.into_iter().collect(),
+        clusters: histogram.iter().map(|(k, v)| (k.as_str(), *v)).collect(),
+        widths: widths.iter().map(|(k, v)| (*k, *v)).collect(),
+    };
+
+    let f = std::fs::File::create(path).with_context(|| format!("Creating {}", path))?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(f), &report)
+        .with_context(|| format!("Writing glyph report to {}", path))
+}
+
+/// How to fetch the calendar, from the command line and the per-run setup
+fn fetch_options(opts: &Opts, setup: &SetupInfo) -> calendar::FetchOptions {
+    calendar::FetchOptions {
+        max_event_duration: opts.max_event_duration.map(chrono::Duration::hours),
+        group_early_starts: opts.early_start_style != EarlyStartStyle::Plain,
+        rollover_hour: setup.rollover_hour,
+        strict: opts.strict,
+        zone: opts.timezone,
+        now: setup.now,
+        sort_within_day: opts.sort_within_day,
+        hide_ended: opts.hide_ended,
+        hide_transparent: opts.hide_transparent,
+        show_cancelled: opts.show_cancelled,
+        calendar_urls: opts.calendar_url.clone(),
+        fetch_command: opts.fetch_command.clone(),
+        calendar_file: opts.calendar_file.clone(),
+        cache: opts.cache_dir.as_ref().map(|dir| fetch_cache::FetchCache {
+            dir: dir.into(),
+            ttl: chrono::Duration::seconds(opts.cache_ttl),
+        }),
+        fetch_timeout: std::time::Duration::from_secs(opts.fetch_timeout),
+        proxy: opts.proxy.clone(),
+        window_end: opts.window_end,
+        days_ahead: opts.days_ahead,
+        dedup_by: opts.dedup_by,
+    }
+}
+
+/// Written by --stats-out
+#[derive(serde::Serialize)]
+struct RunStats<'a> {
+    days: usize,
+    events: usize,
+    /// The calendar couldn't be fetched, and an error board was drawn instead
+    fetch_failed: bool,
+    /// Events skipped because they couldn't be parsed
+    skipped: &'a [calendar::ParseWarning],
+}
+
+fn write_stats(path: &str, stats: &RunStats) -> Result<()> {
+    let f = std::fs::File::create(path).with_context(|| format!("Creating {}", path))?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(f), stats)
+        .with_context(|| format!("Writing stats to {}", path))
+}
+
+/// Fetches the days to show, along with any events skipped as malformed. With --error-board, a
+/// failed fetch gives today with no events instead, and marks `setup` so the layout says why.
+fn fetch_days(opts: &Opts, setup: &mut SetupInfo) -> Result<(Vec<CalendarDay>, Vec<calendar::ParseWarning>)> {
+    if opts.sample_data {
+        return Ok((sample_data(opts.timezone), vec![]));
+    }
+
+    let (days, warnings) = match calendar::fetch_calendar(&fetch_options(opts, setup)) {
+        Ok((days, warnings)) => {
+            if !warnings.is_empty() {
+                warn!("Skipped {} malformed events", warnings.len());
+            }
+            (days, warnings)
+        }
+        Err(e) if opts.error_board => {
+            error!("Failed to fetch the calendar; rendering an error board instead: {:#}", e);
+            setup.fetch_failed = true;
+            // Today's header, with the failure message where "no events" would go. The
+            // footer carries the generation time.
+            return Ok((vec![CalendarDay { date: setup.now.date(), events: vec![] }], vec![]));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let days = if opts.only_day.is_empty() {
+        days
+    } else {
+        days.into_iter()
+            .filter(|day| opts.only_day.contains(&day.date.naive_local()))
+            .collect()
+    };
+
+    Ok((days, warnings))
+}
+
+/// Writes the --prev-json report and the --json-out and --ics-out files. An error board has no
+/// events, so these are skipped for it; it shouldn't look like every event was removed.
+fn write_event_files(
+    opts: &Opts,
+    setup: &SetupInfo,
+    days: &[CalendarDay],
+    events: &[snapshot::EventSnapshot],
+) -> Result<()> {
+    if setup.fetch_failed {
+        return Ok(());
+    }
+
+    if let Some(prev_path) = &opts.prev_json {
+        let prev = snapshot::load(prev_path)?;
+        let diff = snapshot::diff(&prev, events);
+        info!(
+            "{} added, {} removed, {} modified since {}",
+            diff.added.len(), diff.removed.len(), diff.modified.len(), prev_path
+        );
+
+        match &opts.diff_out {
+            Some(path) => std::fs::write(path, diff.to_string())
+                .with_context(|| format!("Writing diff to {}", path))?,
+            None => print!("{}", diff),
+        }
+    }
+    if let Some(path) = &opts.json_out {
+        snapshot::save(path, events)?;
+    }
+    if let Some(path) = &opts.ics_out {
+        std::fs::write(path, calendar::write_ics(days, setup.now))
+            .with_context(|| format!("Writing calendar to {}", path))?;
+    }
+
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
     let opts: Opts = Opts::parse();
 
     tracing_subscriber::fmt::init();
     info!("Starting calendar generation");
 
-    let setup = setup_environment(&opts)?;
-    let days = if opts.sample_data { sample_data() } else { calendar::fetch_calendar()? };
+    run(&opts)
+}
+
+fn run(opts: &Opts) -> anyhow::Result<()> {
+    set_wrap_overflow(opts.wrap_overflow);
+    set_line_spacing(opts.line_spacing);
+    set_debug_colors(opts.debug_colors);
+
+    let mut setup = setup_environment(opts)?;
+    let (days, warnings) = fetch_days(opts, &mut setup)?;
+
+    if let Some(path) = &opts.stats_out {
+        write_stats(path, &RunStats {
+            days: days.len(),
+            events: days.iter().map(|day| day.events.len()).sum(),
+            fetch_failed: setup.fetch_failed,
+            skipped: &warnings,
+        })?;
+    }
 
-    let (final_layout, data) = compute_full_layout(&setup, &days)?;
-    dump_text_histograms();
+    let events = snapshot::snapshot(&days);
+    write_event_files(opts, &setup, &days, &events)?;
 
-    debug!("Final image size: {:?}", final_layout.bounds());
+    if opts.list_events {
+        print_event_list(&days, &setup);
+        return Ok(());
+    }
 
-    render_to_file(&final_layout, &data, &opts.output)?;
+    if opts.skip_if_empty && !setup.fetch_failed && days.iter().all(|day| day.events.is_empty()) {
+        info!("No events found; leaving {} untouched", opts.output);
+        return Ok(());
+    }
 
-    Ok(())
+    if let Some(path) = &opts.preview {
+        let layout = preview_layout(&setup, &days)?;
+        render_preview_svg(&layout, &event_list_lines(&days, &setup).join("\n"), path)?;
+        return Ok(());
+    }
+
+    if let Some(dir) = &opts.event_tiles {
+        write_event_tiles(&setup, &days, dir)?;
+        return Ok(());
+    }
+
+    let event_count: usize = days.iter().map(|day| day.events.len()).sum();
+    let layout_span = info_span!(
+        "layout",
+        days = days.len() as u64,
+        events = event_count as u64,
+        elapsed_ms = field::Empty
+    );
+    let reuse = match &opts.layout_cache {
+        Some(path) => load_cached_scroll(path, &setup, &days, &events),
+        None => None,
+    };
+    let (final_layout, data, scroll) = in_timed_span(layout_span, |_| compute_full_layout(&setup, &days, reuse))?;
+    if let Some(path) = &opts.layout_cache {
+        save_cached_scroll(path, &setup, &events, &scroll)?;
+    }
+    dump_text_histograms();
+    let text_histogram = take_text_histogram();
+    let width_histogram = take_width_histogram();
+    if let Some(path) = &opts.glyph_report {
+        write_glyph_report(path, &text_histogram, &width_histogram)?;
+    }
+
+    debug!("Final image size: {:?}", final_layout.bounds());
+
+    in_timed_span(info_span!("render", elapsed_ms = field::Empty), |_| {
+        render_to_file(&final_layout, setup.overlay.as_ref().map(|o| o as &dyn Renderable), &data, setup.background_key, &opts.output)
+    })?;
+
+    if opts.verify {
+        info!("Verifying datastream in {}", opts.output);
+        let mut written = load_png_surface(&opts.output)?;
+        data.verify(&mut written)
+            .with_context(|| format!("Datastream verification failed for {}", opts.output))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jst(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<FixedOffset> {
+        FixedOffset::east(9 * 3600).ymd(y, m, d).and_hms(h, min, 0)
+    }
+
+    fn event(start: DateTime<FixedOffset>, end: Option<DateTime<FixedOffset>>) -> CalendarEvent {
+        CalendarEvent {
+            uid: "test@example.com".into(),
+            start_time: start,
+            end_time: end,
+            body: "テストイベント".into(),
+            location: None,
+            description: None,
+            geo: None,
+            category: None,
+            full_width: false,
+            all_day: false,
+            cancelled: false,
+        }
+    }
+
+    /// Options as if run with the images in the repository, at noon JST on 2021-06-01
+    fn test_opts(extra: &[&str]) -> Opts {
+        zoned_test_opts("Asia/Tokyo", extra)
+    }
+
+    /// As test_opts, but displaying times in `zone`
+    fn zoned_test_opts(zone: &str, extra: &[&str]) -> Opts {
+        let mut args = vec!["--now", "2021-06-01T12:00:00+09:00"];
+        args.extend_from_slice(extra);
+
+        untimed_test_opts(zone, &args)
+    }
+
+    /// As zoned_test_opts, but leaving the current time to VRC_CAL_NOW or the clock
+    fn untimed_test_opts(zone: &str, extra: &[&str]) -> Opts {
+        let mut args = vec![
+            "calendar-updater",
+            "--template-image",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/template.png"),
+            "--header-image",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/header.png"),
+            "--output",
+            "unused.png",
+            "--timezone",
+            zone,
+        ];
+        args.extend_from_slice(extra);
+
+        Opts::parse_from(args)
+    }
+
+    fn test_setup(extra: &[&str]) -> SetupInfo {
+        setup_environment(&test_opts(extra)).unwrap()
+    }
+
+    /// One day with an event which ended before `now` and one still to come
+    fn test_days() -> Vec<CalendarDay> {
+        vec![CalendarDay {
+            date: jst(2021, 6, 1, 0, 0).date(),
+            events: vec![
+                event(jst(2021, 6, 1, 9, 0), Some(jst(2021, 6, 1, 10, 0))),
+                event(jst(2021, 6, 1, 21, 0), Some(jst(2021, 6, 1, 23, 0))),
+            ],
+        }]
+    }
+
+    #[test]
+    fn event_list_shows_each_day_and_event() {
+        let setup = test_setup(&[]);
+
+        assert_eq!(
+            event_list_lines(&test_days(), &setup),
+            vec![
+                "2021/06/01 (火)",
+                "  09:00    ~10:00               テストイベント",
+                "  21:00    ~23:00               テストイベント",
+            ]
+        );
+    }
+
+    /// A path in the temp directory which is removed, if anything was written there, on drop
+    struct TempPath(String);
+
+    impl TempPath {
+        fn as_str(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl std::ops::Deref for TempPath {
+        type Target = str;
+
+        fn deref(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl AsRef<std::path::Path> for TempPath {
+        fn as_ref(&self) -> &std::path::Path {
+            self.0.as_ref()
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// A fresh temp path for each call, so tests running in parallel never share a file
+    fn temp_path(name: &str) -> TempPath {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::SeqCst);
+
+        TempPath(
+            std::env::temp_dir()
+                .join(format!("calendar-updater-test-{}-{}-{}", std::process::id(), n, name))
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+
+    fn pixels(mut surface: cairo::ImageSurface) -> Vec<u8> {
+        surface.get_data().unwrap().to_vec()
+    }
+
+    /// A one pixel wide A8 surface whose thirds (one row each) have the given alpha values,
+    /// squashed into a single BGRA pixel
+    fn squash_pixel(alphas: [u8; 3], packing: ChannelPacking, alpha_threshold: u8) -> Vec<u8> {
+        let mut surface = cairo::ImageSurface::create(cairo::Format::A8, 1, 3).unwrap();
+        let stride = surface.get_stride() as usize;
+        {
+            let mut data = surface.get_data().unwrap();
+            for (y, alpha) in alphas.iter().copied().enumerate() {
+                data[y * stride] = alpha;
+            }
+        }
+
+        pixels(squash_surface(surface, packing, alpha_threshold).unwrap())[..4].to_vec()
+    }
+
+    #[test]
+    fn squash_surface_follows_channel_packing() {
+        let alphas = [0x10, 0x80, 0xF0];
+
+        assert_eq!(squash_pixel(alphas, ChannelPacking::Bgr, 0), vec![0x10, 0x80, 0xF0, 0xFF]);
+        assert_eq!(squash_pixel(alphas, ChannelPacking::Rgb, 0), vec![0xF0, 0x80, 0x10, 0xFF]);
+        assert_eq!(squash_pixel(alphas, ChannelPacking::Gbr, 0), vec![0x80, 0x10, 0xF0, 0xFF]);
+    }
+
+    #[test]
+    fn alpha_threshold_zeroes_faint_pixels() {
+        let alphas = [0x08, 0x20, 0xF0];
+
+        assert_eq!(squash_pixel(alphas, ChannelPacking::Bgr, 0), vec![0x08, 0x20, 0xF0, 0xFF]);
+        // The threshold itself is kept
+        assert_eq!(squash_pixel(alphas, ChannelPacking::Bgr, 0x20), vec![0x00, 0x20, 0xF0, 0xFF]);
+        assert_eq!(squash_pixel(alphas, ChannelPacking::Bgr, 0x21), vec![0x00, 0x00, 0xF0, 0xFF]);
+    }
+
+    #[test]
+    fn channel_packing_is_recorded_in_datastream() {
+        let setup = test_setup(&["--channel-packing", "rgb"]);
+        let (_, data, _) = compute_full_layout(&setup, &test_days(), None).unwrap();
+        assert_eq!(data.channel_packing, ChannelPacking::Rgb.code());
+    }
+
+    fn end_text(start: DateTime<FixedOffset>, end: DateTime<FixedOffset>) -> Option<String> {
+        format_end(&event(start, Some(end)), DAY_ROLLOVER_HOUR, &Labels::default())
+    }
+
+    /// End text for an event from 22:00 until the given time the next morning
+    fn next_morning_end_text(rollover_hour: u32, hour: u32, min: u32) -> Option<String> {
+        let ev = event(jst(2021, 6, 1, 22, 0), Some(jst(2021, 6, 2, hour, min)));
+        format_end(&ev, rollover_hour, &Labels::default())
+    }
+
+    #[test]
+    fn format_end_same_day() {
+        assert_eq!(end_text(jst(2021, 6, 1, 20, 0), jst(2021, 6, 1, 22, 30)).as_deref(), Some("~22:30"));
+    }
+
+    #[test]
+    fn format_end_early_next_morning() {
+        assert_eq!(end_text(jst(2021, 6, 1, 22, 0), jst(2021, 6, 2, 2, 0)).as_deref(), Some("~26:00"));
+        assert_eq!(end_text(jst(2021, 6, 1, 22, 0), jst(2021, 6, 2, 3, 30)).as_deref(), Some("~27:30"));
+    }
+
+    #[test]
+    fn format_end_next_day() {
+        assert_eq!(end_text(jst(2021, 6, 1, 22, 0), jst(2021, 6, 2, 10, 0)).as_deref(), Some("~翌10:00"));
+    }
+
+    #[test]
+    fn format_end_exactly_one_day_later() {
+        assert_eq!(end_text(jst(2021, 6, 1, 20, 0), jst(2021, 6, 2, 20, 0)).as_deref(), Some("~翌20:00"));
+    }
+
+    #[test]
+    fn format_end_multi_day() {
+        assert_eq!(
+            end_text(jst(2021, 6, 1, 20, 0), jst(2021, 6, 3, 1, 0)).as_deref(),
+            Some("~06/03 (木) 01:00")
+        );
+    }
+
+    #[test]
+    fn format_end_without_end_time() {
+        assert_eq!(format_end(&event(jst(2021, 6, 1, 20, 0), None), DAY_ROLLOVER_HOUR, &Labels::default()), None);
+    }
+
+    #[test]
+    fn format_end_rollover_at_midnight() {
+        assert_eq!(next_morning_end_text(0, 0, 30).as_deref(), Some("~24:30"));
+        assert_eq!(next_morning_end_text(0, 1, 0).as_deref(), Some("~翌01:00"));
+    }
+
+    #[test]
+    fn format_end_rollover_at_three() {
+        assert_eq!(next_morning_end_text(3, 3, 59).as_deref(), Some("~27:59"));
+        assert_eq!(next_morning_end_text(3, 4, 0).as_deref(), Some("~翌04:00"));
+    }
+
+    #[test]
+    fn format_end_rollover_at_six() {
+        assert_eq!(next_morning_end_text(6, 6, 30).as_deref(), Some("~30:30"));
+        assert_eq!(next_morning_end_text(6, 7, 0).as_deref(), Some("~翌07:00"));
+    }
+
+    /// Start text for a 01:30 event listed under the day before
+    fn early_start_text(style: EarlyStartStyle) -> String {
+        let ev = event(jst(2021, 6, 2, 1, 30), Some(jst(2021, 6, 2, 3, 0)));
+        format_start(&ev, jst(2021, 6, 1, 0, 0).date(), style, &Labels::default())
+    }
+
+    #[test]
+    fn snapped_rows_start_on_whole_pixels() {
+        let setup = test_setup(&["--snap-rows"]);
+        let days = test_days();
+        let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 16, 16).unwrap();
+        let context = cairo::Context::new(&surface);
+
+        let mut entries = vec![];
+        let mut first_event = 0;
+        for day in days.iter() {
+            layout_day(&context, &setup, day, first_event, &mut entries).unwrap();
+            first_event += day.events.len();
+        }
+
+        snap_entries(&mut entries);
+        let mut y = 0.0;
+        for entry in entries.iter() {
+            assert_eq!(y.fract(), 0.0, "entry starts at y={}", y);
+            y += entry.height();
+        }
+
+        let (height, vdata) = event_list(&setup, &days);
+        assert_eq!(height, y);
+        assert_eq!(vdata.len(), y as usize);
+    }
+
+    #[test]
+    fn wrapped_end_time_grows_entry() {
+        let setup = test_setup(&[]);
+        let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 16, 16).unwrap();
+        let context = cairo::Context::new(&surface);
+        let height = |end| {
+            let ev = event(jst(2021, 6, 1, 20, 0), Some(end));
+            layout_single_event(&context, &setup, ev.start_time.date(), &ev, true).unwrap().height()
+        };
+
+        // "~22:00" fits beside the start time; "~06/03 (木) 01:00" wraps onto a second line
+        let one_line = height(jst(2021, 6, 1, 22, 0));
+        let two_lines = height(jst(2021, 6, 3, 1, 0));
+        assert!(two_lines > one_line, "{} <= {}", two_lines, one_line);
+    }
+
+    #[test]
+    fn early_start_styles() {
+        assert_eq!(early_start_text(EarlyStartStyle::Plain), "01:30");
+        assert_eq!(early_start_text(EarlyStartStyle::NextDay), "翌01:30");
+        assert_eq!(early_start_text(EarlyStartStyle::Extended), "25:30");
+    }
+
+    #[test]
+    fn same_day_start_is_unmarked() {
+        let ev = event(jst(2021, 6, 2, 1, 30), None);
+        let date = jst(2021, 6, 2, 0, 0).date();
+        assert_eq!(format_start(&ev, date, EarlyStartStyle::NextDay, &Labels::default()), "01:30");
+        assert_eq!(format_start(&ev, date, EarlyStartStyle::Extended, &Labels::default()), "01:30");
+    }
+
+    /// The height of the event list laid out for `days`, and its row data
+    fn event_list(setup: &SetupInfo, days: &[CalendarDay]) -> (f64, Vec<VerticalData>) {
+        let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 16, 16).unwrap();
+        let context = cairo::Context::new(&surface);
+        let (mut vdata, mut day_event_counts, mut owners) = (vec![], vec![], vec![]);
+
+        let layout = generate_variable_layout(
+            &context, setup, days, &mut vdata, &mut day_event_counts, &mut owners, usize::max_value()
+        )
+        .unwrap();
+        (layout.height(), vdata)
+    }
+
+    /// Every palette index used by event rows (rather than day headers)
+    fn row_colors(rows: &[VerticalData]) -> Vec<PaletteIndex> {
+        rows.iter()
+            .flat_map(|row| match row.col_info {
+                RowColorInfo::Colors(colors) => colors.to_vec(),
+                RowColorInfo::DayHeader { .. } => vec![],
+            })
+            .collect()
+    }
+
+    /// Every palette index used by the event rows of the test days' layout
+    fn event_row_colors(extra: &[&str]) -> Vec<PaletteIndex> {
+        row_colors(&event_list(&test_setup(extra), &test_days()).1)
+    }
+
+    /// The palette index of each day header in the layout of today and tomorrow
+    fn day_header_colors(extra: &[&str]) -> Vec<PaletteIndex> {
+        let mut days = test_days();
+        days.push(CalendarDay {
+            date: jst(2021, 6, 2, 0, 0).date(),
+            events: vec![event(jst(2021, 6, 2, 21, 0), Some(jst(2021, 6, 2, 23, 0)))],
+        });
+
+        event_list(&test_setup(extra), &days)
+            .1
+            .iter()
+            .filter_map(|row| match row.col_info {
+                RowColorInfo::DayHeader { offset: 0, color } => Some(color),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn only_todays_header_is_highlighted() {
+        assert_eq!(day_header_colors(&[]), vec![PAL_DATE, PAL_DATE]);
+        assert_eq!(day_header_colors(&["--highlight-today"]), vec![PAL_TODAY, PAL_DATE]);
+    }
+
+    #[test]
+    fn implied_end_times_are_hidden() {
+        let back_to_back = vec![CalendarDay {
+            date: jst(2021, 6, 1, 0, 0).date(),
+            events: (20..23).map(|h| event(jst(2021, 6, 1, h, 0), Some(jst(2021, 6, 1, h + 1, 0)))).collect(),
+        }];
+        // Every end time is drawn as "~HH:MM"
+        let end_times_shown = |extra: &[&str]| {
+            let setup = test_setup(extra);
+            take_text_histogram();
+            event_list(&setup, &back_to_back);
+            take_text_histogram().get("~").copied().unwrap_or(0)
+        };
+
+        assert_eq!(end_times_shown(&[]), 3);
+        // Only the last event's end isn't the next one's start
+        assert_eq!(end_times_shown(&["--hide-implied-end-times"]), 1);
+    }
+
+    #[test]
+    fn events_starting_soon_get_soon_palette() {
+        let setup = test_setup(&["--highlight-soon", "60"]);
+        let time_colors = |start: DateTime<FixedOffset>| {
+            let days = vec![CalendarDay {
+                date: start.date(),
+                events: vec![event(start, Some(start + chrono::Duration::hours(1)))],
+            }];
+            // The time column's color in each event row
+            row_colors(&event_list(&setup, &days).1).into_iter().step_by(4).collect::<Vec<_>>()
+        };
+
+        let soon = time_colors(jst(2021, 6, 1, 12, 30));
+        assert!(soon.contains(&PAL_SOON) && !soon.contains(&PAL_TIME), "{:?}", soon);
+
+        let later = time_colors(jst(2021, 6, 1, 17, 0));
+        assert!(later.contains(&PAL_TIME) && !later.contains(&PAL_SOON), "{:?}", later);
+
+        // The soon color sits after the today color in the palette
+        let (_, data, _) = compute_full_layout(&setup, &test_days(), None).unwrap();
+        assert_eq!(data.palette[PAL_SOON.get() as usize], ByteColor::from(RGB_SOON));
+    }
+
+    #[test]
+    fn shown_cancelled_events_use_ended_palette() {
+        let setup = test_setup(&["--show-cancelled"]);
+        let start = jst(2021, 6, 2, 21, 0);
+        let mut cancelled = event(start, Some(start + chrono::Duration::hours(1)));
+        assert!(!event_is_ended(&setup, &cancelled));
+
+        cancelled.cancelled = true;
+        assert!(event_is_ended(&setup, &cancelled));
+    }
+
+    #[test]
+    fn force_state_applies_palette_uniformly() {
+        let active = [PAL_TEXT, PAL_TIME, PAL_MARKER];
+        let ended = [PAL_TEXT_ENDED, PAL_TIME_ENDED, PAL_MARKER_ENDED];
+
+        // One event has ended at noon and one has not
+        let auto = event_row_colors(&[]);
+        assert!(active.iter().chain(ended.iter()).all(|pal| auto.contains(pal)));
+
+        let forced_active = event_row_colors(&["--force-state", "active"]);
+        assert!(active.iter().all(|pal| forced_active.contains(pal)));
+        assert!(!ended.iter().any(|pal| forced_active.contains(pal)));
+
+        let forced_ended = event_row_colors(&["--force-state", "ended"]);
+        assert!(ended.iter().all(|pal| forced_ended.contains(pal)));
+        assert!(!active.iter().any(|pal| forced_ended.contains(pal)));
+    }
+
+    #[test]
+    fn compact_layout_puts_time_above_body() {
+        let (wide_height, _) = event_list(&test_setup(&[]), &test_days());
+        let (compact_height, rows) = event_list(&test_setup(&["--layout-mode", "compact"]), &test_days());
+        assert!(compact_height > wide_height, "{} <= {}", compact_height, wide_height);
+
+        // The body column of the still-active event: its time line first, then its body
+        let body_colors: Vec<PaletteIndex> = rows
+            .iter()
+            .filter_map(|row| match row.col_info {
+                RowColorInfo::Colors(colors) if colors[2] == PAL_TIME || colors[2] == PAL_TEXT => Some(colors[2]),
+                _ => None,
+            })
+            .collect();
+        let last_time = body_colors.iter().rposition(|&pal| pal == PAL_TIME).unwrap();
+        let first_text = body_colors.iter().position(|&pal| pal == PAL_TEXT).unwrap();
+        assert!(last_time < first_text);
+    }
+
+    #[test]
+    fn winter_date_picks_winter_header() {
+        let headers: Vec<(HeaderImage, ())> = ["summer.png@06-01..08-31", "winter.png@12-01..02-28", "default.png"]
+            .iter()
+            .map(|arg| (arg.parse().unwrap(), ()))
+            .collect();
+        let chosen = |y, m, d| choose_header(&headers, &jst(y, m, d, 12, 0).date()).unwrap().0.path.as_str();
+
+        // The winter range wraps around the new year
+        assert_eq!(chosen(2021, 12, 24), "winter.png");
+        assert_eq!(chosen(2022, 1, 15), "winter.png");
+        assert_eq!(chosen(2021, 7, 1), "summer.png");
+        assert_eq!(chosen(2021, 4, 1), "default.png");
+
+        // With no match at all, the first is used
+        assert_eq!(choose_header(&headers[1..2], &jst(2021, 4, 1, 12, 0).date()).unwrap().0.path, "winter.png");
+    }
+
+    #[test]
+    fn event_spacing_grows_scroll_height() {
+        let (height, rows) = event_list(&test_setup(&[]), &test_days());
+        let (spaced_height, spaced_rows) = event_list(&test_setup(&["--event-spacing", "10"]), &test_days());
+
+        // One gap, between the day's two events
+        assert_eq!(spaced_height - height, 10.0);
+        assert_eq!(spaced_rows.len(), rows.len() + 10);
+    }
+
+    #[test]
+    fn long_day_header_stops_at_minimum_scale() {
+        let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 16, 16).unwrap();
+        let context = cairo::Context::new(&surface);
+        let text = "2021年06月01日 (火曜日) ".repeat(8);
+
+        let (_title, scale) = shrink_to_fit(&load_font(FONT_DAY_HEADER), 100.0, |font| {
+            TextBox::new(&context, text.clone(), 10000.0, RGB_DATE.into(), font, 1, false)
+        })
+        .unwrap();
+
+        assert!(scale >= MIN_DAY_HEADER_FONT_SCALE, "shrunk to {}", scale);
+        assert!((scale - MIN_DAY_HEADER_FONT_SCALE).abs() < 1e-9, "stopped early at {}", scale);
+    }
+
+    /// Two weeks of days with an event every hour from 08:00 to 20:00
+    fn busy_days() -> Vec<CalendarDay> {
+        let start = jst(2021, 6, 1, 0, 0);
+        (0..14)
+            .map(|d| {
+                let date = start + chrono::Duration::days(d);
+                CalendarDay {
+                    date: date.date(),
+                    events: (0..12)
+                        .map(|h| event(date + chrono::Duration::hours(8 + h), Some(date + chrono::Duration::hours(9 + h))))
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn max_scroll_height_truncates_scroll() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let days = busy_days();
+        let (_, unbounded, _) = compute_full_layout(&test_setup(&[]), &days, None).unwrap();
+        assert!(unbounded.scroll_height > 100);
+
+        let setup = test_setup(&["--max-scroll-height", "100"]);
+        let warnings = Warnings::default();
+        let subscriber = tracing_subscriber::Registry::default().with(warnings.clone());
+        let (_, data, scroll) =
+            tracing::subscriber::with_default(subscriber, || compute_full_layout(&setup, &days, None)).unwrap();
+
+        assert_eq!(scroll.max_height, 100);
+        assert!(data.scroll_height <= 100, "scroll height {}", data.scroll_height);
+        assert!(data.vdata.len() <= 300);
+        let warnings = warnings.0.lock().unwrap();
+        assert!(warnings.iter().any(|w| w.contains("will be cut off")), "{:?}", warnings);
+    }
+
+    #[test]
+    fn oversized_calendar_overflows_datastream() {
+        let mut setup = test_setup(&["--event-ids"]);
+        // A day header template this tall leaves only a few rows beside it for the datastream
+        setup.day_header_template = FillRect::rect(RGB_DATE.into(), 200.0, 1380.0).into_rc();
+
+        // Two weeks of busy days fill the whole scroll section
+        let days = busy_days();
+
+        match compute_full_layout(&setup, &days, None) {
+            Err(CalendarUpdaterError::LayoutOverflow { needed, available }) => {
+                assert!(available > 0);
+                assert!(needed > available, "{} needed, {} available", needed, available);
+            }
+            Err(e) => panic!("expected LayoutOverflow, got {:?}", e),
+            Ok(_) => panic!("expected LayoutOverflow, but the layout fit"),
+        }
+    }
+
+    #[test]
+    fn huge_layout_reports_exceeded_limit() {
+        let mut setup = test_setup(&[]);
+        setup.day_header_template = FillRect::rect(RGB_DATE.into(), 200.0, TEXTURE_HEIGHT as f64 * 2.0).into_rc();
+
+        let err = match compute_full_layout(&setup, &test_days(), None) {
+            Err(e) => e,
+            Ok(_) => panic!("expected DimensionLimit, but the layout fit"),
+        };
+        match &err {
+            CalendarUpdaterError::DimensionLimit { value, limit, .. } => {
+                assert_eq!(*limit, TEXTURE_HEIGHT as f64);
+                assert!(value > limit);
+            }
+            e => panic!("expected DimensionLimit, got {:?}", e),
+        }
+        assert!(err.to_string().contains(&format!("exceeds the limit of {}", TEXTURE_HEIGHT)), "{}", err);
+    }
+
+    /// Reads the elements `data` encodes to back out of `surface`, from where `write` puts them
+    fn read_datastream(surface: &mut cairo::ImageSurface, data: &DatastreamElements) -> Vec<ByteColor> {
+        let len = data.encode().unwrap().len();
+        let stride = surface.get_stride() as usize;
+        let ds_width = data.datastream_width as usize;
+        let pixels = surface.get_data().unwrap();
+
+        (0..len)
+            .map(|i| {
+                let (y, rx) = (i / ds_width, i % ds_width);
+                let offset = stride * y + (data.viewport_w as usize - rx - 1) * 4;
+                let px = u32::from_ne_bytes(pixels[offset..offset + 4].try_into().unwrap());
+                ByteColor::from(((px >> 16) as u8, (px >> 8) as u8, px as u8))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rendered_surface_carries_datastream() {
+        let setup = test_setup(&[]);
+        let (layout, data, _) = compute_full_layout(&setup, &test_days(), None).unwrap();
+        let (width, height) = layout.bounds();
+
+        let mut surface = render_to_surface(&layout, None, &data, setup.background_key).unwrap();
+        assert_eq!(surface.get_width() as usize, (width as usize).next_power_of_two());
+        assert_eq!(surface.get_height() as usize, (height as usize).next_power_of_two());
+
+        let decoded = DatastreamElements::decode(&read_datastream(&mut surface, &data)).unwrap();
+        assert_eq!(decoded.datastream_width, data.datastream_width);
+        assert_eq!(decoded.palette, data.palette);
+        assert_eq!(decoded.vdata, data.vdata);
+        assert_eq!(decoded.day_event_counts, data.day_event_counts);
+    }
+
+    #[test]
+    fn footer_contains_link_text_when_enabled() {
+        let link = "https://example.com/vrc.ics";
+        assert!(footer_link(&test_setup(&[]), (1024.0, 128.0)).unwrap().is_none());
+
+        let setup = test_setup(&["--footer-link", link]);
+        let mut data = config_datastream_info();
+        take_text_histogram();
+        layout_template(&setup, &mut data).unwrap();
+        let clusters = take_text_histogram();
+
+        for ch in link.chars() {
+            assert!(clusters.contains_key(&ch.to_string()), "{:?} was not laid out", ch);
+        }
+    }
+
+    /// Collects (span name, field name) for every field recorded on a span after it was created
+    #[derive(Clone, Default)]
+    struct SpanRecords(std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>);
+
+    struct FieldNames(Vec<String>);
+
+    impl tracing::field::Visit for FieldNames {
+        fn record_debug(&mut self, field: &tracing::field::Field, _value: &dyn std::fmt::Debug) {
+            self.0.push(field.name().to_string());
+        }
+    }
+
+    impl<S> tracing_subscriber::Layer<S> for SpanRecords
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let span = ctx.span(id).map(|span| span.name()).unwrap_or_default();
+            let mut fields = FieldNames(vec![]);
+            values.record(&mut fields);
+
+            let mut records = self.0.lock().unwrap();
+            records.extend(fields.0.into_iter().map(|field| (span.to_string(), field)));
+        }
+    }
+
+    /// Collects the message of every warning logged
+    #[derive(Clone, Default)]
+    struct Warnings(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    struct Message(String);
+
+    impl tracing::field::Visit for Message {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Warnings {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+            if *event.metadata().level() == Level::WARN {
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                self.0.lock().unwrap().push(message.0);
+            }
+        }
+    }
+
+    #[test]
+    fn unbreakable_token_warns() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 16, 16).unwrap();
+        let context = cairo::Context::new(&surface);
+        let font = load_font(FONT_EVENT_INFO);
+        let layout = |text: &str| {
+            let warnings = Warnings::default();
+            let subscriber = tracing_subscriber::Registry::default().with(warnings.clone());
+            tracing::subscriber::with_default(subscriber, || {
+                TextBox::new(&context, text.into(), 200.0, RGB_TEXT.into(), &font, 2, false).unwrap()
+            });
+            let messages = warnings.0.lock().unwrap().clone();
+            messages
+        };
+
+        assert!(layout("a short title that wraps onto a second line").is_empty());
+
+        // Far wider than 200px, with nowhere to break between words
+        let token = "unbreakable".repeat(8);
+        let warnings = layout(&token);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("too wide to word-wrap") && warnings[0].contains(&token), "{:?}", warnings);
+    }
+
+    #[test]
+    fn emoji_summary_is_drawn() {
+        let font = load_font(FONT_EVENT_INFO);
+        let family = font.get_family().unwrap().to_string();
+        assert!(family.ends_with(EMOJI_FALLBACK_FAMILIES), "{}", family);
+
+        let mut surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 64, 64).unwrap();
+        let mut context = cairo::Context::new(&surface);
+        let text = TextBox::new(&context, "🎵".into(), 64.0, RGB_TEXT.into(), &font, 1, false).unwrap();
+        text.render(&mut context).unwrap();
+        std::mem::drop(context);
+        surface.flush();
+
+        // A color emoji, or failing that a monochrome glyph in the text color
+        let stride = surface.get_stride() as usize;
+        let data = surface.get_data().unwrap();
+        let drawn = data
+            .chunks_exact(stride)
+            .flat_map(|row| row[..64 * 4].chunks_exact(4))
+            .filter(|px| u32::from_ne_bytes([px[0], px[1], px[2], px[3]]) & 0x00FF_FFFF != 0)
+            .count();
+        assert!(drawn > 0, "the emoji was not drawn");
+    }
+
+    #[test]
+    fn pipeline_phases_are_timed_in_spans() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let calendar = temp_path("spans.ics");
+        std::fs::write(&calendar, calendar::write_ics(&test_days(), jst(2021, 6, 1, 12, 0))).unwrap();
+        let output = temp_path("spans.png");
+        let mut opts = test_opts(&["--calendar-file", calendar.as_str()]);
+        opts.output = output.to_string();
+
+        let records = SpanRecords::default();
+        let subscriber = tracing_subscriber::Registry::default().with(records.clone());
+        tracing::subscriber::with_default(subscriber, || run(&opts).unwrap());
+
+        let records = records.0.lock().unwrap();
+        let recorded = |span: &str, field: &str| records.iter().any(|(s, f)| s == span && f == field);
+        for phase in ["fetch", "parse", "filter", "layout", "encode", "render"].iter().copied() {
+            assert!(recorded(phase, "elapsed_ms"), "no elapsed_ms for {}", phase);
+        }
+        assert!(recorded("parse", "skipped"));
+        assert!(recorded("filter", "remaining"));
+        assert!(recorded("encode", "bytes"));
+    }
+
+    #[test]
+    fn svg_preview_contains_event_text() {
+        let setup = test_setup(&[]);
+        let days = test_days();
+        let path = temp_path("preview.svg");
+
+        let layout = preview_layout(&setup, &days).unwrap();
+        render_preview_svg(&layout, &event_list_lines(&days, &setup).join("\n"), &path).unwrap();
+
+        let svg = std::fs::read_to_string(path.as_str()).unwrap();
+        assert!(svg.contains("<desc>"));
+        for text in ["2021/06/01 (火)", "09:00", "~23:00", "テストイベント"].iter().copied() {
+            assert!(svg.contains(text), "{:?} missing from the preview", text);
+        }
+    }
+
+    #[test]
+    fn verify_passes_on_normal_render() {
+        let calendar = temp_path("verify.ics");
+        std::fs::write(&calendar, calendar::write_ics(&test_days(), jst(2021, 6, 1, 12, 0))).unwrap();
+        let output = temp_path("verify.png");
+
+        let mut opts = test_opts(&["--calendar-file", calendar.as_str(), "--verify"]);
+        opts.output = output.to_string();
+        run(&opts).unwrap();
+    }
+
+    #[test]
+    fn verify_catches_corrupted_datastream() {
+        let setup = test_setup(&[]);
+        let (layout, data, _) = compute_full_layout(&setup, &test_days(), None).unwrap();
+        let mut surface = render_to_surface(&layout, None, &data, setup.background_key).unwrap();
+        data.verify(&mut surface).unwrap();
+
+        // Element 2 of the datastream: the third pixel left of the viewport's right edge
+        {
+            let x = data.viewport_w as usize - 3;
+            let mut pixels = surface.get_data().unwrap();
+            for byte in pixels[x * 4..x * 4 + 3].iter_mut() {
+                *byte ^= 0xFF;
+            }
+        }
+
+        let err = data.verify(&mut surface).unwrap_err();
+        assert!(err.to_string().contains("mismatch at element 2"), "{}", err);
+    }
+
+    #[test]
+    fn english_labels_change_rendered_strings() {
+        let path = temp_path("labels.json");
+        std::fs::write(
+            &path,
+            r#"{
+                "weekdays": ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"],
+                "next_day": "+1 ",
+                "all_day": "All day",
+                "no_events": "No events"
+            }"#,
+        )
+        .unwrap();
+        let setup = test_setup(&["--labels", path.as_str()]);
+
+        let mut all_day = event(jst(2021, 6, 1, 0, 0), Some(jst(2021, 6, 2, 0, 0)));
+        all_day.all_day = true;
+        let late = event(jst(2021, 6, 1, 20, 0), Some(jst(2021, 6, 2, 10, 0)));
+        let days = vec![
+            CalendarDay { date: jst(2021, 6, 1, 0, 0).date(), events: vec![all_day, late] },
+            CalendarDay { date: jst(2021, 6, 2, 0, 0).date(), events: vec![] },
+        ];
+
+        let lines = event_list_lines(&days, &setup);
+        assert_eq!(lines[0], "2021/06/01 (Tue)");
+        assert!(lines[1].trim_start().starts_with("All day"), "{:?}", lines[1]);
+        assert!(lines[2].contains("~+1 10:00"), "{:?}", lines[2]);
+        assert_eq!(lines[3], "2021/06/02 (Wed)");
+
+        // The empty day gets the English filler, and none of the Japanese defaults are drawn
+        take_text_histogram();
+        event_list(&setup, &days);
+        let clusters = take_text_histogram();
+        assert!("No events".chars().filter(|c| !c.is_whitespace()).all(|c| clusters.contains_key(&c.to_string())));
+        for label in ["終", "翌", "【", "火"].iter().copied() {
+            assert!(!clusters.contains_key(label), "{:?} was still drawn", label);
+        }
+    }
+
+    #[test]
+    fn skip_if_empty_leaves_no_output() {
+        let calendar = temp_path("empty.ics");
+        std::fs::write(&calendar, "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nEND:VCALENDAR\r\n").unwrap();
+        let output = temp_path("empty.png");
+
+        let mut opts = test_opts(&["--calendar-file", calendar.as_str(), "--skip-if-empty"]);
+        opts.output = output.to_string();
+        run(&opts).unwrap();
+        assert!(!std::path::Path::new(output.as_str()).exists());
+
+        // Without the flag, the empty board is written as usual
+        opts.skip_if_empty = false;
+        run(&opts).unwrap();
+        assert!(std::path::Path::new(output.as_str()).exists());
+    }
+
+    #[test]
+    fn only_day_renders_just_that_day() {
+        let days: Vec<CalendarDay> = (2..5)
+            .map(|d| CalendarDay {
+                date: jst(2021, 6, d, 0, 0).date(),
+                events: vec![event(jst(2021, 6, d, 21, 0), Some(jst(2021, 6, d, 23, 0)))],
+            })
+            .collect();
+        let path = temp_path("only-day.ics");
+        std::fs::write(&path, calendar::write_ics(&days, jst(2021, 6, 1, 12, 0))).unwrap();
+
+        let run = |extra: &[&str]| {
+            let mut args = vec!["--calendar-file", path.as_str()];
+            args.extend_from_slice(extra);
+            let opts = test_opts(&args);
+            let mut setup = setup_environment(&opts).unwrap();
+            let (days, _) = fetch_days(&opts, &mut setup).unwrap();
+            let (_, data, _) = compute_full_layout(&setup, &days, None).unwrap();
+            (days.iter().map(|day| day.date.naive_local()).collect::<Vec<_>>(), data.scroll_height)
+        };
+        let (all_dates, all_height) = run(&[]);
+        let (dates, height) = run(&["--only-day", "2021-06-03"]);
+
+        assert_eq!(all_dates.len(), 3);
+        assert_eq!(dates, vec![NaiveDate::from_ymd(2021, 6, 3)]);
+        assert!(height < all_height, "{} >= {}", height, all_height);
+    }
+
+    #[test]
+    fn assets_are_reused_across_calendars() {
+        let opts = test_opts(&[]);
+        let assets = load_assets(&opts).unwrap();
+        let setup = setup_run(&opts, &assets).unwrap();
+
+        let mut busier = test_days();
+        busier[0].events.push(event(jst(2021, 6, 1, 22, 0), Some(jst(2021, 6, 1, 23, 30))));
+
+        let render = |days: &Vec<CalendarDay>| {
+            let (layout, data, _) = compute_full_layout(&setup, days, None).unwrap();
+            let mut surface = render_to_surface(&layout, None, &data, setup.background_key).unwrap();
+            data.verify(&mut surface).unwrap();
+            (data.vdata, pixels(surface))
+        };
+        let (first_rows, first) = render(&test_days());
+        let (second_rows, second) = render(&busier);
+
+        assert!(second_rows.len() > first_rows.len());
+        assert!(first != second);
+    }
+
+    #[test]
+    fn event_ids_map_rows_to_events() {
+        let setup = test_setup(&["--event-ids"]);
+        let mut days = test_days();
+        days[0].events[0].uid = "morning@example.com".into();
+        days[0].events[1].uid = "evening@example.com".into();
+
+        let (_, data, _) = compute_full_layout(&setup, &days, None).unwrap();
+        let decoded = DatastreamElements::decode(&data.encode().unwrap()).unwrap();
+        assert_eq!(decoded.event_rows, data.event_rows);
+
+        let id_at = |row: u32| {
+            decoded.event_rows.iter().find(|r| r.start_row <= row && row < r.end_row).map(|r| r.id)
+        };
+        let ids: Vec<u32> = decoded.event_rows.iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![event_id("morning@example.com"), event_id("evening@example.com")]);
+        assert!(ids.iter().all(|&id| id < 1 << 18));
+
+        // Every row of each range resolves to its event, and day header rows to none
+        for range in decoded.event_rows.iter() {
+            assert!(range.start_row < range.end_row && range.end_row as usize <= decoded.vdata.len());
+            assert!((range.start_row..range.end_row).all(|row| id_at(row) == Some(range.id)));
+        }
+        for row in decoded.day_header_rows() {
+            assert_eq!(id_at(row), None);
+        }
+    }
+
+    #[test]
+    fn overlay_covers_events_but_not_datastream() {
+        let setup = test_setup(&[]);
+        let (layout, data, _) = compute_full_layout(&setup, &test_days(), None).unwrap();
+        let (width, height) = layout.bounds();
+        let accent = (0x12, 0x34, 0x56);
+        let overlay = FillRect::rect(accent.into(), width, height);
+
+        let mut surface = render_to_surface(&layout, Some(&overlay), &data, setup.background_key).unwrap();
+        data.verify(&mut surface).unwrap();
+        let decoded = DatastreamElements::decode(&read_datastream(&mut surface, &data)).unwrap();
+        assert_eq!(decoded.vdata, data.vdata);
+
+        // A pixel in the middle of the event list is covered
+        let stride = surface.get_stride() as usize;
+        let offset = stride * (data.scroll_tex_y as usize + 10) + 512 * 4;
+        let pixels = surface.get_data().unwrap();
+        let px = u32::from_ne_bytes(pixels[offset..offset + 4].try_into().unwrap()) & 0x00FF_FFFF;
+        assert_eq!(px, 0x123456);
+    }
+
+    #[test]
+    fn datastream_is_written_relative_to_viewport() {
+        let setup = test_setup(&[]);
+        let (_, data, _) = compute_full_layout(&setup, &test_days(), None).unwrap();
+        let surface_width = data.viewport_w as i32 * 2;
+
+        let mut surface = cairo::ImageSurface::create(cairo::Format::Rgb24, surface_width, 64).unwrap();
+        data.write(&mut surface).unwrap();
+        data.verify(&mut surface).unwrap();
+
+        let decoded = DatastreamElements::decode(&read_datastream(&mut surface, &data)).unwrap();
+        assert_eq!(decoded.vdata, data.vdata);
+
+        // Nothing lands in the padding right of the viewport
+        let pixels = surface.get_data().unwrap();
+        let padding = (data.viewport_w as usize * 4)..(surface_width as usize * 4);
+        assert!(pixels[padding].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn layout_cache_hit_matches_fresh_layout() {
+        let setup = test_setup(&[]);
+        let days = test_days();
+        let events = snapshot::snapshot(&days);
+        let path = temp_path("layout-cache.json");
+
+        let (layout, data, scroll) = compute_full_layout(&setup, &days, None).unwrap();
+        save_cached_scroll(&path, &setup, &events, &scroll).unwrap();
+        let _texture = TempPath(layout_cache::surface_path(&path));
+
+        let reuse = load_cached_scroll(&path, &setup, &days, &events);
+        assert!(reuse.is_some(), "cache was not used");
+        let (cached_layout, cached_data, _) = compute_full_layout(&setup, &days, reuse).unwrap();
+
+        assert_eq!(cached_data, data);
+        let fresh = render_to_surface(&layout, None, &data, setup.background_key).unwrap();
+        let cached = render_to_surface(&cached_layout, None, &cached_data, setup.background_key).unwrap();
+        assert!(pixels(cached) == pixels(fresh), "cached layout renders differently");
+    }
+
+    #[test]
+    fn corrupt_layout_cache_is_ignored() {
+        let setup = test_setup(&[]);
+        let days = test_days();
+        let path = temp_path("corrupt-layout-cache.json");
+        std::fs::write(&path, "{ not json").unwrap();
+
+        assert!(load_cached_scroll(&path, &setup, &days, &snapshot::snapshot(&days)).is_none());
+    }
+
+    #[test]
+    fn glyph_report_lists_rendered_characters() {
+        let histogram: HashMap<String, u32> =
+            vec![("a".to_string(), 2), ("b".to_string(), 1), ("\"".to_string(), 1)].into_iter().collect();
+        let widths: HashMap<u32, u32> = vec![(8, 4)].into_iter().collect();
+        let path = temp_path("glyph-report.json");
+
+        write_glyph_report(&path, &histogram, &widths).unwrap();
+        let report: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+
+        assert_eq!(report["characters"], "\"ab");
+        assert_eq!(report["clusters"]["a"], 2);
+        assert_eq!(report["clusters"]["\""], 1);
+        assert_eq!(report["widths"]["8"], 4);
+    }
+
+    #[test]
+    fn stats_list_skipped_events() {
+        let warnings = vec![calendar::ParseWarning {
+            event: "broken@example.com".into(),
+            reason: "Event is missing property: SUMMARY".into(),
+        }];
+        let path = temp_path("stats.json");
+
+        write_stats(&path, &RunStats { days: 2, events: 5, fetch_failed: false, skipped: &warnings }).unwrap();
+        let stats: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+
+        assert_eq!(stats["events"], 5);
+        assert_eq!(stats["skipped"][0]["event"], "broken@example.com");
+        assert_eq!(stats["skipped"][0]["reason"], "Event is missing property: SUMMARY");
+    }
+
+    fn contains_color(pixels: &[u8], (r, g, b): RGBInt) -> bool {
+        let want = u32::from_be_bytes([0, r, g, b]);
+        pixels
+            .chunks_exact(4)
+            .any(|px| u32::from_ne_bytes([px[0], px[1], px[2], px[3]]) & 0x00FF_FFFF == want)
+    }
+
+    #[test]
+    fn custom_background_key_is_filled_in() {
+        let setup = test_setup(&["--background-key", "123456"]);
+        let (layout, data, _) = compute_full_layout(&setup, &test_days(), None).unwrap();
+        let custom = pixels(render_to_surface(&layout, None, &data, setup.background_key).unwrap());
+
+        assert_eq!(setup.background_key, (0x12, 0x34, 0x56));
+        assert!(contains_color(&custom, (0x12, 0x34, 0x56)));
+        assert!(!contains_color(&custom, RGB_BACKGROUND_KEY));
+    }
+
+    #[test]
+    fn custom_marker_colors_are_drawn_and_encoded() {
+        let (active, ended) = ((0x12, 0x34, 0x56), (0x65, 0x43, 0x21));
+        let setup = test_setup(&["--marker-color", "123456", "--marker-ended-color", "654321"]);
+        let days = test_days();
+
+        // The first event has ended by noon, the second has not
+        let ended_tile = pixels(render_event_tile(&days[0].events[0], &setup).unwrap());
+        let active_tile = pixels(render_event_tile(&days[0].events[1], &setup).unwrap());
+        assert!(contains_color(&ended_tile, ended) && !contains_color(&ended_tile, active));
+        assert!(contains_color(&active_tile, active) && !contains_color(&active_tile, ended));
+
+        let (_, data, _) = compute_full_layout(&setup, &days, None).unwrap();
+        assert_eq!(data.palette[PAL_MARKER.get() as usize], ByteColor::from(active));
+        assert_eq!(data.palette[PAL_MARKER_ENDED.get() as usize], ByteColor::from(ended));
+    }
+
+    #[test]
+    fn full_width_event_has_no_marker_or_time_column() {
+        let setup = test_setup(&[]);
+        let mut days = test_days();
+        let normal = pixels(render_event_tile(&days[0].events[1], &setup).unwrap());
+        assert!(contains_color(&normal, RGB_EVENT_MARKER));
+
+        days[0].events[1].full_width = true;
+        let wide = pixels(render_event_tile(&days[0].events[1], &setup).unwrap());
+        assert!(!contains_color(&wide, RGB_EVENT_MARKER));
+
+        // Only the ended event has time column colors left
+        let colors = row_colors(&event_list(&setup, &days).1);
+        assert!(colors.contains(&PAL_TEXT));
+        assert!(!colors.contains(&PAL_MARKER) && !colors.contains(&PAL_TIME));
+    }
+
+    /// The scroll background sample over the background key, for a template which is fully
+    /// transparent there
+    fn backdrop_pixels(extra: &[&str]) -> Vec<u8> {
+        let mut setup = test_setup(extra);
+        let (width, height) = setup.template.bounds();
+        setup.template = Pad::new(width, height).into_rc();
+
+        let background = scroll_background(&setup, BG_SAMPLE_HEIGHT as f64);
+        let (w, h) = background.bounds();
+        let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, w.ceil() as i32, h.ceil() as i32).unwrap();
+        let mut context = cairo::Context::new(&surface);
+        let key: Color = setup.background_key.into();
+        context.set_source_rgb(key.r, key.g, key.b);
+        context.paint();
+        background.render(&mut context).unwrap();
+        std::mem::drop(context);
+
+        pixels(surface)
+    }
+
+    #[test]
+    fn scroll_backdrop_fills_behind_events() {
+        let bare = backdrop_pixels(&[]);
+        assert!(contains_color(&bare, RGB_BACKGROUND_KEY));
+
+        let backed = backdrop_pixels(&["--scroll-backdrop", "204060"]);
+        assert!(contains_color(&backed, (0x20, 0x40, 0x60)));
+        assert!(!contains_color(&backed, RGB_BACKGROUND_KEY));
+
+        // The packed text layer is unaffected
+        let scroll = |extra: &[&str]| pixels(compute_full_layout(&test_setup(extra), &test_days(), None).unwrap().2.surface);
+        assert!(scroll(&[]) == scroll(&["--scroll-backdrop", "204060"]));
+    }
+
+    /// For each column of `surface`, how many of its pixels are exactly `color`
+    fn color_columns(mut surface: cairo::ImageSurface, (r, g, b): RGBInt) -> Vec<usize> {
+        let want = u32::from_be_bytes([0, r, g, b]);
+        let (width, stride) = (surface.get_width() as usize, surface.get_stride() as usize);
+        let data = surface.get_data().unwrap();
+
+        (0..width)
+            .map(|x| {
+                data.chunks_exact(stride)
+                    .filter(|row| u32::from_ne_bytes(row[x * 4..x * 4 + 4].try_into().unwrap()) & 0x00FF_FFFF == want)
+                    .count()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mirrored_layout_flips_time_column_and_marker() {
+        let event = &test_days()[0].events[1];
+        let columns = |extra: &[&str], color| color_columns(render_event_tile(event, &test_setup(extra)).unwrap(), color);
+        let centroid = |counts: &[usize]| {
+            let total: usize = counts.iter().sum();
+            counts.iter().enumerate().map(|(x, n)| x * n).sum::<usize>() as f64 / total as f64
+        };
+
+        let time = columns(&[], RGB_TIME);
+        let mirrored_time = columns(&["--mirror"], RGB_TIME);
+        let middle = time.len() as f64 / 2.0;
+        assert!(centroid(&time) < middle && centroid(&mirrored_time) > middle);
+
+        // The marker's flat edge is on the time column's side, with the point away from it
+        let marker: Vec<usize> = columns(&[], RGB_EVENT_MARKER).into_iter().filter(|&n| n > 0).collect();
+        let mirrored_marker: Vec<usize> = columns(&["--mirror"], RGB_EVENT_MARKER).into_iter().filter(|&n| n > 0).collect();
+        assert!(marker.first() > marker.last(), "{:?}", marker);
+        assert!(mirrored_marker.first() < mirrored_marker.last(), "{:?}", mirrored_marker);
+    }
+
+    #[test]
+    fn background_key_collisions_cover_runtime_palette() {
+        let setup = test_setup(&["--marker-color", "123456", "--extra-palette-color", "ABCDEF", "--highlight-today"]);
+        let (_, data, _) = compute_full_layout(&setup, &test_days(), None).unwrap();
+
+        assert!(background_key_collisions(RGB_BACKGROUND_KEY, &data.palette).is_empty());
+        assert_eq!(background_key_collisions((0x12, 0x34, 0x56), &data.palette), vec![PAL_MARKER.get() as usize]);
+        assert_eq!(background_key_collisions(RGB_TODAY, &data.palette), vec![PAL_TODAY.get() as usize]);
+        assert_eq!(background_key_collisions((0xAB, 0xCD, 0xEF), &data.palette), vec![data.palette.len() - 1]);
+        // The default marker color is no longer drawn once it has been replaced
+        assert!(background_key_collisions(RGB_EVENT_MARKER, &data.palette).is_empty());
+    }
+
+    /// The pixels of the event's tile
+    fn tile(setup: &SetupInfo, start: DateTime<FixedOffset>, end: DateTime<FixedOffset>) -> Vec<u8> {
+        let tile = render_event_tile(&event(start, Some(end)), setup).unwrap();
+        assert_eq!(tile.get_width(), VARIABLE_OUTER_RIGHT - VARIABLE_OUTER_LEFT);
+        assert!(tile.get_height() > 0);
+        pixels(tile)
+    }
+
+    #[test]
+    fn active_event_tile() {
+        let setup = test_setup(&[]);
+        let tile = tile(&setup, jst(2021, 6, 1, 21, 0), jst(2021, 6, 1, 23, 0));
+
+        assert!(contains_color(&tile, RGB_EVENT_MARKER));
+    }
+
+    #[test]
+    fn ended_event_tile() {
+        let setup = test_setup(&[]);
+        let active = tile(&setup, jst(2021, 6, 1, 21, 0), jst(2021, 6, 1, 23, 0));
+        let ended = tile(&setup, jst(2021, 6, 1, 9, 0), jst(2021, 6, 1, 10, 0));
+
+        assert!(contains_color(&ended, RGB_EVENT_MARKER_ENDED));
+        assert!(!contains_color(&ended, RGB_EVENT_MARKER));
+        assert!(ended != active);
+    }
+
+    #[test]
+    fn overnight_event_tile() {
+        let setup = test_setup(&[]);
+        let same_day = tile(&setup, jst(2021, 6, 1, 22, 0), jst(2021, 6, 1, 23, 0));
+        let overnight = tile(&setup, jst(2021, 6, 1, 22, 0), jst(2021, 6, 2, 2, 0));
+
+        // Not yet ended, but drawn with a different end time
+        assert!(contains_color(&overnight, RGB_EVENT_MARKER));
+        assert!(overnight != same_day);
+    }
+
+    #[test]
+    fn oversized_font_is_clamped() {
+        let font = load_font("M+ 1m bold 9999");
+        assert_eq!(font.get_size(), (MAX_FONT_SIZE * pango::SCALE as f64) as i32);
+
+        let font = load_font(FONT_CONFIG_INFO);
+        assert_eq!(font.get_size(), (10.8 * pango::SCALE as f64) as i32);
+    }
+
+    /// 21:00-23:00 JST on 2021-06-02, i.e. 05:00-07:00 PDT the same day
+    const ZONED_EVENT_ICS: &str = "BEGIN:VCALENDAR\r\n\
+        VERSION:2.0\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:zoned@example.com\r\n\
+        DTSTART:20210602T210000+0900\r\n\
+        DTEND:20210602T230000+0900\r\n\
+        SUMMARY:Zoned event\r\n\
+        END:VEVENT\r\n\
+        END:VCALENDAR\r\n";
+
+    /// Fetches ZONED_EVENT_ICS displayed in `zone`, returning the event's listing date, its
+    /// start and end text, and its rendered tile
+    fn zoned_event(zone: &str) -> (NaiveDate, String, Option<String>, Vec<u8>) {
+        let path = temp_path(&format!("zoned-{}.ics", zone.replace('/', "-")));
+        std::fs::write(&path, ZONED_EVENT_ICS).unwrap();
+
+        let opts = zoned_test_opts(zone, &["--calendar-file", &path]);
+        let setup = setup_environment(&opts).unwrap();
+        let (days, _) = calendar::fetch_calendar(&fetch_options(&opts, &setup)).unwrap();
+
+        assert_eq!(days.len(), 1);
+        let day = &days[0];
+        let event = &day.events[0];
+        (
+            day.date.naive_local(),
+            format_start(event, day.date, setup.early_start_style, &setup.labels),
+            format_end(event, setup.rollover_hour, &setup.labels),
+            pixels(render_event_tile(event, &setup).unwrap()),
+        )
+    }
+
+    #[test]
+    fn event_times_follow_display_zone() {
+        let (tokyo_date, tokyo_start, tokyo_end, tokyo_tile) = zoned_event("Asia/Tokyo");
+        let (la_date, la_start, la_end, la_tile) = zoned_event("America/Los_Angeles");
+
+        assert_eq!(tokyo_date, NaiveDate::from_ymd(2021, 6, 2));
+        assert_eq!(tokyo_start, "21:00");
+        assert_eq!(tokyo_end.as_deref(), Some("~23:00"));
+
+        assert_eq!(la_date, NaiveDate::from_ymd(2021, 6, 2));
+        assert_eq!(la_start, "05:00");
+        assert_eq!(la_end.as_deref(), Some("~07:00"));
+
+        assert!(tokyo_tile != la_tile, "times render the same in both zones");
+    }
+
+    /// One event at 21:00 JST on 2021-06-02, and another a month later
+    const MONTH_APART_ICS: &str = "BEGIN:VCALENDAR\r\n\
+        VERSION:2.0\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:june@example.com\r\n\
+        DTSTART:20210602T210000+0900\r\n\
+        SUMMARY:June\r\n\
+        END:VEVENT\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:july@example.com\r\n\
+        DTSTART:20210702T210000+0900\r\n\
+        SUMMARY:July\r\n\
+        END:VEVENT\r\n\
+        END:VCALENDAR\r\n";
+
+    /// The current time and the titles of the events shown, for the given options
+    fn shown_events(extra: &[&str]) -> (DateTime<FixedOffset>, Vec<String>) {
+        let path = temp_path("month-apart.ics");
+        std::fs::write(&path, MONTH_APART_ICS).unwrap();
+
+        let mut args = vec!["--calendar-file", path.as_str()];
+        args.extend_from_slice(extra);
+        let opts = untimed_test_opts("Asia/Tokyo", &args);
+        let setup = setup_environment(&opts).unwrap();
+        let (days, _) = calendar::fetch_calendar(&fetch_options(&opts, &setup)).unwrap();
+
+        let bodies = days.iter().flat_map(|day| day.events.iter()).map(|e| e.body.clone()).collect();
+        (setup.now, bodies)
+    }
+
+    #[test]
+    fn now_flag_takes_precedence_over_environment() {
+        // No other test leaves the time to VRC_CAL_NOW, so setting it here can't affect them
+        std::env::set_var("VRC_CAL_NOW", "2021-07-01T12:00:00+09:00");
+        let (env_now, env_events) = shown_events(&[]);
+        let (flag_now, flag_events) = shown_events(&["--now", "2021-06-01T12:00:00+09:00"]);
+        std::env::remove_var("VRC_CAL_NOW");
+        let (clock_now, _) = shown_events(&[]);
+
+        assert_eq!(env_now, jst(2021, 7, 1, 12, 0));
+        assert_eq!(env_events, vec!["July"]);
+
+        assert_eq!(flag_now, jst(2021, 6, 1, 12, 0));
+        assert_eq!(flag_events, vec!["June"]);
+
+        let drift = Utc::now().signed_duration_since(clock_now);
+        assert!(drift >= chrono::Duration::zero() && drift < chrono::Duration::minutes(1), "clock time {}", clock_now);
+    }
+
+    #[test]
+    fn failed_fetch_renders_error_board() {
+        let missing = temp_path("missing.ics");
+        let json_out = temp_path("error-board.json");
+        let ics_out = temp_path("error-board.ics");
+        let opts = test_opts(&[
+            "--error-board",
+            "--calendar-file", &missing,
+            "--json-out", &json_out,
+            "--ics-out", &ics_out,
+        ]);
+        let mut setup = setup_environment(&opts).unwrap();
+
+        let (days, warnings) = fetch_days(&opts, &mut setup).unwrap();
+        assert!(setup.fetch_failed);
+        assert!(warnings.is_empty());
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].date, setup.now.date());
+        assert!(days[0].events.is_empty());
+        // The label itself is left alone; the layout picks the message
+        assert_eq!(setup.labels.no_events, Labels::default().no_events);
+
+        write_event_files(&opts, &setup, &days, &snapshot::snapshot(&days)).unwrap();
+        assert!(!std::path::Path::new(json_out.as_str()).exists());
+        assert!(!std::path::Path::new(ics_out.as_str()).exists());
+
+        let (layout, data, _) = compute_full_layout(&setup, &days, None).unwrap();
+        let error_board = pixels(render_to_surface(&layout, None, &data, setup.background_key).unwrap());
+
+        setup.fetch_failed = false;
+        let (layout, data, _) = compute_full_layout(&setup, &days, None).unwrap();
+        let empty_board = pixels(render_to_surface(&layout, None, &data, setup.background_key).unwrap());
+        assert!(error_board != empty_board, "error board says there are no events");
+    }
+
+    /// Label for an update `minutes` before 2021-06-01 12:00 JST
+    fn age_text(minutes: i64) -> String {
+        let now = jst(2021, 6, 1, 12, 0);
+        age_label(now - chrono::Duration::minutes(minutes), now, &Labels::default())
+    }
+
+    #[test]
+    fn age_label_buckets_by_threshold() {
+        assert_eq!(age_text(0), "更新 0分前");
+        assert_eq!(age_text(59), "更新 59分前");
+        assert_eq!(age_text(60), "更新 1時間前");
+        assert_eq!(age_text(23 * 60 + 59), "更新 23時間前");
+        assert_eq!(age_text(24 * 60), "更新 1日前");
+        assert_eq!(age_text(10 * 24 * 60), "更新 10日前");
+    }
+
+    #[test]
+    fn age_label_treats_future_updates_as_just_now() {
+        assert_eq!(age_text(-5), "更新 0分前");
+    }
 }
@@ -24,7 +24,9 @@ use cairo::Rectangle;
 
 use pango::{FontDescription, Layout};
 
-use crate::config::FONT_SCALE;
+use crate::config::{FONT_SCALE, PLACEHOLDER_SIZE};
+
+use tracing::warn;
 
 pub type RGBInt = (u8, u8, u8);
 
@@ -36,23 +38,80 @@ pub const fn rgb(col: u32) -> RGBInt {
     (r, g, b)
 }
 
+/// Parses a color in `RRGGBB` or `#RRGGBB` form
+pub fn parse_rgb(s: &str) -> Result<RGBInt> {
+    let hex = s.trim_start_matches('#');
+    if hex.len() != 6 {
+        anyhow::bail!("Expected a color in RRGGBB form, got {:?}", s);
+    }
+
+    let col = u32::from_str_radix(hex, 16)
+        .with_context(|| format!("Parsing color {:?}", s))?;
+
+    Ok(rgb(col))
+}
+
 const PANGO_SCALE: f64 = 1024.0;
 
 use std::rc::Rc;
 
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+static DEBUG_COLORS: AtomicBool = AtomicBool::new(false);
 
-static COUNTER: AtomicU32 = AtomicU32::new(0);
+static WRAP_OVERFLOW: AtomicBool = AtomicBool::new(false);
 
-fn debug_color(surf: &mut cairo::Context) {
-    let mut c = COUNTER.fetch_add(1, Ordering::Relaxed);
-    let r = (1 + c % 4) as f64 / 4.0;
-    c >>= 2;
-    let g = (1 + c % 4) as f64 / 4.0;
-    c >>= 2;
-    let b = (1 + c % 4) as f64 / 4.0;
+/// Bits of the f64 line spacing factor; 1.0 to start with
+static LINE_SPACING: AtomicU64 = AtomicU64::new(0x3FF0_0000_0000_0000);
 
-    surf.set_source_rgb(r, g, b);
+/// When set, text which can't be word-wrapped to fit (e.g. a long URL) is broken between
+/// characters instead of running past its box and being clipped
+pub fn set_wrap_overflow(enabled: bool) {
+    WRAP_OVERFLOW.store(enabled, Ordering::Relaxed);
+}
+
+pub fn wrap_overflow() -> bool {
+    WRAP_OVERFLOW.load(Ordering::Relaxed)
+}
+
+/// Scales the distance between lines of wrapped text; 1.0 leaves pango's own spacing alone
+pub fn set_line_spacing(factor: f64) {
+    LINE_SPACING.store(factor.to_bits(), Ordering::Relaxed);
+}
+
+pub fn line_spacing() -> f64 {
+    f64::from_bits(LINE_SPACING.load(Ordering::Relaxed))
+}
+
+/// When set, every renderable is tinted with a color derived from its position and size
+pub fn set_debug_colors(enabled: bool) {
+    DEBUG_COLORS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn debug_colors() -> bool {
+    DEBUG_COLORS.load(Ordering::Relaxed)
+}
+
+/// 32-bit FNV-1a; stable across runs and platforms, unlike the std hashers
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for b in bytes {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+
+    hash
+}
+
+/// Picks a color from where something of size `bounds` is drawn, so the same layout gets the
+/// same colors on every run
+fn debug_color(cr: &cairo::Context, bounds: (f64, f64)) -> Color {
+    let (x, y) = cr.user_to_device(0.0, 0.0);
+    let key = [x, y, bounds.0, bounds.1];
+    let hash = fnv1a(key.iter().flat_map(|v| (v.round() as i64).to_le_bytes().to_vec()));
+
+    let level = |shift: u32| (1 + (hash >> shift) % 4) as f64 / 4.0;
+    Color { r: level(0), g: level(2), b: level(4) }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -72,24 +131,72 @@ impl From<RGBInt> for Color {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
+#[cfg(test)]
+thread_local! {
+    /// Makes prepare_layout fail, standing in for pango being unable to create a layout
+    static FAIL_LAYOUTS: std::cell::Cell<bool> = std::cell::Cell::new(false);
+}
+
 pub fn prepare_layout(
     context: &cairo::Context,
     font: &FontDescription,
     width: i32,
     text: &str,
+    markup: bool,
+    wrap: pango::WrapMode,
+    align: Align,
+    ellipsize_lines: Option<usize>,
 ) -> Result<Layout> {
+    #[cfg(test)]
+    {
+        if FAIL_LAYOUTS.with(|fail| fail.get()) {
+            anyhow::bail!("Failed to create pango layout (simulated)");
+        }
+    }
+
     let layout = pangocairo::create_layout(context)
         .ok_or_else(|| anyhow::anyhow!("Failed to create pango layout"))?;
 
     layout.set_font_description(Some(&font));
-    layout.set_text(text);
+    if markup {
+        layout.set_markup(text);
+    } else {
+        layout.set_text(text);
+    }
     layout.set_width(width.try_into()?);
-    layout.set_wrap(pango::WrapMode::Word);
+    layout.set_wrap(wrap);
+    layout.set_alignment(match align {
+        Align::Start => pango::Alignment::Left,
+        Align::Center => pango::Alignment::Center,
+        Align::End => pango::Alignment::Right,
+    });
+    let spacing = line_spacing();
+    if spacing != 1.0 {
+        // Layout::set_line_spacing needs pango 1.44, so add the extra space between lines
+        // ourselves. Line heights come from the font, so the first line's is representative.
+        if let Some(iter) = layout.get_iter() {
+            let (top, bottom) = iter.get_line_yrange();
+            layout.set_spacing(((spacing - 1.0) * (bottom - top) as f64).round() as i32);
+        }
+    }
+    if let Some(lines) = ellipsize_lines {
+        // A negative height is a limit in lines, rather than in pango units
+        layout.set_ellipsize(pango::EllipsizeMode::End);
+        layout.set_height(-(lines.min(i32::max_value() as usize) as i32));
+    }
 
     let (_w, _h) = layout.get_size();
     Ok(layout)
 }
 
+/// The pango wrap width, in layout units, for text drawn scaled by `font_scale` into `width`
+/// pixels. Rounding happens only once, in pango units, so the effective wrap width stays
+/// within a pango unit (rather than up to `font_scale` pixels) of the requested width.
+fn wrap_width_units(width: f64, font_scale: f64) -> i32 {
+    ((width / font_scale) * PANGO_SCALE).floor() as i32
+}
+
 pub fn layout_size_px(layout: &pango::Layout) -> (f64, f64) {
     let (w, h) = layout.get_size();
     (w as f64 / PANGO_SCALE, h as f64 / PANGO_SCALE)
@@ -122,6 +229,54 @@ impl<R: Renderable> Renderable for TestBorder<R> {
     }
 }
 
+/// A frame drawn over the edge of its child. The stroke is kept inside the child's bounds, so
+/// adding a border doesn't change the layout.
+pub struct Border<R> {
+    inner: R,
+    color: Color,
+    line_width: f64,
+    corner_radius: f64,
+}
+
+impl<R: Renderable> Border<R> {
+    pub fn new(inner: R, color: Color, line_width: f64) -> Self {
+        Self { inner, color, line_width, corner_radius: 0.0 }
+    }
+
+    pub fn with_radius(self, corner_radius: f64) -> Self {
+        Self { corner_radius, ..self }
+    }
+}
+
+impl<R: Renderable> Renderable for Border<R> {
+    fn render_internal(&self, cr: &mut cairo::Context) -> Result<()> {
+        let (w, h) = self.bounds();
+
+        cr.save();
+        self.inner.render(cr)?;
+        cr.restore();
+
+        // Strokes are centered on the path, so inset it by half the line width
+        let inset = self.line_width / 2.0;
+        let rect = Rectangle {
+            x: inset,
+            y: inset,
+            width: (w - self.line_width).max(0.0),
+            height: (h - self.line_width).max(0.0),
+        };
+        rounded_rect_path(cr, &rect, (self.corner_radius - inset).max(0.0));
+
+        cr.set_source_rgb(self.color.r, self.color.g, self.color.b);
+        cr.set_line_width(self.line_width);
+        cr.stroke();
+
+        Ok(())
+    }
+    fn bounds(&self) -> (f64, f64) {
+        self.inner.bounds()
+    }
+}
+
 #[derive(Clone)]
 pub struct RcRenderable(pub Rc<dyn Renderable>);
 
@@ -147,6 +302,17 @@ pub trait Renderable {
         let result = self.render_internal(cr);
         cr.restore();
 
+        if debug_colors() {
+            let (w, h) = self.bounds();
+            let color = debug_color(cr, (w, h));
+            cr.save();
+            cr.new_path();
+            cr.set_source_rgba(color.r, color.g, color.b, 0.25);
+            cr.rectangle(0.0, 0.0, w, h);
+            cr.fill();
+            cr.restore();
+        }
+
         result
     }
 
@@ -185,7 +351,6 @@ impl Renderable for cairo::ImageSurface {
         cr.move_to(0.0, 0.0);
         //cr.set_operator(cairo::Operator::Source);
         cr.set_source_surface(self, 0.0, 0.0);
-        //debug_color(cr);
         cr.new_path();
         cr.rectangle(0.0, 0.0, self.get_width() as f64, self.get_height() as f64);
         cr.fill();
@@ -198,6 +363,54 @@ impl Renderable for cairo::ImageSurface {
     }
 }
 
+/// A QR code, drawn as solid modules scaled to `size` pixels square
+#[cfg(feature = "qr")]
+pub struct QrCode {
+    modules: Vec<bool>,
+    modules_w: usize,
+    size: f64,
+    color: Color,
+}
+
+#[cfg(feature = "qr")]
+impl QrCode {
+    pub fn new(data: &str, size: f64, color: Color) -> Result<Self> {
+        let code = qrcode::QrCode::new(data.as_bytes())
+            .map_err(|e| anyhow::anyhow!("Failed to encode QR code: {}", e))?;
+
+        Ok(QrCode {
+            modules: code.to_colors().into_iter().map(|c| c == qrcode::Color::Dark).collect(),
+            modules_w: code.width(),
+            size,
+            color,
+        })
+    }
+}
+
+#[cfg(feature = "qr")]
+impl Renderable for QrCode {
+    fn render_internal(&self, cr: &mut cairo::Context) -> Result<()> {
+        let module_size = self.size / self.modules_w as f64;
+
+        cr.set_source_rgb(self.color.r, self.color.g, self.color.b);
+        cr.new_path();
+        for (i, dark) in self.modules.iter().copied().enumerate() {
+            if dark {
+                let x = (i % self.modules_w) as f64 * module_size;
+                let y = (i / self.modules_w) as f64 * module_size;
+                cr.rectangle(x, y, module_size, module_size);
+            }
+        }
+        cr.fill();
+
+        Ok(())
+    }
+
+    fn bounds(&self) -> (f64, f64) {
+        (self.size, self.size)
+    }
+}
+
 pub struct RenderTranslate {
     pub inner: Box<dyn Renderable>,
     pub offset: (f64, f64),
@@ -221,6 +434,18 @@ pub trait RenderableEx: Renderable {
         Clip {
             inner: self,
             clip_bounds,
+            corner_radius: 0.0,
+        }
+    }
+
+    fn clip_to_rounded(self, clip_bounds: Rectangle, corner_radius: f64) -> Clip<Self>
+    where
+        Self: Sized,
+    {
+        Clip {
+            inner: self,
+            clip_bounds,
+            corner_radius,
         }
     }
 
@@ -231,6 +456,20 @@ pub trait RenderableEx: Renderable {
         Scale::scale_by(self, w, h)
     }
 
+    fn fixed_size(self, w: f64, h: f64, align_x: Align, align_y: Align) -> FixedSize<Self>
+    where
+        Self: Sized,
+    {
+        FixedSize::new(self, w, h, align_x, align_y)
+    }
+
+    fn tile(self, direction: TileDirection, w: f64, h: f64) -> Tile<Self>
+    where
+        Self: Sized,
+    {
+        Tile::new(self, direction, w, h)
+    }
+
     fn offset(self, x: f64, y: f64) -> RenderTranslate
     where
         Self: Sized + 'static,
@@ -284,6 +523,14 @@ pub trait RenderableEx: Renderable {
         .into_rc()
     }
 
+    /// Strokes a frame just inside this item's bounds
+    fn border(self, color: Color, line_width: f64) -> Border<Self>
+    where
+        Self: Sized,
+    {
+        Border::new(self, color, line_width)
+    }
+
     fn with_operator(self, operator: cairo::Operator) -> RcRenderable
     where
         Self: Sized + 'static,
@@ -372,9 +619,10 @@ impl Renderable for RenderGroup {
 
 pub struct TextBox {
     text: String,
-    original_width: i32,
     color: Color,
-    font: FontDescription,
+    /// The layout measured when the box was created, drawn again as-is. None when pango
+    /// couldn't lay out the text; a "?" box is drawn instead.
+    layout: Option<Layout>,
     width: f64,
     height: f64,
 
@@ -399,19 +647,104 @@ impl TextBox {
         color: Color,
         font: &FontDescription,
         max_lines: usize,
+        markup: bool,
     ) -> Result<TextBox> {
-        let width = (width / FONT_SCALE).floor();
-        let width = (width * PANGO_SCALE) as i32;
-        let layout = prepare_layout(context, font, width, &text)?;
+        Self::layout(context, text, width, color, font, max_lines, markup, Align::Start, false)
+    }
+
+    /// Like `new`, but text running past `max_lines` ends in an ellipsis instead of being cut
+    /// off by the box's clip
+    pub fn ellipsized(
+        context: &cairo::Context,
+        text: String,
+        width: f64,
+        color: Color,
+        font: &FontDescription,
+        max_lines: usize,
+        markup: bool,
+    ) -> Result<TextBox> {
+        Self::layout(context, text, width, color, font, max_lines, markup, Align::Start, true)
+    }
+
+    /// Like `new`, but with lines aligned within `width`. Unless left-aligned, the box is then
+    /// always the full `width` wide, rather than only as wide as the text.
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_align(
+        context: &cairo::Context,
+        text: String,
+        width: f64,
+        color: Color,
+        font: &FontDescription,
+        max_lines: usize,
+        markup: bool,
+        align: Align,
+    ) -> Result<TextBox> {
+        Self::layout(context, text, width, color, font, max_lines, markup, align, false)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn layout(
+        context: &cairo::Context,
+        text: String,
+        width: f64,
+        color: Color,
+        font: &FontDescription,
+        max_lines: usize,
+        markup: bool,
+        align: Align,
+        ellipsize: bool,
+    ) -> Result<TextBox> {
+        let box_width = width;
+        let ellipsize_lines = if ellipsize { Some(max_lines) } else { None };
+
+        let width = wrap_width_units(width, FONT_SCALE);
+
+        // Explicit line breaks count toward max_lines just like wrapped lines. Normalize them so
+        // pango sees one break per newline, and drop trailing ones which would add a blank line.
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        let text = text.trim_end_matches('\n').to_string();
+
+        // Malformed markup would otherwise make pango render nothing at all; fall back to
+        // showing the raw string instead.
+        let markup = markup && match pango::parse_markup(&text, '\0') {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Invalid markup in {:?}, rendering as plain text: {}", text, e);
+                false
+            }
+        };
+
+        let mut wrap = pango::WrapMode::Word;
+        let layout = match prepare_layout(context, font, width, &text, markup, wrap, align, ellipsize_lines) {
+            Ok(layout) => layout,
+            Err(e) => {
+                warn!("Failed to lay out {:?}, drawing a placeholder instead: {}", text, e);
+                return Ok(TextBox::placeholder(text, color));
+            }
+        };
+
+        // Word wrapping can't break a single long token, which then runs past the wrap width
+        if layout.get_size().0 > width {
+            if wrap_overflow() {
+                warn!("Text {:?} is too wide to word-wrap; breaking it between characters", text);
+                wrap = pango::WrapMode::WordChar;
+                layout.set_wrap(wrap);
+            } else {
+                warn!("Text {:?} is too wide to word-wrap and will be clipped", text);
+            }
+        }
         let (w, h) = layout_size_px(&layout);
 
         let mut rv = TextBox {
             text: text.clone(),
-            original_width: width,
             color,
-            font: font.clone(),
-            width: w,
-            height: h,
+            layout: Some(layout.clone()),
+            // Scaled into pixels; height is recomputed below to respect max_lines
+            width: match align {
+                Align::Start => (w * FONT_SCALE).ceil(),
+                _ => (w * FONT_SCALE).ceil().max(box_width.floor()),
+            },
+            height: (h * FONT_SCALE).ceil(),
             min_baseline: 0.0,
         };
 
@@ -421,6 +754,9 @@ impl TextBox {
             return Ok(rv);
         }
 
+        // Cluster indices refer to the text after markup has been stripped
+        let text = layout.get_text().map(|s| s.to_string()).unwrap_or_default();
+
         let mut iter = iter.unwrap();
         let mut index = 0;
         loop {
@@ -459,21 +795,50 @@ impl TextBox {
         let mut iter = layout.get_iter().unwrap();
 
         let top = iter.get_line_yrange().0;
-        for _ in 0..(max_lines - 1) {
-            iter.next_line();
+        for _ in 1..max_lines {
+            if !iter.next_line() {
+                break;
+            }
         }
         let bottom = iter.get_line_yrange().1;
 
-        rv.width = (rv.width * FONT_SCALE).ceil();
         rv.height = ((((bottom - top) as f64) / PANGO_SCALE) * FONT_SCALE).ceil();
         rv.min_baseline = ((iter.get_baseline() as f64 / PANGO_SCALE) * FONT_SCALE).ceil();
 
         Ok(rv)
     }
 
+    /// A fixed-size box with a "?" in it, standing in for text that couldn't be laid out
+    fn placeholder(text: String, color: Color) -> TextBox {
+        TextBox {
+            text,
+            color,
+            layout: None,
+            width: PLACEHOLDER_SIZE,
+            height: PLACEHOLDER_SIZE,
+            min_baseline: PLACEHOLDER_SIZE,
+        }
+    }
+
     pub fn min_baseline(&self) -> f64 {
         self.min_baseline
     }
+
+    fn render_placeholder(&self, cr: &mut cairo::Context) {
+        // Pango is what failed, so stick to cairo's toy text API here
+        cr.set_line_width(1.0);
+        cr.rectangle(0.5, 0.5, self.width - 1.0, self.height - 1.0);
+        cr.stroke();
+
+        cr.select_font_face("sans-serif", cairo::FontSlant::Normal, cairo::FontWeight::Bold);
+        cr.set_font_size(self.width.min(self.height) * 0.75);
+        let extents = cr.text_extents("?");
+        cr.move_to(
+            (self.width - extents.width) / 2.0 - extents.x_bearing,
+            (self.height - extents.height) / 2.0 - extents.y_bearing,
+        );
+        cr.show_text("?");
+    }
 }
 
 fn dump_histo<T: Clone + std::fmt::Debug>(h: &HashMap<T, u32>, cutoff: usize) {
@@ -500,6 +865,16 @@ fn dump_histo<T: Clone + std::fmt::Debug>(h: &HashMap<T, u32>, cutoff: usize) {
     }
 }
 
+/// Returns the number of times each text cluster was laid out, clearing the histogram
+pub fn take_text_histogram() -> HashMap<String, u32> {
+    TEXT_HISTOGRAM.with(|h| std::mem::take(&mut *h.borrow_mut()))
+}
+
+/// Returns the number of clusters laid out at each pixel width, clearing the histogram
+pub fn take_width_histogram() -> HashMap<u32, u32> {
+    WIDTH_HISTOGRAM.with(|h| std::mem::take(&mut *h.borrow_mut()))
+}
+
 pub fn dump_text_histograms() {
     eprintln!("=== Text histogram ===");
     TEXT_HISTOGRAM.with(|h| dump_histo(&*h.borrow(), 10));
@@ -513,13 +888,22 @@ impl Renderable for TextBox {
         cr.new_path();
         cr.rectangle(0.0, 0.0, self.width, self.height);
         cr.clip();
-        cr.scale(FONT_SCALE, FONT_SCALE);
-
         cr.new_path();
 
         cr.set_source_rgb(self.color.r, self.color.g, self.color.b);
-        let layout = prepare_layout(cr, &self.font, self.original_width, &self.text)?;
-        pangocairo::show_layout(cr, &layout);
+        let layout = match &self.layout {
+            Some(layout) => layout,
+            None => {
+                self.render_placeholder(cr);
+                return Ok(());
+            }
+        };
+
+        // The layout was measured against another context. Bring it up to date with this one
+        // (before scaling, as when it was created); its line breaks don't change.
+        pangocairo::update_layout(cr, layout);
+        cr.scale(FONT_SCALE, FONT_SCALE);
+        pangocairo::show_layout(cr, layout);
 
         Ok(())
     }
@@ -553,7 +937,6 @@ impl Renderable for FillRect {
     fn render_internal(&self, cr: &mut cairo::Context) -> Result<()> {
         cr.move_to(0.0, 0.0);
         cr.set_source_rgb(self.color.r, self.color.g, self.color.b);
-        //debug_color(cr);
         cr.new_path();
         cr.rectangle(
             self.bounds.x,
@@ -573,18 +956,65 @@ impl Renderable for FillRect {
     }
 }
 
+/// A filled rectangle with rounded corners, for backgrounds and badges
+#[derive(Clone, Copy, Debug)]
+pub struct RoundedRect {
+    pub width: f64,
+    pub height: f64,
+    /// Top left, top right, bottom right, bottom left; each is limited to half the shorter side
+    pub radii: [f64; 4],
+    pub color: Color,
+}
+
+impl RoundedRect {
+    pub fn new(color: Color, w: f64, h: f64, radius: f64) -> Self {
+        Self::with_corners(color, w, h, [radius; 4])
+    }
+
+    pub fn with_corners(color: Color, w: f64, h: f64, radii: [f64; 4]) -> Self {
+        Self { width: w, height: h, radii, color }
+    }
+}
+
+impl Renderable for RoundedRect {
+    fn render_internal(&self, cr: &mut cairo::Context) -> Result<()> {
+        cr.set_source_rgb(self.color.r, self.color.g, self.color.b);
+        rounded_rect_corners_path(
+            cr,
+            &Rectangle { x: 0.0, y: 0.0, width: self.width, height: self.height },
+            self.radii,
+        );
+        cr.fill();
+
+        Ok(())
+    }
+
+    fn bounds(&self) -> (f64, f64) {
+        (self.width, self.height)
+    }
+}
+
 pub struct RenderColumn {
-    items: Vec<Box<dyn Renderable>>,
+    /// Items, already offset vertically, along with their widths
+    items: Vec<(Box<dyn Renderable>, f64)>,
     height: f64,
     width: f64,
+    /// Horizontal placement of each item relative to the widest one; applied at render time,
+    /// since a later item may widen the column.
+    align: Align,
 }
 
 impl RenderColumn {
     pub fn new() -> Self {
+        Self::with_align(Align::Start)
+    }
+
+    pub fn with_align(align: Align) -> Self {
         Self {
             items: vec![],
             height: 0.0,
             width: 0.0,
+            align,
         }
     }
 
@@ -599,7 +1029,7 @@ impl RenderColumn {
             self.width = width;
         }
 
-        self.items.push(Box::new(item));
+        self.items.push((Box::new(item), width));
 
         offset
     }
@@ -607,8 +1037,63 @@ impl RenderColumn {
 
 impl Renderable for RenderColumn {
     fn render_internal(&self, cr: &mut cairo::Context) -> Result<()> {
-        for item in self.items.iter() {
-            item.render(cr)?;
+        for (item, width) in self.items.iter() {
+            item.render_to(cr, (self.align.place(self.width, *width), 0.0))?;
+        }
+
+        Ok(())
+    }
+    fn bounds(&self) -> (f64, f64) {
+        (self.width, self.height)
+    }
+}
+
+/// Lays items out left to right, as RenderColumn does top to bottom
+pub struct RenderRow {
+    /// Items, already offset horizontally, along with their heights
+    items: Vec<(Box<dyn Renderable>, f64)>,
+    height: f64,
+    width: f64,
+    /// Vertical placement of each item relative to the tallest one; applied at render time,
+    /// since a later item may heighten the row.
+    align: Align,
+}
+
+impl RenderRow {
+    pub fn new() -> Self {
+        Self::with_align(Align::Start)
+    }
+
+    pub fn with_align(align: Align) -> Self {
+        Self {
+            items: vec![],
+            height: 0.0,
+            width: 0.0,
+            align,
+        }
+    }
+
+    pub fn push(&mut self, item: impl Renderable + 'static) -> f64 {
+        let offset = self.width;
+
+        let item = item.offset(offset, 0.0);
+        let (width, height) = item.bounds();
+
+        self.width = width;
+        if height > self.height {
+            self.height = height;
+        }
+
+        self.items.push((Box::new(item), height));
+
+        offset
+    }
+}
+
+impl Renderable for RenderRow {
+    fn render_internal(&self, cr: &mut cairo::Context) -> Result<()> {
+        for (item, height) in self.items.iter() {
+            item.render_to(cr, (0.0, self.align.place(self.height, *height)))?;
         }
 
         Ok(())
@@ -701,10 +1186,79 @@ impl<R: Renderable> Renderable for SwapXY<R> {
     }
 }
 
+/// Rotates its child by an angle in radians (clockwise, as y points down) about the child's
+/// center. The bounds are those of the rotated child's bounding box, which is placed at the
+/// origin.
+pub struct Rotate<R> {
+    inner: R,
+    angle: f64,
+}
+
+impl<R: Renderable> Rotate<R> {
+    pub fn new(inner: R, angle: f64) -> Self {
+        Self { inner, angle }
+    }
+}
+
+impl<R: Renderable> Renderable for Rotate<R> {
+    fn render_internal(&self, cr: &mut cairo::Context) -> Result<()> {
+        let (w, h) = self.inner.bounds();
+        let (outer_w, outer_h) = self.bounds();
+
+        cr.save();
+
+        cr.translate(outer_w / 2.0, outer_h / 2.0);
+        cr.rotate(self.angle);
+        cr.translate(-w / 2.0, -h / 2.0);
+        let r = self.inner.render(cr);
+
+        cr.restore();
+
+        r
+    }
+
+    fn bounds(&self) -> (f64, f64) {
+        let (w, h) = self.inner.bounds();
+        let (sin, cos) = self.angle.sin_cos();
+        (w * cos.abs() + h * sin.abs(), w * sin.abs() + h * cos.abs())
+    }
+}
+
+/// Builds a rectangular path with rounded corners. The radius is limited to half the
+/// shorter side.
+pub fn rounded_rect_path(cr: &cairo::Context, rect: &Rectangle, radius: f64) {
+    rounded_rect_corners_path(cr, rect, [radius; 4]);
+}
+
+/// Like `rounded_rect_path`, with a radius for each corner: top left, top right, bottom right,
+/// then bottom left
+pub fn rounded_rect_corners_path(cr: &cairo::Context, rect: &Rectangle, radii: [f64; 4]) {
+    use std::f64::consts::{FRAC_PI_2, PI};
+
+    let limit = |radius: f64| f64::max(0.0, radius.min(rect.width / 2.0).min(rect.height / 2.0));
+    let [top_left, top_right, bottom_right, bottom_left] = radii;
+    let x0 = rect.x;
+    let y0 = rect.y;
+    let x1 = rect.x + rect.width;
+    let y1 = rect.y + rect.height;
+
+    cr.new_path();
+    let r = limit(top_right);
+    cr.arc(x1 - r, y0 + r, r, -FRAC_PI_2, 0.0);
+    let r = limit(bottom_right);
+    cr.arc(x1 - r, y1 - r, r, 0.0, FRAC_PI_2);
+    let r = limit(bottom_left);
+    cr.arc(x0 + r, y1 - r, r, FRAC_PI_2, PI);
+    let r = limit(top_left);
+    cr.arc(x0 + r, y0 + r, r, PI, PI + FRAC_PI_2);
+    cr.close_path();
+}
+
 #[derive(Clone)]
 pub struct Clip<R> {
     inner: R,
     clip_bounds: Rectangle,
+    corner_radius: f64,
 }
 
 impl<R: Renderable> Renderable for Clip<R> {
@@ -712,13 +1266,17 @@ impl<R: Renderable> Renderable for Clip<R> {
         cr.save();
 
         cr.translate(-self.clip_bounds.x, -self.clip_bounds.y);
-        cr.new_path();
-        cr.rectangle(
-            self.clip_bounds.x,
-            self.clip_bounds.y,
-            self.clip_bounds.width,
-            self.clip_bounds.height,
-        );
+        if self.corner_radius > 0.0 {
+            rounded_rect_path(cr, &self.clip_bounds, self.corner_radius);
+        } else {
+            cr.new_path();
+            cr.rectangle(
+                self.clip_bounds.x,
+                self.clip_bounds.y,
+                self.clip_bounds.width,
+                self.clip_bounds.height,
+            );
+        }
         cr.clip();
         cr.new_path();
         let result = self.inner.render(cr);
@@ -732,6 +1290,62 @@ impl<R: Renderable> Renderable for Clip<R> {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Align {
+    Start,
+    Center,
+    End,
+}
+
+impl Align {
+    fn place(self, outer: f64, inner: f64) -> f64 {
+        match self {
+            Align::Start => 0.0,
+            Align::Center => (outer - inner) / 2.0,
+            Align::End => outer - inner,
+        }
+    }
+}
+
+/// Forces a child into a box of a fixed size. Children smaller than the box are positioned
+/// according to the alignment; larger children are clipped to the box.
+#[derive(Clone)]
+pub struct FixedSize<R> {
+    inner: Clip<R>,
+}
+
+impl<R: Renderable> FixedSize<R> {
+    pub fn new(inner: R, width: f64, height: f64, align_x: Align, align_y: Align) -> Self {
+        let (iw, ih) = inner.bounds();
+        let x = align_x.place(width, iw);
+        let y = align_y.place(height, ih);
+
+        // Clip translates by the negated clip origin, so this places the child at (x, y)
+        // and clips to (0, 0, width, height).
+        Self {
+            inner: Clip {
+                inner,
+                clip_bounds: Rectangle {
+                    x: -x,
+                    y: -y,
+                    width,
+                    height,
+                },
+                corner_radius: 0.0,
+            },
+        }
+    }
+}
+
+impl<R: Renderable> Renderable for FixedSize<R> {
+    fn render_internal(&self, cr: &mut cairo::Context) -> Result<()> {
+        self.inner.render_internal(cr)
+    }
+    fn bounds(&self) -> (f64, f64) {
+        self.inner.bounds()
+    }
+}
+
 impl<R: Renderable> Scale<R> {
     fn scale_by(inner: R, w: f64, h: f64) -> Self {
         Self {
@@ -741,6 +1355,65 @@ impl<R: Renderable> Scale<R> {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TileDirection {
+    Horizontal,
+    Vertical,
+}
+
+/// Repeats the inner renderable along one axis to fill the given bounds
+#[derive(Clone)]
+pub struct Tile<R> {
+    inner: R,
+    direction: TileDirection,
+    bounds: (f64, f64),
+}
+
+impl<R: Renderable> Tile<R> {
+    pub fn new(inner: R, direction: TileDirection, w: f64, h: f64) -> Self {
+        Self {
+            inner,
+            direction,
+            bounds: (w, h),
+        }
+    }
+}
+
+impl<R: Renderable> Renderable for Tile<R> {
+    fn render_internal(&self, cr: &mut cairo::Context) -> Result<()> {
+        let (iw, ih) = self.inner.bounds();
+        let (step, extent) = match self.direction {
+            TileDirection::Horizontal => (iw, self.bounds.0),
+            TileDirection::Vertical => (ih, self.bounds.1),
+        };
+
+        if step <= 0.0 {
+            return Ok(());
+        }
+
+        cr.new_path();
+        cr.rectangle(0.0, 0.0, self.bounds.0, self.bounds.1);
+        cr.clip();
+        cr.new_path();
+
+        let mut pos = 0.0;
+        while pos < extent {
+            let origin = match self.direction {
+                TileDirection::Horizontal => (pos, 0.0),
+                TileDirection::Vertical => (0.0, pos),
+            };
+            self.inner.render_to(cr, origin)?;
+            pos += step;
+        }
+
+        Ok(())
+    }
+
+    fn bounds(&self) -> (f64, f64) {
+        self.bounds
+    }
+}
+
 pub struct Margin<R: Renderable> {
     inner: R,
     bounds: (f64, f64),
@@ -764,18 +1437,31 @@ pub struct Separator {
     pub color: Color,
     pub width: f64,
     pub thickness: f64,
-    pub dash: f64,
+    /// Alternating on/off segment lengths; empty for a solid line
+    pub dash: Vec<f64>,
+    pub dash_offset: f64,
+    pub cap: cairo::LineCap,
     pub margin: f64,
+    /// Width of a gap left in the middle of the line (e.g. for a label); zero for none
+    pub center_gap: f64,
 }
 
 impl Renderable for Separator {
     fn render_internal(&self, cr: &mut cairo::Context) -> Result<()> {
         cr.new_path();
         cr.set_source_rgb(self.color.r, self.color.g, self.color.b);
-        cr.move_to(0.0, self.margin);
-        cr.set_dash(&[self.dash], self.dash * 1.5);
+        cr.set_dash(&self.dash, self.dash_offset);
         cr.set_line_width(self.thickness);
-        cr.set_line_cap(cairo::LineCap::Round);
+        cr.set_line_cap(self.cap);
+
+        if self.center_gap > 0.0 {
+            let gap_start = (self.width - self.center_gap) / 2.0;
+            cr.move_to(0.0, self.margin);
+            cr.line_to(gap_start, self.margin);
+            cr.move_to(gap_start + self.center_gap, self.margin);
+        } else {
+            cr.move_to(0.0, self.margin);
+        }
         cr.line_to(self.width, self.margin);
         cr.stroke();
 
@@ -853,4 +1539,458 @@ pub fn pad_sides(r: RcRenderable, pad_left: f64, pad_right: f64) -> RcRenderable
     }
 
     render_group.into_rc()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_histogram_matches_rendered_characters() {
+        let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 16, 16).unwrap();
+        let context = cairo::Context::new(&surface);
+        let font = FontDescription::from_string("M+ 1m regular 12");
+        let text = "abcab 予定";
+
+        take_text_histogram();
+        TextBox::new(&context, text.into(), 1000.0, Color { r: 1.0, g: 1.0, b: 1.0 }, &font, 1, false).unwrap();
+        let histogram = take_text_histogram();
+
+        let mut rendered: Vec<char> = histogram
+            .iter()
+            .flat_map(|(cluster, &count)| std::iter::repeat(cluster.chars()).take(count as usize).flatten())
+            .collect();
+        let mut expected: Vec<char> = text.chars().collect();
+        rendered.sort();
+        expected.sort();
+        assert_eq!(rendered, expected);
+
+        assert!(take_text_histogram().is_empty(), "histogram was not cleared");
+    }
+
+    fn assert_bounds(actual: (f64, f64), expected: (f64, f64)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-9 && (actual.1 - expected.1).abs() < 1e-9,
+            "bounds {:?}, expected {:?}",
+            actual,
+            expected
+        );
+    }
+
+    /// Lays out `text` as markup, returning the text pango was given and the clusters laid out
+    fn markup_text(text: &str) -> (String, Vec<String>) {
+        let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 16, 16).unwrap();
+        let context = cairo::Context::new(&surface);
+        let font = FontDescription::from_string("M+ 1m regular 12");
+
+        take_text_histogram();
+        let text_box = TextBox::new(&context, text.into(), 1000.0, Color { r: 1.0, g: 1.0, b: 1.0 }, &font, 1, true).unwrap();
+        let clusters = take_text_histogram().into_iter().map(|(cluster, _)| cluster).collect();
+
+        let layout = text_box.layout.expect("text was not laid out");
+        (layout.get_text().map(|s| s.to_string()).unwrap_or_default(), clusters)
+    }
+
+    #[test]
+    fn markup_is_applied() {
+        let (text, clusters) = markup_text("<b>bold</b> plain");
+
+        assert_eq!(text, "bold plain");
+        assert!(clusters.iter().all(|c| !c.contains('<') && !c.contains('>')), "{:?}", clusters);
+    }
+
+    #[test]
+    fn malformed_markup_falls_back_to_plain_text() {
+        let (text, clusters) = markup_text("<b>unclosed & broken");
+
+        assert_eq!(text, "<b>unclosed & broken");
+        assert!(clusters.iter().any(|c| c == "<"), "{:?}", clusters);
+    }
+
+    fn plain_text(text: &str, max_lines: usize) -> TextBox {
+        let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 16, 16).unwrap();
+        let context = cairo::Context::new(&surface);
+        let font = FontDescription::from_string("M+ 1m regular 12");
+
+        TextBox::new(&context, text.into(), 1000.0, Color { r: 1.0, g: 1.0, b: 1.0 }, &font, max_lines, false).unwrap()
+    }
+
+    fn aligned_text(text: &str, align: Align) -> TextBox {
+        let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 16, 16).unwrap();
+        let context = cairo::Context::new(&surface);
+        let font = FontDescription::from_string("M+ 1m regular 12");
+
+        TextBox::with_align(&context, text.into(), 200.0, Color { r: 1.0, g: 1.0, b: 1.0 }, &font, 1, false, align)
+            .unwrap()
+    }
+
+    /// The first and last columns with any ink in them
+    fn ink_columns(pixels: &[u32], w: usize) -> (usize, usize) {
+        let inked: Vec<usize> = (0..w).filter(|&x| pixels.iter().skip(x).step_by(w).any(|&px| px != 0)).collect();
+        (*inked.first().expect("nothing was drawn"), *inked.last().unwrap())
+    }
+
+    #[test]
+    fn aligned_text_is_placed_within_width() {
+        let left = aligned_text("abc", Align::Start);
+        let center = aligned_text("abc", Align::Center);
+        let right = aligned_text("abc", Align::End);
+
+        assert_eq!(left.layout.as_ref().unwrap().get_alignment(), pango::Alignment::Left);
+        assert_eq!(center.layout.as_ref().unwrap().get_alignment(), pango::Alignment::Center);
+        assert_eq!(right.layout.as_ref().unwrap().get_alignment(), pango::Alignment::Right);
+
+        // Left-aligned boxes shrink to the text; the others span the whole width
+        let (text_width, height) = left.bounds();
+        assert!(text_width < 100.0, "{}", text_width);
+        assert_bounds(center.bounds(), (200.0, height));
+        assert_bounds(right.bounds(), (200.0, height));
+
+        let h = height as i32;
+        let (left_start, left_end) = ink_columns(&render_pixels(&left, 200, h), 200);
+        let (center_start, center_end) = ink_columns(&render_pixels(&center, 200, h), 200);
+        let (right_start, right_end) = ink_columns(&render_pixels(&right, 200, h), 200);
+
+        assert!(left_start < 5, "{}", left_start);
+        assert!(right_end > 194, "{}", right_end);
+        let margins = (center_start as i32, 199 - center_end as i32);
+        assert!((margins.0 - margins.1).abs() <= 2, "{:?}", margins);
+        // The ink is the same width wherever it's placed, give or take antialiasing
+        let ink_width = (left_end - left_start) as i32;
+        assert!(((center_end - center_start) as i32 - ink_width).abs() <= 1);
+        assert!(((right_end - right_start) as i32 - ink_width).abs() <= 1);
+    }
+
+    #[test]
+    fn long_text_is_ellipsized_within_max_lines() {
+        let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 16, 16).unwrap();
+        let context = cairo::Context::new(&surface);
+        let font = FontDescription::from_string("M+ 1m regular 12");
+        let white = Color { r: 1.0, g: 1.0, b: 1.0 };
+        let long = "word ".repeat(200);
+
+        let two_lines = TextBox::new(&context, "first\nsecond".into(), 150.0, white, &font, 2, false).unwrap();
+        let ellipsized = TextBox::ellipsized(&context, long.clone(), 150.0, white, &font, 2, false).unwrap();
+        let layout = ellipsized.layout.as_ref().unwrap();
+        assert!(layout.is_ellipsized());
+        assert_eq!(layout.get_line_count(), 2);
+        assert_eq!(ellipsized.height, two_lines.height);
+        assert!(ellipsized.width <= 150.0, "{}", ellipsized.width);
+
+        // Without ellipsizing the text is laid out in full, and only clipped to max_lines
+        let clipped = TextBox::new(&context, long, 150.0, white, &font, 2, false).unwrap();
+        let layout = clipped.layout.as_ref().unwrap();
+        assert!(!layout.is_ellipsized());
+        assert!(layout.get_line_count() > 2);
+        assert_eq!(clipped.height, two_lines.height);
+    }
+
+    #[test]
+    fn wrap_width_matches_requested_pixels() {
+        for font_scale in [1.0, 2.0].iter().copied() {
+            for width in [1.0, 99.5, 111.0, 333.3, 788.0].iter().copied() {
+                let units = wrap_width_units(width, font_scale);
+                // Measured widths are scaled back the same way TextBox does it
+                let effective = units as f64 / PANGO_SCALE * font_scale;
+
+                assert!(effective <= width, "{} wraps at {} at scale {}", width, effective, font_scale);
+                // Within one pango unit, well inside a pixel
+                assert!(width - effective <= font_scale / PANGO_SCALE, "{} wraps at {} at scale {}", width, effective, font_scale);
+            }
+        }
+    }
+
+    #[test]
+    fn failed_layout_draws_placeholder() {
+        FAIL_LAYOUTS.with(|fail| fail.set(true));
+        let text = plain_text("壊れたイベント", 2);
+        FAIL_LAYOUTS.with(|fail| fail.set(false));
+
+        assert!(text.layout.is_none());
+        assert_bounds(text.bounds(), (PLACEHOLDER_SIZE, PLACEHOLDER_SIZE));
+
+        // The "?" box is drawn in the text color
+        let size = PLACEHOLDER_SIZE as i32;
+        let pixels = render_pixels(&text, size, size);
+        assert!(pixels.iter().any(|&px| px != 0));
+    }
+
+    #[test]
+    fn explicit_newline_is_a_line_break() {
+        let one_line = plain_text("first", 2);
+        let two_lines = plain_text("first\nsecond", 2);
+
+        let layout = two_lines.layout.as_ref().expect("text was not laid out");
+        assert_eq!(layout.get_line_count(), 2);
+        assert_eq!(layout.get_text().map(|s| s.to_string()).unwrap_or_default(), "first\nsecond");
+        assert!(two_lines.height > one_line.height * 1.5, "{} vs {}", two_lines.height, one_line.height);
+
+        // A third line is past max_lines and adds no height; CRLF counts once, like LF
+        assert_eq!(plain_text("first\nsecond\nthird", 2).height, two_lines.height);
+        assert_eq!(plain_text("first\r\nsecond", 2).height, two_lines.height);
+    }
+
+    const RED: Color = Color { r: 1.0, g: 0.0, b: 0.0 };
+
+    /// Renders `item` onto a black w x h surface, returning each pixel as 0xRRGGBB, row by row
+    fn render_pixels(item: &impl Renderable, w: i32, h: i32) -> Vec<u32> {
+        let mut surface = cairo::ImageSurface::create(cairo::Format::Rgb24, w, h).unwrap();
+        let mut context = cairo::Context::new(&surface);
+        item.render(&mut context).unwrap();
+        std::mem::drop(context);
+        surface.flush();
+
+        let stride = surface.get_stride() as usize;
+        let data = surface.get_data().unwrap();
+        (0..h as usize)
+            .flat_map(|y| {
+                let row = &data[y * stride..y * stride + 4 * w as usize];
+                row.chunks_exact(4).map(|px| u32::from_ne_bytes([px[0], px[1], px[2], px[3]]) & 0x00FF_FFFF)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fixed_size_centers_smaller_child() {
+        let boxed = FillRect::rect(RED, 4.0, 2.0).fixed_size(10.0, 6.0, Align::Center, Align::Center);
+        assert_bounds(boxed.bounds(), (10.0, 6.0));
+
+        let pixels = render_pixels(&boxed, 10, 6);
+        for y in 0..6 {
+            for x in 0..10 {
+                let inside = (3..7).contains(&x) && (2..4).contains(&y);
+                assert_eq!(pixels[y * 10 + x], if inside { 0xFF0000 } else { 0 }, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_size_clips_larger_child() {
+        let boxed = FillRect::rect(RED, 20.0, 20.0).fixed_size(10.0, 6.0, Align::Start, Align::Start);
+        assert_bounds(boxed.bounds(), (10.0, 6.0));
+
+        let pixels = render_pixels(&boxed, 16, 16);
+        for y in 0..16 {
+            for x in 0..16 {
+                let inside = x < 10 && y < 6;
+                assert_eq!(pixels[y * 16 + x], if inside { 0xFF0000 } else { 0 }, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn rounded_clip_cuts_corners() {
+        let clip = Rectangle { x: 0.0, y: 0.0, width: 20.0, height: 20.0 };
+        let clipped = FillRect::rect(RED, 30.0, 30.0).clip_to_rounded(clip, 8.0);
+        assert_bounds(clipped.bounds(), (20.0, 20.0));
+
+        let pixels = render_pixels(&clipped, 24, 24);
+        let px = |x: usize, y: usize| pixels[y * 24 + x];
+        for &(x, y) in &[(0, 0), (19, 0), (0, 19), (19, 19)] {
+            assert_eq!(px(x, y), 0, "corner ({}, {}) was drawn", x, y);
+        }
+        assert_eq!(px(10, 10), 0xFF0000);
+        assert_eq!(px(10, 0), 0xFF0000);
+        assert_eq!(px(0, 10), 0xFF0000);
+        assert_eq!(px(21, 10), 0, "drawn outside the clip");
+    }
+
+    /// Renders `item` onto a transparent w x h surface, returning each pixel's alpha, row by row
+    fn render_alpha(item: &impl Renderable, w: i32, h: i32) -> Vec<u8> {
+        let mut surface = cairo::ImageSurface::create(cairo::Format::A8, w, h).unwrap();
+        let mut context = cairo::Context::new(&surface);
+        item.render(&mut context).unwrap();
+        std::mem::drop(context);
+        surface.flush();
+
+        let stride = surface.get_stride() as usize;
+        let data = surface.get_data().unwrap();
+        (0..h as usize).flat_map(|y| data[y * stride..y * stride + w as usize].to_vec()).collect()
+    }
+
+    #[test]
+    fn rounded_rect_leaves_corners_transparent() {
+        let rect = RoundedRect::new(RED, 20.0, 20.0, 8.0);
+        assert_bounds(rect.bounds(), (20.0, 20.0));
+
+        let alpha = render_alpha(&rect, 20, 20);
+        let at = |x: usize, y: usize| alpha[y * 20 + x];
+        for &(x, y) in &[(0, 0), (19, 0), (19, 19), (0, 19)] {
+            assert_eq!(at(x, y), 0, "corner ({}, {}) was drawn", x, y);
+        }
+        for &(x, y) in &[(10, 10), (10, 0), (0, 10), (19, 10), (10, 19)] {
+            assert_eq!(at(x, y), 0xFF, "edge ({}, {}) was not drawn", x, y);
+        }
+    }
+
+    #[test]
+    fn rounded_rect_corners_are_independent() {
+        // Only the top left and bottom right corners are rounded
+        let rect = RoundedRect::with_corners(RED, 20.0, 20.0, [8.0, 0.0, 8.0, 0.0]);
+        let alpha = render_alpha(&rect, 20, 20);
+        let at = |x: usize, y: usize| alpha[y * 20 + x];
+
+        assert_eq!(at(0, 0), 0);
+        assert_eq!(at(19, 19), 0);
+        assert_eq!(at(19, 0), 0xFF);
+        assert_eq!(at(0, 19), 0xFF);
+    }
+
+    #[test]
+    fn unrotated_bounds_are_unchanged() {
+        assert_bounds(Rotate::new(Pad::new(40.0, 10.0), 0.0).bounds(), (40.0, 10.0));
+    }
+
+    #[test]
+    fn quarter_turn_swaps_bounds() {
+        let angle = std::f64::consts::FRAC_PI_2;
+        assert_bounds(Rotate::new(Pad::new(40.0, 10.0), angle).bounds(), (10.0, 40.0));
+        assert_bounds(Rotate::new(Pad::new(40.0, 10.0), -angle).bounds(), (10.0, 40.0));
+    }
+
+    #[test]
+    fn diagonal_bounds_cover_rotated_corners() {
+        let side = (40.0 + 10.0) * std::f64::consts::FRAC_1_SQRT_2;
+        let angle = std::f64::consts::FRAC_PI_4;
+        assert_bounds(Rotate::new(Pad::new(40.0, 10.0), angle).bounds(), (side, side));
+    }
+
+    #[test]
+    fn border_leaves_bounds_unchanged() {
+        let color = Color { r: 1.0, g: 1.0, b: 1.0 };
+        assert_bounds(Pad::new(40.0, 10.0).border(color, 3.0).bounds(), (40.0, 10.0));
+        assert_bounds(Pad::new(40.0, 10.0).border(color, 3.0).with_radius(5.0).bounds(), (40.0, 10.0));
+    }
+
+    #[test]
+    fn tile_repeats_strip() {
+        // A 4px strip with a different shade in each row, tiled to 20px
+        let mut strip = RenderGroup::new();
+        for row in 0..4 {
+            let shade = row as f64 / 4.0;
+            strip.push(FillRect::rect(Color { r: shade, g: 1.0 - shade, b: 0.5 }, 2.0, 1.0).offset(0.0, row as f64));
+        }
+        let tile = strip.tile(TileDirection::Vertical, 2.0, 20.0);
+        assert_bounds(tile.bounds(), (2.0, 20.0));
+
+        let mut surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 2, 20).unwrap();
+        let mut context = cairo::Context::new(&surface);
+        tile.render(&mut context).unwrap();
+        std::mem::drop(context);
+        surface.flush();
+
+        let stride = surface.get_stride() as usize;
+        let data = surface.get_data().unwrap();
+        let row = |y: usize| data[y * stride..y * stride + 4].to_vec();
+
+        for y in 1..4 {
+            assert!(row(y) != row(y - 1), "rows {} and {} of the strip match", y - 1, y);
+        }
+        for y in 4..20 {
+            assert_eq!(row(y), row(y % 4), "row {}", y);
+        }
+    }
+
+    #[test]
+    fn centered_column_items_center_on_widest() {
+        let blue = Color { r: 0.0, g: 0.0, b: 1.0 };
+        let mut column = RenderColumn::with_align(Align::Center);
+        // The widest item comes last, so the first can only be placed at render time
+        column.push(FillRect::rect(RED, 10.0, 2.0));
+        column.push(FillRect::rect(blue, 30.0, 2.0));
+        assert_bounds(column.bounds(), (30.0, 4.0));
+
+        let pixels = render_pixels(&column, 30, 4);
+        for x in 0..30 {
+            let expected = if (10..20).contains(&x) { 0xFF0000 } else { 0 };
+            assert_eq!(pixels[x], expected, "column {}", x);
+            assert_eq!(pixels[2 * 30 + x], 0x0000FF, "column {}", x);
+        }
+    }
+
+    #[test]
+    fn row_places_items_side_by_side() {
+        let blue = Color { r: 0.0, g: 0.0, b: 1.0 };
+        let mut row = RenderRow::new();
+        assert_eq!(row.push(FillRect::rect(RED, 6.0, 4.0)), 0.0);
+        assert_eq!(row.push(FillRect::rect(blue, 10.0, 2.0)), 6.0);
+        assert_bounds(row.bounds(), (16.0, 4.0));
+
+        let pixels = render_pixels(&row, 20, 4);
+        for y in 0..4 {
+            for x in 0..20 {
+                let expected = match x {
+                    0..=5 => 0xFF0000,
+                    6..=15 if y < 2 => 0x0000FF,
+                    _ => 0,
+                };
+                assert_eq!(pixels[y * 20 + x], expected, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn centered_row_items_center_on_tallest() {
+        let blue = Color { r: 0.0, g: 0.0, b: 1.0 };
+        let mut row = RenderRow::with_align(Align::Center);
+        // The tallest item comes last, so the first can only be placed at render time
+        row.push(FillRect::rect(RED, 2.0, 10.0));
+        row.push(FillRect::rect(blue, 2.0, 30.0));
+        assert_bounds(row.bounds(), (4.0, 30.0));
+
+        let pixels = render_pixels(&row, 4, 30);
+        for y in 0..30 {
+            let expected = if (10..20).contains(&y) { 0xFF0000 } else { 0 };
+            assert_eq!(pixels[y * 4], expected, "row {}", y);
+            assert_eq!(pixels[y * 4 + 2], 0x0000FF, "row {}", y);
+        }
+    }
+
+    /// The debug colors picked for a fixed set of placements, each on a fresh context
+    fn debug_color_run() -> Vec<(u64, u64, u64)> {
+        let placements = [((0.0, 0.0), (100.0, 20.0)), ((23.0, 585.0), (930.0, 95.0)), ((160.0, 700.5), (788.0, 40.0))];
+        placements
+            .iter()
+            .map(|&((x, y), bounds)| {
+                let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, 16, 16).unwrap();
+                let context = cairo::Context::new(&surface);
+                context.translate(x, y);
+                let color = debug_color(&context, bounds);
+                (color.r.to_bits(), color.g.to_bits(), color.b.to_bits())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn debug_colors_are_reproducible() {
+        let first = debug_color_run();
+        assert_eq!(debug_color_run(), first);
+
+        // Keyed on placement, not on how many colors were picked before
+        assert!(first[0] != first[1] || first[1] != first[2], "{:?}", first);
+    }
+
+    #[test]
+    fn separator_follows_dash_pattern() {
+        // 6px on, 2px off, 2px on, 2px off, along a 2px line covering rows 1 and 2
+        let separator = Separator {
+            color: RED,
+            width: 40.0,
+            thickness: 2.0,
+            dash: vec![6.0, 2.0, 2.0, 2.0],
+            dash_offset: 0.0,
+            cap: cairo::LineCap::Butt,
+            margin: 2.0,
+            center_gap: 0.0,
+        };
+        let pixels = render_pixels(&separator, 40, 4);
+
+        for x in 0..40 {
+            let phase = x % 12;
+            let expected = if phase < 6 || (8..10).contains(&phase) { 0xFF0000 } else { 0 };
+            assert_eq!(pixels[40 + x], expected, "column {}", x);
+            assert_eq!(pixels[80 + x], expected, "column {}", x);
+            assert_eq!(pixels[x], 0, "column {} above the line", x);
+        }
+    }
+}
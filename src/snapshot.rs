@@ -0,0 +1,195 @@
+// Copyright 2020-2021 bd_
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions: The above copyright
+// notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Machine-readable snapshots of the fetched calendar, and comparison between two of them.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+
+use super::CalendarDay;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EventSnapshot {
+    pub uid: String,
+    /// RFC 3339
+    pub start: String,
+    pub end: Option<String>,
+    pub body: String,
+    #[serde(default)]
+    pub location: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+pub fn snapshot(days: &[CalendarDay]) -> Vec<EventSnapshot> {
+    days.iter()
+        .flat_map(|day| day.events.iter())
+        .map(|ev| EventSnapshot {
+            uid: ev.uid.clone(),
+            start: ev.start_time.to_rfc3339(),
+            end: ev.end_time.map(|t| t.to_rfc3339()),
+            body: ev.body.clone(),
+            location: ev.location.clone(),
+            description: ev.description.clone(),
+        })
+        .collect()
+}
+
+pub fn save(path: &str, events: &[EventSnapshot]) -> Result<()> {
+    let f = std::fs::File::create(path).with_context(|| format!("Creating {}", path))?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(f), events)
+        .with_context(|| format!("Writing calendar snapshot to {}", path))
+}
+
+pub fn load(path: &str) -> Result<Vec<EventSnapshot>> {
+    let f = std::fs::File::open(path).with_context(|| format!("Opening {}", path))?;
+    serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Reading calendar snapshot from {}", path))
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CalendarDiff {
+    pub added: Vec<EventSnapshot>,
+    pub removed: Vec<EventSnapshot>,
+    /// (before, after) pairs of events whose UID is unchanged
+    pub modified: Vec<(EventSnapshot, EventSnapshot)>,
+}
+
+impl CalendarDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Compares two snapshots by UID. Recurring events share a UID, so instances are matched up
+/// in start-time order.
+pub fn diff(prev: &[EventSnapshot], cur: &[EventSnapshot]) -> CalendarDiff {
+    fn by_uid(events: &[EventSnapshot]) -> BTreeMap<&str, Vec<&EventSnapshot>> {
+        let mut map: BTreeMap<&str, Vec<&EventSnapshot>> = BTreeMap::new();
+        for ev in events {
+            map.entry(ev.uid.as_str()).or_default().push(ev);
+        }
+        map
+    }
+
+    let prev_map = by_uid(prev);
+    let cur_map = by_uid(cur);
+    let mut result = CalendarDiff::default();
+
+    for (uid, prev_events) in prev_map.iter() {
+        let cur_events = cur_map.get(uid).map(|v| v.as_slice()).unwrap_or(&[]);
+
+        for (i, before) in prev_events.iter().enumerate() {
+            match cur_events.get(i) {
+                Some(after) if after != before => {
+                    result.modified.push(((*before).clone(), (*after).clone()))
+                }
+                Some(_) => (),
+                None => result.removed.push((*before).clone()),
+            }
+        }
+        result.added.extend(cur_events.iter().skip(prev_events.len()).map(|&ev| ev.clone()));
+    }
+
+    for (uid, cur_events) in cur_map.iter() {
+        if !prev_map.contains_key(uid) {
+            result.added.extend(cur_events.iter().map(|&ev| ev.clone()));
+        }
+    }
+
+    result
+}
+
+fn describe(ev: &EventSnapshot) -> String {
+    match &ev.end {
+        Some(end) => format!("{} - {} {} ({})", ev.start, end, ev.body, ev.uid),
+        None => format!("{} {} ({})", ev.start, ev.body, ev.uid),
+    }
+}
+
+impl std::fmt::Display for CalendarDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for ev in self.added.iter() {
+            writeln!(f, "+ {}", describe(ev))?;
+        }
+        for ev in self.removed.iter() {
+            writeln!(f, "- {}", describe(ev))?;
+        }
+        for (before, after) in self.modified.iter() {
+            writeln!(f, "~ {}", describe(before))?;
+            writeln!(f, "  -> {}", describe(after))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snap(uid: &str, start: &str, body: &str) -> EventSnapshot {
+        EventSnapshot {
+            uid: uid.into(),
+            start: start.into(),
+            end: None,
+            body: body.into(),
+            location: None,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn diff_reports_added_removed_and_modified_events() {
+        let kept = snap("kept", "2021-06-01T21:00:00+09:00", "定例会");
+        let gone = snap("gone", "2021-06-01T22:00:00+09:00", "中止になった集会");
+        let before = snap("moved", "2021-06-02T20:00:00+09:00", "勉強会");
+        let after = snap("moved", "2021-06-02T21:00:00+09:00", "勉強会");
+        let new = snap("new", "2021-06-03T21:00:00+09:00", "新しい集会");
+
+        let diff = diff(
+            &[kept.clone(), gone.clone(), before.clone()],
+            &[kept, after.clone(), new.clone()],
+        );
+
+        assert_eq!(diff.added, vec![new]);
+        assert_eq!(diff.removed, vec![gone]);
+        assert_eq!(diff.modified, vec![(before, after)]);
+    }
+
+    #[test]
+    fn diff_matches_recurring_instances_in_order() {
+        let first = snap("weekly", "2021-06-01T21:00:00+09:00", "定例会");
+        let second = snap("weekly", "2021-06-08T21:00:00+09:00", "定例会");
+
+        let diff = diff(&[first.clone()], &[first, second.clone()]);
+
+        assert_eq!(diff.added, vec![second]);
+        assert!(diff.removed.is_empty());
+        assert!(diff.modified.is_empty());
+    }
+
+    #[test]
+    fn identical_snapshots_have_empty_diff() {
+        let events = vec![snap("a", "2021-06-01T21:00:00+09:00", "定例会")];
+        assert!(diff(&events, &events).is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2020-2021 bd_
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions: The above copyright
+// notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use anyhow::{anyhow, Result};
+
+use chrono::prelude::*;
+use chrono::Offset;
+use chrono_tz::Tz;
+
+/// The time zone in which event times are displayed and grouped into days.
+///
+/// Times are converted into this zone once, when parsed, and are then carried around as
+/// fixed-offset times; date arithmetic afterwards does not account for DST transitions.
+#[derive(Clone, Copy, Debug)]
+pub enum DisplayZone {
+    /// The zone of the machine we're running on (honoring `TZ`)
+    Local,
+    Named(Tz),
+}
+
+impl DisplayZone {
+    pub fn convert<T: TimeZone>(&self, dt: &DateTime<T>) -> DateTime<FixedOffset> {
+        match self {
+            DisplayZone::Local => {
+                let local = dt.with_timezone(&Local);
+                local.with_timezone(local.offset())
+            }
+            DisplayZone::Named(tz) => {
+                let zoned = dt.with_timezone(tz);
+                zoned.with_timezone(&zoned.offset().fix())
+            }
+        }
+    }
+
+    /// Midnight at the start of `date` in this zone. If a DST change skips midnight, the first
+    /// time after the gap is used instead.
+    pub fn start_of_day(&self, date: NaiveDate) -> DateTime<FixedOffset> {
+        self.from_local(date.and_hms(0, 0, 0))
+    }
+
+    /// The given wall-clock time in this zone. Times skipped by a DST change are moved forward
+    /// past the gap (02:30 becomes 03:30 when clocks jump from 02:00 to 03:00), and ambiguous
+    /// ones resolve to the earlier instant.
+    pub fn from_local(&self, local: NaiveDateTime) -> DateTime<FixedOffset> {
+        match self {
+            DisplayZone::Local => {
+                let local = resolve_local(&Local, local);
+                local.with_timezone(local.offset())
+            }
+            DisplayZone::Named(tz) => {
+                let zoned = resolve_local(tz, local);
+                zoned.with_timezone(&zoned.offset().fix())
+            }
+        }
+    }
+
+    pub fn now(&self) -> DateTime<FixedOffset> {
+        self.convert(&Utc::now())
+    }
+}
+
+/// DST gaps are at most an hour in every zone we'd display, so a skipped time exists an hour
+/// later. Failing that, the wall-clock time is taken as UTC rather than giving up.
+fn resolve_local<Z: TimeZone>(zone: &Z, local: NaiveDateTime) -> DateTime<Z> {
+    zone.from_local_datetime(&local)
+        .earliest()
+        .or_else(|| zone.from_local_datetime(&(local + chrono::Duration::hours(1))).earliest())
+        .unwrap_or_else(|| zone.from_utc_datetime(&local))
+}
+
+impl std::str::FromStr for DisplayZone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("local") {
+            return Ok(DisplayZone::Local);
+        }
+
+        s.parse::<Tz>()
+            .map(DisplayZone::Named)
+            .map_err(|e| anyhow!("Unknown time zone {:?}: {}", s, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn los_angeles() -> DisplayZone {
+        "America/Los_Angeles".parse().unwrap()
+    }
+
+    #[test]
+    fn skipped_time_moves_past_the_gap() {
+        // Clocks went from 02:00 PST to 03:00 PDT
+        let dt = los_angeles().from_local(NaiveDate::from_ymd(2021, 3, 14).and_hms(2, 30, 0));
+
+        assert_eq!(dt, DateTime::parse_from_rfc3339("2021-03-14T03:30:00-07:00").unwrap());
+        assert_eq!(dt.naive_local().date(), NaiveDate::from_ymd(2021, 3, 14));
+    }
+
+    #[test]
+    fn ambiguous_time_resolves_to_earlier_instant() {
+        // 01:00-02:00 happened twice, first in PDT
+        let dt = los_angeles().from_local(NaiveDate::from_ymd(2021, 11, 7).and_hms(1, 30, 0));
+
+        assert_eq!(dt, DateTime::parse_from_rfc3339("2021-11-07T01:30:00-07:00").unwrap());
+    }
+
+    #[test]
+    fn skipped_midnight_starts_day_after_the_gap() {
+        // Brazil began DST at midnight, so 2018-11-04 started at 01:00
+        let zone: DisplayZone = "America/Sao_Paulo".parse().unwrap();
+        let dt = zone.start_of_day(NaiveDate::from_ymd(2018, 11, 4));
+
+        assert_eq!(dt, DateTime::parse_from_rfc3339("2018-11-04T01:00:00-02:00").unwrap());
+    }
+}